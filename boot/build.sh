@@ -0,0 +1,47 @@
+#!/bin/sh
+# Builds the bundled boot ROMs from dmg_boot.asm and cgb_boot.asm with RGBDS.
+# With --check the results are compared with the committed .bin files instead,
+# which fails when a source was changed without rebuilding them.
+#
+#   bin            source         MODEL
+#   dmg0_boot.bin  dmg_boot.asm   0
+#   dmg_boot.bin   dmg_boot.asm   1
+#   mgb_boot.bin   dmg_boot.asm   2
+#   sgb_boot.bin   dmg_boot.asm   3
+#   sgb2_boot.bin  dmg_boot.asm   4
+#   cgb_boot.bin   cgb_boot.asm   0 (also used for CGB0)
+#   agb_boot.bin   cgb_boot.asm   1
+set -eu
+
+cd "$(dirname "$0")"
+out=$(mktemp -d)
+trap 'rm -rf "$out"' EXIT
+failed=0
+
+build() {
+    bin=$1 source=$2 model=$3
+    rgbasm -D MODEL="$model" -o "$out/$bin.o" "$source"
+    rgblink -x -o "$out/$bin" "$out/$bin.o"
+
+    if [ "$check" = 1 ]; then
+        if ! cmp -s "$out/$bin" "$bin"; then
+            echo "$bin differs from $source built with MODEL=$model" >&2
+            failed=1
+        fi
+    else
+        cp "$out/$bin" "$bin"
+    fi
+}
+
+check=0
+if [ "${1:-}" = "--check" ]; then check=1; fi
+
+build dmg0_boot.bin dmg_boot.asm 0
+build dmg_boot.bin dmg_boot.asm 1
+build mgb_boot.bin dmg_boot.asm 2
+build sgb_boot.bin dmg_boot.asm 3
+build sgb2_boot.bin dmg_boot.asm 4
+build cgb_boot.bin cgb_boot.asm 0
+build agb_boot.bin cgb_boot.asm 1
+
+exit $failed
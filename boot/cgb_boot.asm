@@ -0,0 +1,232 @@
+; Free replacement boot ROM for the CGB
+;
+; Same idea as dmg_boot.asm, plus clearing both VRAM banks, loading grayscale
+; palettes and locking DMG cartridges into compatibility mode through KEY0.
+; The layout matches the original: $0000-$00FF and $0200-$08FF, the header
+; of the cartridge shows through in between.
+;
+; Build with RGBDS, MODEL being 0 = CGB (also used for CGB0), 1 = AGB:
+;   rgbasm -D MODEL=0 -o cgb_boot.o cgb_boot.asm
+;   rgblink -x -o cgb_boot.bin cgb_boot.o
+; The emulator bundles cgb_boot.bin and agb_boot.bin, built with MODEL 0 and 1
+; by build.sh.
+
+DEF rLCDC EQU $FF40
+DEF rSCY  EQU $FF42
+DEF rLY   EQU $FF44
+DEF rBGP  EQU $FF47
+DEF rKEY0 EQU $FF4C
+DEF rVBK  EQU $FF4F
+DEF rBOOT EQU $FF50
+DEF rBCPS EQU $FF68
+DEF rBCPD EQU $FF69
+DEF rOCPS EQU $FF6A
+DEF rOCPD EQU $FF6B
+DEF rOPRI EQU $FF6C
+DEF rNR50 EQU $FF24
+DEF rNR51 EQU $FF25
+DEF rNR52 EQU $FF26
+
+SECTION "Boot", ROM0[$0000]
+Start:
+    ld sp, $FFFE
+
+    ld a, 1
+    ldh [rVBK], a
+    call ClearVram
+    xor a
+    ldh [rVBK], a
+    call ClearVram
+    jp Main
+
+ClearVram:
+    xor a
+    ld hl, $9FFF
+.loop
+    ld [hl-], a
+    bit 7, h
+    jr nz, .loop
+    ret
+
+SECTION "Handover", ROM0[$00FE]
+Handover:
+    ldh [rBOOT], a
+
+SECTION "Main", ROM0[$0200]
+Main:
+    ld a, $80
+    ldh [rNR52], a
+    ldh [$FF11], a
+    ld a, $F3
+    ldh [$FF12], a
+    ldh [rNR51], a
+    ld a, $77
+    ldh [rNR50], a
+
+    ld a, $FC
+    ldh [rBGP], a
+
+    ; BG palette 0 and both OBJ palettes in grayscale, the other BG palettes white
+    ld a, $80
+    ldh [rBCPS], a
+    ld c, LOW(rBCPD)
+    call WriteGrayPalette
+    ld b, 7 * 8
+    ld a, $FF
+.whitePalettes
+    ldh [rBCPD], a
+    dec b
+    jr nz, .whitePalettes
+
+    ld a, $80
+    ldh [rOCPS], a
+    ld c, LOW(rOCPD)
+    call WriteGrayPalette
+    call WriteGrayPalette
+
+    ld de, $0104
+    ld hl, $8010
+.logo
+    ld a, [de]
+    swap a
+    call ExpandNibble
+    ld a, [de]
+    call ExpandNibble
+    inc de
+    ld a, e
+    cp $34
+    jr nz, .logo
+
+    ld de, RegisteredTile
+    ld b, 8
+.registered
+    ld a, [de]
+    ld [hl+], a
+    inc hl
+    inc de
+    dec b
+    jr nz, .registered
+
+    ld hl, $9904
+    ld a, $01
+    call WriteMapRow
+    ld [hl], $19
+    ld l, $24
+    call WriteMapRow
+
+    ld a, $64
+    ldh [rSCY], a
+    ld a, $91
+    ldh [rLCDC], a
+
+.scroll
+    call WaitVBlank
+    ldh a, [rSCY]
+    dec a
+    ldh [rSCY], a
+    jr nz, .scroll
+
+    ld a, $83
+    call Chime
+    ld b, 8
+    call WaitFrames
+    ld a, $C1
+    call Chime
+    ld b, 60
+    call WaitFrames
+
+    ; KEY0 gets the CGB flag of the header, DMG cartridges are locked into compatibility mode
+    ld a, [$0143]
+    bit 7, a
+    jr nz, .cgbCartridge
+    ld a, $04
+    ldh [rKEY0], a
+    ld a, $01
+    ldh [rOPRI], a
+    ld hl, $1180
+    push hl
+    ld bc, $0000
+    ld de, $0008
+    ld hl, $007C
+    jr .handover
+.cgbCartridge
+    ldh [rKEY0], a
+    ld hl, $1180
+    push hl
+    ld bc, $0000
+    ld de, $FF56
+    ld hl, $000D
+.handover
+    pop af
+IF MODEL == 1
+    ; The AGB boot ROM ends with an extra inc b, which games use to detect it
+    inc b
+ENDC
+    jp Handover
+
+; Writes white, light gray, dark gray and black to the palette data register in C
+WriteGrayPalette:
+    ld hl, GrayPalette
+    ld b, 8
+.loop
+    ld a, [hl+]
+    ld [$FF00+c], a
+    dec b
+    jr nz, .loop
+    ret
+
+WriteMapRow:
+    ld c, 12
+.loop
+    ld [hl+], a
+    inc a
+    dec c
+    jr nz, .loop
+    ret
+
+ExpandNibble:
+    ld c, a
+    ld b, 4
+.loop
+    rr c
+    rra
+    sra a
+    dec b
+    jr nz, .loop
+    ld [hl+], a
+    inc hl
+    ld [hl+], a
+    inc hl
+    ret
+
+Chime:
+    ldh [$FF13], a
+    ld a, $87
+    ldh [$FF14], a
+    ret
+
+WaitFrames:
+    call WaitVBlank
+    dec b
+    jr nz, WaitFrames
+    ret
+
+WaitVBlank:
+    ldh a, [rLY]
+    cp $90
+    jr nz, WaitVBlank
+    ld a, 30
+.delay
+    dec a
+    jr nz, .delay
+    ret
+
+GrayPalette:
+    dw $7FFF, $5294, $294A, $0000
+
+RegisteredTile:
+    db $3C, $42, $B9, $A5, $B9, $A5, $42, $3C
+
+; Pads the image to the full $900 bytes
+SECTION "End", ROM0[$08FF]
+    db $00
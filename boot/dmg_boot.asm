@@ -0,0 +1,200 @@
+; Free replacement boot ROM for the DMG family (DMG0, DMG, MGB, SGB, SGB2)
+;
+; It clears VRAM, sets up the APU, scrolls the cartridge logo into view with a
+; chime and hands over at $0100 with the registers of the selected model.
+; Unlike the original it does not verify the logo or the header checksum.
+;
+; Build with RGBDS, MODEL being 0 = DMG0, 1 = DMG, 2 = MGB, 3 = SGB, 4 = SGB2:
+;   rgbasm -D MODEL=1 -o dmg_boot.o dmg_boot.asm
+;   rgblink -x -o dmg_boot.bin dmg_boot.o
+; The emulator bundles dmg0_boot.bin, dmg_boot.bin, mgb_boot.bin, sgb_boot.bin
+; and sgb2_boot.bin, built with MODEL 0 to 4. build.sh builds all of them, and
+; build.sh --check verifies that the committed files match their sources.
+
+DEF rLCDC EQU $FF40
+DEF rSCY  EQU $FF42
+DEF rLY   EQU $FF44
+DEF rBGP  EQU $FF47
+DEF rBOOT EQU $FF50
+DEF rNR50 EQU $FF24
+DEF rNR51 EQU $FF25
+DEF rNR52 EQU $FF26
+
+IF MODEL == 0
+DEF AF_CHECKSUM EQU $0100
+DEF AF_NO_CHECKSUM EQU $0100
+DEF BC_VALUE EQU $FF13
+DEF DE_VALUE EQU $00C1
+DEF HL_VALUE EQU $8403
+ELIF MODEL == 1
+DEF AF_CHECKSUM EQU $01B0
+DEF AF_NO_CHECKSUM EQU $0180
+DEF BC_VALUE EQU $0013
+DEF DE_VALUE EQU $00D8
+DEF HL_VALUE EQU $014D
+ELIF MODEL == 2
+DEF AF_CHECKSUM EQU $FFB0
+DEF AF_NO_CHECKSUM EQU $FF80
+DEF BC_VALUE EQU $0013
+DEF DE_VALUE EQU $00D8
+DEF HL_VALUE EQU $014D
+ELIF MODEL == 3
+DEF AF_CHECKSUM EQU $0100
+DEF AF_NO_CHECKSUM EQU $0100
+DEF BC_VALUE EQU $0014
+DEF DE_VALUE EQU $0000
+DEF HL_VALUE EQU $C060
+ELSE
+DEF AF_CHECKSUM EQU $FF00
+DEF AF_NO_CHECKSUM EQU $FF00
+DEF BC_VALUE EQU $0014
+DEF DE_VALUE EQU $0000
+DEF HL_VALUE EQU $C060
+ENDC
+
+SECTION "Boot", ROM0[$0000]
+Start:
+    ld sp, $FFFE
+
+    xor a
+    ld hl, $9FFF
+.clearVram
+    ld [hl-], a
+    bit 7, h
+    jr nz, .clearVram
+
+    ld a, $80
+    ldh [rNR52], a
+    ldh [$FF11], a
+    ld a, $F3
+    ldh [$FF12], a
+    ldh [rNR51], a
+    ld a, $77
+    ldh [rNR50], a
+
+    ld a, $FC
+    ldh [rBGP], a
+
+    ; Every logo nibble becomes a doubled row, written twice for double height
+    ld de, $0104
+    ld hl, $8010
+.logo
+    ld a, [de]
+    swap a
+    call ExpandNibble
+    ld a, [de]
+    call ExpandNibble
+    inc de
+    ld a, e
+    cp $34
+    jr nz, .logo
+
+    ld de, RegisteredTile
+    ld b, 8
+.registered
+    ld a, [de]
+    ld [hl+], a
+    inc hl
+    inc de
+    dec b
+    jr nz, .registered
+
+    ; Tiles $01-$0C on the top row, $0D-$18 below and (R) at the end of the top row
+    ld hl, $9904
+    ld a, $01
+    call WriteMapRow
+    ld [hl], $19
+    ld l, $24
+    call WriteMapRow
+
+    ld a, $64
+    ldh [rSCY], a
+    ld a, $91
+    ldh [rLCDC], a
+
+.scroll
+    call WaitVBlank
+    ldh a, [rSCY]
+    dec a
+    ldh [rSCY], a
+    jr nz, .scroll
+
+    ld a, $83
+    call Chime
+    ld b, 8
+    call WaitFrames
+    ld a, $C1
+    call Chime
+    ld b, 60
+    call WaitFrames
+
+    ; DMG and MGB report a non zero header checksum through H and C
+    ld hl, AF_CHECKSUM
+    ld a, [$014D]
+    and a
+    jr nz, .handover
+    ld hl, AF_NO_CHECKSUM
+.handover
+    push hl
+    ld bc, BC_VALUE
+    ld de, DE_VALUE
+    ld hl, HL_VALUE
+    pop af
+    jp Handover
+
+; Writes A and the next 11 tile numbers to [HL] onwards
+WriteMapRow:
+    ld c, 12
+.loop
+    ld [hl+], a
+    inc a
+    dec c
+    jr nz, .loop
+    ret
+
+; Expands the low nibble of A into a byte with every bit doubled
+ExpandNibble:
+    ld c, a
+    ld b, 4
+.loop
+    rr c
+    rra
+    sra a
+    dec b
+    jr nz, .loop
+    ld [hl+], a
+    inc hl
+    ld [hl+], a
+    inc hl
+    ret
+
+; Plays a short note on channel 1 with the frequency low byte in A
+Chime:
+    ldh [$FF13], a
+    ld a, $87
+    ldh [$FF14], a
+    ret
+
+WaitFrames:
+    call WaitVBlank
+    dec b
+    jr nz, WaitFrames
+    ret
+
+; Waits for line $90, then long enough to be past it
+WaitVBlank:
+    ldh a, [rLY]
+    cp $90
+    jr nz, WaitVBlank
+    ld a, 30
+.delay
+    dec a
+    jr nz, .delay
+    ret
+
+RegisteredTile:
+    db $3C, $42, $B9, $A5, $B9, $A5, $42, $3C
+
+SECTION "Handover", ROM0[$00FE]
+Handover:
+    ldh [rBOOT], a
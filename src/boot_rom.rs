@@ -0,0 +1,25 @@
+use crate::model::Model;
+
+// Free replacement boot ROMs, assembled from the sources in boot/
+const DMG0_BOOT_ROM: &[u8] = include_bytes!("../boot/dmg0_boot.bin");
+const DMG_BOOT_ROM: &[u8] = include_bytes!("../boot/dmg_boot.bin");
+const MGB_BOOT_ROM: &[u8] = include_bytes!("../boot/mgb_boot.bin");
+const SGB_BOOT_ROM: &[u8] = include_bytes!("../boot/sgb_boot.bin");
+const SGB2_BOOT_ROM: &[u8] = include_bytes!("../boot/sgb2_boot.bin");
+const CGB_BOOT_ROM: &[u8] = include_bytes!("../boot/cgb_boot.bin");
+const AGB_BOOT_ROM: &[u8] = include_bytes!("../boot/agb_boot.bin");
+
+pub fn built_in(model: Model) -> Vec<u8> {
+    let boot_rom = match model {
+        Model::DMG0 => DMG0_BOOT_ROM,
+        Model::DMG => DMG_BOOT_ROM,
+        Model::MGB => MGB_BOOT_ROM,
+        Model::SGB => SGB_BOOT_ROM,
+        Model::SGB2 => SGB2_BOOT_ROM,
+        // The CGB0 boot ROM hands over with the same state as the later one
+        Model::CGB0 | Model::CGB => CGB_BOOT_ROM,
+        Model::AGB => AGB_BOOT_ROM
+    };
+
+    boot_rom.to_vec()
+}
@@ -0,0 +1,77 @@
+use crate::model::Model;
+
+pub const TITLE_BEGIN: usize = 0x0134;
+pub const TITLE_END: usize = 0x0143;
+pub const CGB_FLAG: usize = 0x0143;
+pub const NEW_LICENSEE_CODE: usize = 0x0144;
+pub const SGB_FLAG: usize = 0x0146;
+pub const CARTRIDGE_TYPE: usize = 0x0147;
+pub const ROM_SIZE: usize = 0x0148;
+pub const RAM_SIZE: usize = 0x0149;
+pub const OLD_LICENSEE_CODE: usize = 0x014B;
+pub const HEADER_CHECKSUM: usize = 0x014D;
+
+// The cartridge header at 0x0100-0x014F, the game ROM must be at least that long
+pub struct Header {
+    pub title: [u8; 16],
+    pub cgb_flag: u8,
+    pub new_licensee_code: [u8; 2],
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub old_licensee_code: u8,
+    pub header_checksum: u8
+}
+
+impl Header {
+    pub fn parse(game_rom: &[u8]) -> Header {
+        let mut title = [0; 16];
+        title.copy_from_slice(&game_rom[TITLE_BEGIN ..= TITLE_END]);
+
+        Header {
+            title,
+            cgb_flag: game_rom[CGB_FLAG],
+            new_licensee_code: [game_rom[NEW_LICENSEE_CODE], game_rom[NEW_LICENSEE_CODE + 1]],
+            sgb_flag: game_rom[SGB_FLAG],
+            cartridge_type: game_rom[CARTRIDGE_TYPE],
+            rom_size: game_rom[ROM_SIZE],
+            ram_size: game_rom[RAM_SIZE],
+            old_licensee_code: game_rom[OLD_LICENSEE_CODE],
+            header_checksum: game_rom[HEADER_CHECKSUM]
+        }
+    }
+
+    // Both 0x80 (enhanced) and 0xC0 (CGB only) have bit 7 set
+    pub fn supports_cgb(&self) -> bool {
+        (self.cgb_flag & 0x80) != 0
+    }
+
+    pub fn requires_cgb(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    // SGB functions are only enabled with the old licensee code 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    // Old licensee 0x33 means the new licensee code is used instead
+    pub fn is_nintendo_licensed(&self) -> bool {
+        self.old_licensee_code == 0x01 || (self.old_licensee_code == 0x33 && &self.new_licensee_code == b"01")
+    }
+
+    pub fn title_checksum(&self) -> u8 {
+        title_checksum(&self.title)
+    }
+
+    // The model used when none is given on the command line
+    pub fn preferred_model(&self) -> Model {
+        if self.supports_cgb() { Model::CGB } else { Model::DMG }
+    }
+}
+
+// Sum of the 16 title bytes, the CGB boot ROM uses it to recognise DMG games
+pub fn title_checksum(title: &[u8; 16]) -> u8 {
+    title.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
@@ -0,0 +1,74 @@
+use std::io::Read;
+
+use crate::error::EmulatorError;
+use crate::memory_bus::{EXTERNAL_RAM_SIZE, WORKING_RAM_BANK_SIZE, WORKING_RAM_BANKS, ZERO_PAGE_SIZE};
+
+// Flags per byte, code, data and subroutine entry use the bits Mesen gives them
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const SUBROUTINE_ENTRY: u8 = 0x08;
+// Set along with CODE on the first byte of an instruction, operands only have CODE
+pub const OPCODE: u8 = 0x10;
+pub const DMA_SOURCE: u8 = 0x20;
+
+pub enum Region {
+    Rom,
+    ExternalRam,
+    WorkingRam,
+    Hram
+}
+
+// Records how every byte of the ROM and RAM was used, for telling code from data when
+// disassembling. The file holds one flag byte per ROM byte, followed by the cartridge RAM,
+// all banks of working RAM and HRAM. It is loaded again on the next run so coverage adds up
+pub struct CodeDataLog {
+    path: String,
+    flags: Vec<u8>,
+    rom_size: usize
+}
+
+impl CodeDataLog {
+    pub fn new(path: &str, rom_size: usize) -> Result<CodeDataLog, EmulatorError> {
+        let size = rom_size + EXTERNAL_RAM_SIZE + WORKING_RAM_BANK_SIZE * WORKING_RAM_BANKS + ZERO_PAGE_SIZE;
+        let mut flags = vec![0; size];
+
+        match std::fs::File::open(path) {
+            Ok(mut file) => {
+                let mut existing = Vec::new();
+                file.read_to_end(&mut existing).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?;
+                // A log of another ROM or layout would only mislead
+                if existing.len() != size {
+                    return Err(EmulatorError::InvalidArgument(format!("{} is a log of {} bytes, this ROM needs {}", path, existing.len(), size)))
+                }
+                flags = existing;
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => { }
+            Err(error) => return Err(EmulatorError::Io { path: path.to_string(), error })
+        }
+
+        Ok(CodeDataLog { path: path.to_string(), flags, rom_size })
+    }
+
+    pub fn mark(&mut self, region: Region, offset: usize, flags: u8) {
+        let start = match region {
+            // ROM beyond the end of the file reads as padding
+            Region::Rom if offset >= self.rom_size => return,
+            Region::Rom => 0,
+            Region::ExternalRam => self.rom_size,
+            Region::WorkingRam => self.rom_size + EXTERNAL_RAM_SIZE,
+            Region::Hram => self.rom_size + EXTERNAL_RAM_SIZE + WORKING_RAM_BANK_SIZE * WORKING_RAM_BANKS
+        };
+        self.flags[start + offset] |= flags;
+    }
+
+    pub fn write(&self) -> Result<(), EmulatorError> {
+        std::fs::write(&self.path, &self.flags).map_err(|error| EmulatorError::Io { path: self.path.clone(), error })
+    }
+}
+
+// Written on exit like the profiler, the emulator stops in too many places to save it in each
+impl Drop for CodeDataLog {
+    fn drop(&mut self) {
+        if let Err(error) = self.write() { eprintln!("{}", error); }
+    }
+}
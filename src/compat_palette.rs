@@ -0,0 +1,267 @@
+use crate::cartridge::Header;
+use crate::joypad::{Joypad, Button};
+
+// Colors the CGB boot ROM gives DMG cartridges, as 0xRRGGBB
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompatPalette {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4]
+}
+
+// Where the palette of a DMG cartridge on CGB hardware comes from
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteChoice {
+    // Button combination held at the end of boot, then the title checksum like the CGB boot ROM
+    Automatic,
+    Fixed(CompatPalette),
+    // A user supplied boot ROM picks the palette itself
+    BootRom
+}
+
+struct ManualPalette {
+    name: &'static str,
+    direction: Button,
+    button: Option<Button>,
+    palette: CompatPalette
+}
+
+const fn all(colors: [u32; 4]) -> CompatPalette {
+    CompatPalette { bg: colors, obj0: colors, obj1: colors }
+}
+
+const WHITE_RED: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const WHITE_GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const WHITE_BLUE: [u32; 4] = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+const WHITE_BROWN: [u32; 4] = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+
+// The palettes selectable by holding a direction, optionally with A or B, during the boot logo
+const MANUAL_PALETTES: [ManualPalette; 12] = [
+    ManualPalette { name: "brown", direction: Button::Up, button: None, palette: all(WHITE_BROWN) },
+    ManualPalette { name: "red", direction: Button::Up, button: Some(Button::A), palette: CompatPalette { bg: WHITE_RED, obj0: WHITE_GREEN, obj1: WHITE_BLUE } },
+    ManualPalette { name: "dark-brown", direction: Button::Up, button: Some(Button::B), palette: all([0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]) },
+    ManualPalette { name: "blue", direction: Button::Left, button: None, palette: CompatPalette { bg: WHITE_BLUE, obj0: WHITE_RED, obj1: WHITE_GREEN } },
+    ManualPalette { name: "dark-blue", direction: Button::Left, button: Some(Button::A), palette: CompatPalette { bg: [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000], obj0: WHITE_RED, obj1: WHITE_BROWN } },
+    ManualPalette { name: "gray", direction: Button::Left, button: Some(Button::B), palette: all([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]) },
+    ManualPalette { name: "pastel", direction: Button::Down, button: None, palette: all([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]) },
+    ManualPalette { name: "orange", direction: Button::Down, button: Some(Button::A), palette: all([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]) },
+    ManualPalette { name: "yellow", direction: Button::Down, button: Some(Button::B), palette: CompatPalette { bg: [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000], obj0: WHITE_BLUE, obj1: WHITE_GREEN } },
+    ManualPalette { name: "green", direction: Button::Right, button: None, palette: all([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]) },
+    ManualPalette { name: "dark-green", direction: Button::Right, button: Some(Button::A), palette: CompatPalette { bg: [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000], obj0: WHITE_RED, obj1: WHITE_RED } },
+    ManualPalette { name: "inverted", direction: Button::Right, button: Some(Button::B), palette: all([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]) }
+];
+
+// Used when neither buttons nor the title checksum pick a palette
+const DEFAULT_PALETTE: usize = 10;
+
+// The palettes of the CGB boot ROM as RGB555, four colors each
+const BOOT_ROM_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000
+];
+
+// Offsets into BOOT_ROM_COLORS of the BG, OBJ0 and OBJ1 colors the boot ROM combines. A few
+// start in the middle of a palette and run into the next one, as on the hardware
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (116, 16, 16), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),
+    (108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (32, 64, 32), (112, 16, 112),
+    (8, 16, 8), (16, 12, 16), (116, 16, 116), (112, 112, 16), (8, 8, 68), (32, 64, 64),
+    (28, 16, 16), (72, 16, 16), (80, 16, 16), (36, 76, 76), (44, 15, 15), (8, 68, 68),
+    (8, 16, 16), (12, 16, 16), (0, 112, 112), (0, 12, 12), (4, 0, 0), (72, 72, 88),
+    (80, 80, 88), (96, 96, 88), (32, 64, 88), (52, 68, 16), (56, 111, 0), (60, 111, 16),
+    (36, 76, 88), (40, 64, 112), (112, 16, 92), (8, 68, 88), (8, 16, 0), (12, 16, 112),
+    (0, 112, 12), (16, 12, 112), (16, 84, 112), (0, 12, 112), (112, 100, 12), (32, 0, 112),
+    (112, 16, 12), (24, 112, 12), (116, 16, 112)
+];
+
+// The boot ROM table of title checksums and the combination each picks. Checksums shared by
+// several games also need the fourth letter of the title to match
+const GAME_PALETTES: [(u8, Option<u8>, usize); 93] = [
+    (0x88, None, 4), // ALLEY WAY
+    (0x16, None, 5), // YAKUMAN
+    (0x36, None, 35), // BASEBALL
+    (0xD1, None, 34), // TENNIS
+    (0xDB, None, 3), // TETRIS
+    (0xF2, None, 31), // QIX
+    (0x3C, None, 15), // DR.MARIO
+    (0x8C, None, 10), // RADARMISSION
+    (0x92, None, 5), // F1RACE
+    (0x3D, None, 19), // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7), // X
+    (0xC9, None, 37), // MARIOLAND2
+    (0x3E, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1D, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5), // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13), // POKEMON RED
+    (0xAA, None, 14), // POKEMON GREEN
+    (0x75, None, 5), // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5), // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6F, None, 9), // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2), // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4B, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xF7, None, 45), // BOY AND BLOB GB2
+    (0xF6, None, 42), // MEGAMAN
+    (0xA2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4E, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xE0, None, 30), // YOSHI'S COOKIE
+    (0x8B, None, 41), // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34), // TOPRANKINGTENNIS
+    (0x0C, None, 5), // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xE8, None, 6), // SPACE INVADERS
+    (0xB7, None, 5), // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9A, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9D, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2), // TETRIS BLAST
+    (0x9C, None, 16), // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 42), // BA.TOSHINDEN
+    (0x6D, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 0), // TETRIS PLUS
+    (0x6B, None, 39), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xA5, Some(b'A'), 6), // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32), // GBWARS
+    (0xD3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 24), // KID ICARUS
+    (0x0D, Some(b'R'), 31), // TETRIS2
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), // GALAGA&GALAXIAN
+    (0xA5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0), // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0), // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 34), // MARIO & YOSHI
+    (0xBF, Some(b'C'), 23), // SOCCER
+    (0x0D, Some(b'E'), 18), // POKEBOM
+    (0xF4, Some(b' '), 29), // G&W GALLERY
+    (0xB3, Some(b'R'), 28) // TETRIS ATTACK
+];
+
+pub const NAMES: &str = "brown, red, dark-brown, blue, dark-blue, gray, pastel, orange, yellow, green, dark-green, inverted";
+
+pub fn from_name(name: &str) -> Option<CompatPalette> {
+    MANUAL_PALETTES.iter().find(|manual| manual.name == name).map(|manual| manual.palette)
+}
+
+// The palette of the button combination currently held, if any
+pub fn from_buttons(joypad: &Joypad) -> Option<CompatPalette> {
+    MANUAL_PALETTES.iter()
+        .find(|manual| {
+            let button_held = match manual.button {
+                Some(button) => joypad.is_held(button),
+                None => !joypad.is_held(Button::A) && !joypad.is_held(Button::B)
+            };
+            joypad.is_held(manual.direction) && button_held
+        })
+        .map(|manual| manual.palette)
+}
+
+// Only cartridges licensed by Nintendo get a palette of their own
+pub fn from_header(header: &Header) -> CompatPalette {
+    if header.is_nintendo_licensed() {
+        let checksum = header.title_checksum();
+        let game = GAME_PALETTES.iter().find(|(sum, letter, _)| {
+            *sum == checksum && letter.is_none_or(|letter| letter == header.title[3])
+        });
+
+        if let Some(&(_, _, combination)) = game { return boot_rom_palette(combination) }
+    }
+
+    MANUAL_PALETTES[DEFAULT_PALETTE].palette
+}
+
+fn boot_rom_palette(combination: usize) -> CompatPalette {
+    let (bg, obj0, obj1) = COMBINATIONS[combination];
+    let colors = |offset: usize| std::array::from_fn(|index| from_rgb555(BOOT_ROM_COLORS[offset + index]));
+    CompatPalette { bg: colors(bg), obj0: colors(obj0), obj1: colors(obj1) }
+}
+
+pub fn select(joypad: &Joypad, header: &Header) -> CompatPalette {
+    from_buttons(joypad).unwrap_or_else(|| from_header(header))
+}
+
+// Palette RAM holds 15 bit colors, dropping the lower 3 bits of every component
+pub fn to_rgb555(color: u32) -> u16 {
+    let red = ((color >> 19) & 0x1F) as u16;
+    let green = ((color >> 11) & 0x1F) as u16;
+    let blue = ((color >> 3) & 0x1F) as u16;
+
+    red | (green << 5) | (blue << 10)
+}
+
+// Widens each component to 8 bits by repeating its top bits
+fn from_rgb555(color: u16) -> u32 {
+    let widen = |component: u16| ((component << 3) | (component >> 2)) as u32;
+    let red = widen(color & 0x1F);
+    let green = widen((color >> 5) & 0x1F);
+    let blue = widen((color >> 10) & 0x1F);
+
+    (red << 16) | (green << 8) | blue
+}
+
+pub fn colors_to_rgb555(colors: [u32; 4]) -> [u16; 4] {
+    colors.map(to_rgb555)
+}
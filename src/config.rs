@@ -0,0 +1,297 @@
+use crate::error::EmulatorError;
+use crate::model::{self, Model};
+use crate::compat_palette::{self, CompatPalette};
+use crate::tracer::{self, TraceFormat, TraceTrigger};
+use crate::save_state;
+use crate::test_runner::{self, Detector};
+
+pub enum Mode { 
+    Main,
+    Boot, 
+    Debug,
+    // Compares the trace with a reference log and stops where they diverge
+    Diff,
+    // Runs a test ROM without a window until it passes, fails or times out
+    Test,
+    // Prints the disassembly of a ROM range instead of running it
+    Disasm
+}
+
+pub struct Config {
+    pub mode: Mode,
+    // Picked from the cartridge header when not given
+    pub model: Option<Model>,
+    // Overrides the built-in boot ROM of the selected model
+    pub boot_rom_path: Option<String>,
+    pub rom_path: String,
+    // Start at 0x0100 with the post-boot hardware state instead of running a boot ROM
+    pub skip_boot: bool,
+    // Enter the debugger instead of idling when an illegal opcode locks the CPU
+    pub break_on_lock: bool,
+    // Colors for DMG cartridges on CGB hardware instead of the boot ROM's selection
+    pub palette: Option<CompatPalette>,
+    // RGBDS symbols, <rom>.sym is loaded when it exists and this isn't given
+    pub sym_path: Option<String>,
+    // Reference log for the diff mode, in the trace format
+    pub reference_path: Option<String>,
+    // Trace output file, - for stdout
+    pub trace_path: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_start: Option<TraceTrigger>,
+    pub trace_stop: Option<TraceTrigger>,
+    // Profiler report and flamegraph input, both written on exit
+    pub profile_path: Option<String>,
+    pub collapsed_stacks_path: Option<String>,
+    // Code/data log, extended when the file already exists
+    pub cdl_path: Option<String>,
+    // Frames between rewind snapshots and the memory they may use in MiB, 0 turns rewinding off
+    pub rewind_interval: u32,
+    pub rewind_memory: usize,
+    // Input movie to record, optionally starting from a save state slot, or to play back
+    pub record_movie_path: Option<String>,
+    pub movie_state_slot: Option<u8>,
+    pub play_movie_path: Option<String>,
+    // LY reads 0x90 as Gameboy Doctor expects
+    pub stub_ly: bool,
+    // Waits for GDB on this local TCP port and lets it control the CPU
+    pub gdb_port: Option<u16>,
+    // ROM bank and inclusive address range for the disasm command
+    pub disasm_bank: u16,
+    pub disasm_from: u16,
+    pub disasm_to: u16,
+    // What the test mode looks for, all but the framebuffer hash when empty
+    pub test_detectors: Vec<Detector>,
+    // Emulated seconds the test mode waits for a result
+    pub test_timeout: u64
+}
+
+pub const USAGE: &str = "Usage: gb_emulator [options] [rom]
+       gb_emulator disasm [--sym <path>] [--from <[bank:]addr>] [--to <addr>] <rom>
+
+Options:
+    --mode <main|boot|debug|diff|test>
+                                Run mode, debug starts in the debugger, diff compares the
+                                trace with --reference and test runs a test ROM without a
+                                window, exiting with 0 when it passes, 1 when it fails and 2
+                                on a timeout (default: main)
+    --reference <path>          diff: reference log in the --trace-format format
+    --expect <detector>         test: how the ROM reports its result, can be repeated: serial,
+                                memory (blargg at 0xA000), mooneye, hash:<crc32> of a frame
+                                (default: serial, memory and mooneye)
+    --timeout <seconds>         test: emulated seconds to wait for the result (default: 60)
+    --model <name>              Hardware model: dmg0, dmg, mgb, sgb, sgb2, cgb0, cgb, agb
+                                (default: cgb for CGB cartridges, else dmg)
+    --boot-rom <path>           Boot ROM to run instead of the built-in one
+    --skip-boot                 Start the game directly with post-boot hardware state
+    --break-on-lock             Enter the debugger when an illegal opcode locks the CPU
+    --palette <name>            Colors for DMG cartridges on a CGB: brown, red, dark-brown,
+                                blue, dark-blue, gray, pastel, orange, yellow, green,
+                                dark-green, inverted (default: picked like the CGB boot ROM)
+    --sym <path>                RGBDS symbol file for labels in the debugger and disassembly
+                                (default: the ROM path with a .sym extension, if it exists)
+    --trace <path>              Log the CPU state before every instruction, - for stdout
+    --trace-format <name>       Trace line format: doctor, bgb (default: doctor)
+    --trace-template <text>     Custom trace line with fields like {pc}, {af}, {op}, {label}:
+                                {a} {f} {b} {c} {d} {e} {h} {l} {af} {bc} {de} {hl} {sp} {pc}
+                                {bank} {label} {flags} {ime} {ly} {cycles} {pcmem} {op}
+    --trace-start <trigger>     Start tracing at pc:<hex> or cycle:<count>
+    --trace-stop <trigger>      Stop tracing at pc:<hex> or cycle:<count>
+    --profile <path>            Write cycles per instruction, function, interrupt handler and
+                                in HALT to a report on exit
+    --profile-stacks <path>     Write cycles per call stack on exit, in the collapsed format
+                                of flamegraph.pl and speedscope
+    --cdl <path>                Record which ROM and RAM bytes are executed as opcodes or
+                                operands, read as data or copied by DMA, added to the file
+                                if it exists
+    --rewind-interval <frames>  Frames between the snapshots rewinding goes back through
+                                (default: 2)
+    --rewind-memory <MiB>       Memory for rewind snapshots, 0 to turn rewinding off
+                                (default: 64)
+    --record-movie <path>       Record the joypad input per frame to a movie (main mode)
+    --movie-state <slot>        Start the recorded movie from a save state slot instead of
+                                power on
+    --play-movie <path>         Play a recorded movie back, in the main, debug or test mode
+    --stub-ly                   LY always reads 0x90, for comparing with Gameboy Doctor logs
+    --gdb <port>                Wait for a GDB remote connection on 127.0.0.1:<port>
+    --from <[bank:]addr>        disasm: first address in hex, banked addresses default to bank 1
+                                (default: 0000)
+    --to <addr>                 disasm: last address in hex (default: end of the bank)";
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            mode: Mode::Main,
+            model: None,
+            boot_rom_path: None,
+            rom_path: "./tests_blargg/instr_timing/instr_timing.gb".to_string(),
+            skip_boot: false,
+            break_on_lock: false,
+            palette: None,
+            sym_path: None,
+            reference_path: None,
+            trace_path: None,
+            trace_format: TraceFormat::Doctor,
+            trace_start: None,
+            trace_stop: None,
+            profile_path: None,
+            collapsed_stacks_path: None,
+            cdl_path: None,
+            rewind_interval: 2,
+            rewind_memory: 64,
+            record_movie_path: None,
+            movie_state_slot: None,
+            play_movie_path: None,
+            stub_ly: false,
+            gdb_port: None,
+            disasm_bank: 0,
+            disasm_from: 0x0000,
+            disasm_to: 0x3FFF,
+            test_detectors: Vec::new(),
+            test_timeout: 60
+        }
+    }
+
+    pub fn from_args(args: &[String]) -> Result<Config, EmulatorError> {
+        let mut config = Config::new();
+        let args = match args.split_first() {
+            Some((command, rest)) if command == "disasm" => {
+                config.mode = Mode::Disasm;
+                rest
+            }
+            _ => args
+        };
+        let mut args = args.iter();
+        let mut disasm_to = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--mode" => {
+                    config.mode = match next_value(&mut args, arg)?.as_str() {
+                        "main" => Mode::Main,
+                        "boot" => Mode::Boot,
+                        "debug" => Mode::Debug,
+                        "diff" => Mode::Diff,
+                        "test" => Mode::Test,
+                        other => return Err(EmulatorError::InvalidArgument(format!("unknown mode '{}'", other)))
+                    };
+                }
+                "--model" => {
+                    let name = next_value(&mut args, arg)?;
+                    config.model = Some(Model::from_name(&name)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("unknown model '{}', expected one of: {}", name, model::NAMES)))?);
+                }
+                "--boot-rom" => { config.boot_rom_path = Some(next_value(&mut args, arg)?); }
+                "--skip-boot" => { config.skip_boot = true; }
+                "--break-on-lock" => { config.break_on_lock = true; }
+                "--palette" => {
+                    let name = next_value(&mut args, arg)?;
+                    config.palette = Some(compat_palette::from_name(&name)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("unknown palette '{}', expected one of: {}", name, compat_palette::NAMES)))?);
+                }
+                "--sym" => { config.sym_path = Some(next_value(&mut args, arg)?); }
+                "--reference" => { config.reference_path = Some(next_value(&mut args, arg)?); }
+                "--trace" => { config.trace_path = Some(next_value(&mut args, arg)?); }
+                "--trace-format" => {
+                    let name = next_value(&mut args, arg)?;
+                    config.trace_format = TraceFormat::from_name(&name)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("unknown trace format '{}', expected one of: {}", name, tracer::FORMAT_NAMES)))?;
+                }
+                "--trace-template" => { config.trace_format = TraceFormat::Template(next_value(&mut args, arg)?); }
+                "--trace-start" | "--trace-stop" => {
+                    let value = next_value(&mut args, arg)?;
+                    let trigger = TraceTrigger::parse(&value)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("'{}' is not a trigger, expected pc:<hex> or cycle:<count>", value)))?;
+                    if arg == "--trace-start" { config.trace_start = Some(trigger); } else { config.trace_stop = Some(trigger); }
+                }
+                "--profile" => { config.profile_path = Some(next_value(&mut args, arg)?); }
+                "--profile-stacks" => { config.collapsed_stacks_path = Some(next_value(&mut args, arg)?); }
+                "--cdl" => { config.cdl_path = Some(next_value(&mut args, arg)?); }
+                "--rewind-interval" => {
+                    let value = next_value(&mut args, arg)?;
+                    config.rewind_interval = value.parse().ok().filter(|&frames| frames > 0)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("'{}' is not a number of frames", value)))?;
+                }
+                "--rewind-memory" => {
+                    let value = next_value(&mut args, arg)?;
+                    // Kept in bytes later on, which has to fit in a usize
+                    config.rewind_memory = value.parse().ok().filter(|&size: &usize| size.checked_mul(1024 * 1024).is_some())
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("'{}' is not a size in MiB", value)))?;
+                }
+                "--record-movie" => { config.record_movie_path = Some(next_value(&mut args, arg)?); }
+                "--movie-state" => {
+                    let value = next_value(&mut args, arg)?;
+                    config.movie_state_slot = Some(value.parse().ok().filter(|&slot| slot < save_state::SLOTS)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("'{}' is not a slot from 0 to {}", value, save_state::SLOTS - 1)))?);
+                }
+                "--play-movie" => { config.play_movie_path = Some(next_value(&mut args, arg)?); }
+                "--stub-ly" => { config.stub_ly = true; }
+                "--expect" => {
+                    let name = next_value(&mut args, arg)?;
+                    config.test_detectors.push(Detector::from_name(&name)
+                        .ok_or_else(|| EmulatorError::InvalidArgument(format!("unknown detector '{}', expected one of: {}", name, test_runner::DETECTOR_NAMES)))?);
+                }
+                "--timeout" => {
+                    let value = next_value(&mut args, arg)?;
+                    config.test_timeout = value.parse()
+                        .map_err(|_| EmulatorError::InvalidArgument(format!("'{}' is not a number of seconds", value)))?;
+                }
+                "--gdb" => {
+                    let port = next_value(&mut args, arg)?;
+                    config.gdb_port = Some(port.parse()
+                        .map_err(|_| EmulatorError::InvalidArgument(format!("'{}' is not a port", port)))?);
+                }
+                "--from" => {
+                    let value = next_value(&mut args, arg)?;
+                    let (bank, address) = match value.split_once(':') {
+                        Some((bank, address)) => (Some(parse_hex(bank)?), parse_hex(address)?),
+                        None => (None, parse_hex(&value)?)
+                    };
+                    config.disasm_bank = bank.unwrap_or(if address >= 0x4000 { 1 } else { 0 });
+                    config.disasm_from = address;
+                }
+                "--to" => { disasm_to = Some(parse_hex(&next_value(&mut args, arg)?)?); }
+                _ if arg.starts_with("--") => {
+                    return Err(EmulatorError::InvalidArgument(format!("unknown option '{}'\n\n{}", arg, USAGE)))
+                }
+                _ => { config.rom_path = arg.clone(); }
+            }
+        }
+
+        if matches!(config.mode, Mode::Diff) && config.reference_path.is_none() {
+            return Err(EmulatorError::InvalidArgument("the diff mode needs a --reference log".to_string()))
+        }
+
+        if config.record_movie_path.is_some() && config.play_movie_path.is_some() {
+            return Err(EmulatorError::InvalidArgument("a movie can't be recorded and played at once".to_string()))
+        }
+        if config.record_movie_path.is_some() && !matches!(config.mode, Mode::Main) {
+            return Err(EmulatorError::InvalidArgument("movies are recorded from the keyboard in the main mode".to_string()))
+        }
+        if config.play_movie_path.is_some() && !matches!(config.mode, Mode::Main | Mode::Debug | Mode::Test) {
+            return Err(EmulatorError::InvalidArgument("movies are played in the main, debug or test mode".to_string()))
+        }
+        if config.movie_state_slot.is_some() && config.record_movie_path.is_none() {
+            return Err(EmulatorError::InvalidArgument("--movie-state needs --record-movie".to_string()))
+        }
+
+        // The range stays within the 16 KiB region of the starting address
+        config.disasm_to = disasm_to.unwrap_or(config.disasm_from | 0x3FFF);
+        if config.disasm_to < config.disasm_from {
+            return Err(EmulatorError::InvalidArgument(format!("disasm range ends at {:04X} before it starts at {:04X}", config.disasm_to, config.disasm_from)))
+        }
+
+        Ok(config)
+    }
+}
+
+pub fn parse_hex(value: &str) -> Result<u16, EmulatorError> {
+    let digits = value.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16)
+        .map_err(|_| EmulatorError::InvalidArgument(format!("'{}' is not a hex address", value)))
+}
+
+fn next_value(args: &mut std::slice::Iter<String>, option: &str) -> Result<String, EmulatorError> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| EmulatorError::InvalidArgument(format!("missing value for '{}'", option)))
+}
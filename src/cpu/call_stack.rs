@@ -0,0 +1,152 @@
+// Shadow copy of the return addresses on the stack, kept from CALL, RST and interrupt entries
+// and RET/RETI exits so the debugger can show where the code came from
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    // Dispatched to the given vector
+    Interrupt(u16)
+}
+
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // Address of the call instruction, or of the instruction the interrupt came before
+    pub caller: u16,
+    pub target: u16,
+    pub return_address: u16,
+    // Where the return address was pushed
+    pub sp: u16
+}
+
+// A return that didn't pop the return address of the innermost frame
+pub struct Imbalance {
+    pub expected: Option<Frame>,
+    pub found: u16,
+    pub sp: u16
+}
+
+pub struct CallStack {
+    frames: Vec<Frame>
+}
+
+// Deeper stacks are runaway recursion or stack tricks, the oldest frames are dropped
+const MAX_FRAMES: usize = 1024;
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack { frames: Vec::new() }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn interrupt_depth(&self) -> usize {
+        self.frames.iter().filter(|frame| matches!(frame.kind, FrameKind::Interrupt(_))).count()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn enter(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_FRAMES { self.frames.remove(0); }
+        self.frames.push(frame);
+    }
+
+    // Called before a return pops address from sp
+    pub fn exit(&mut self, sp: u16, address: u16) -> Option<Imbalance> {
+        // Frames whose return address was already dropped from the stack, by moving SP, are gone
+        let discarded = self.frames.iter().rposition(|frame| frame.sp >= sp).map_or(self.frames.len(), |index| self.frames.len() - index - 1);
+        let expected = self.frames.last().copied();
+        self.frames.truncate(self.frames.len() - discarded);
+
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp && frame.return_address == address && discarded == 0 => {
+                self.frames.pop();
+                None
+            }
+            Some(frame) if frame.sp == sp => {
+                // Returned to a patched or different address, the frame still ends here
+                self.frames.pop();
+                Some(Imbalance { expected, found: address, sp })
+            }
+            // Popping something pushed after the last call, like a jump table's push hl / ret
+            _ => Some(Imbalance { expected, found: address, sp })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(stack: &mut CallStack, caller: u16, sp: u16) {
+        stack.enter(Frame { kind: FrameKind::Call, caller, target: 0x4000, return_address: caller + 3, sp });
+    }
+
+    #[test]
+    fn matching_return_pops_the_frame() {
+        let mut stack = CallStack::new();
+        call(&mut stack, 0x0150, 0xCFFE);
+        call(&mut stack, 0x4010, 0xCFFC);
+
+        assert!(stack.exit(0xCFFC, 0x4013).is_none());
+        assert!(stack.exit(0xCFFE, 0x0153).is_none());
+        assert!(stack.frames().is_empty());
+    }
+
+    #[test]
+    fn patched_return_address_still_ends_the_frame() {
+        let mut stack = CallStack::new();
+        call(&mut stack, 0x0150, 0xCFFE);
+
+        let imbalance = stack.exit(0xCFFE, 0x2000).unwrap();
+        assert_eq!(imbalance.expected.map(|frame| frame.caller), Some(0x0150));
+        assert_eq!((imbalance.found, imbalance.sp), (0x2000, 0xCFFE));
+        assert!(stack.frames().is_empty());
+    }
+
+    #[test]
+    fn frames_dropped_by_moving_sp_are_discarded() {
+        let mut stack = CallStack::new();
+        call(&mut stack, 0x0150, 0xCFFE);
+        call(&mut stack, 0x4010, 0xCFFC);
+
+        // Returns from the outer call with the inner frame still on the shadow stack
+        let imbalance = stack.exit(0xCFFE, 0x0153).unwrap();
+        assert_eq!(imbalance.expected.map(|frame| frame.caller), Some(0x4010));
+        assert!(stack.frames().is_empty());
+    }
+
+    #[test]
+    fn return_to_a_pushed_address_keeps_the_frame() {
+        let mut stack = CallStack::new();
+        call(&mut stack, 0x0150, 0xCFFE);
+
+        // push hl / ret below the frame of the call
+        let imbalance = stack.exit(0xCFFC, 0x4100).unwrap();
+        assert_eq!(imbalance.found, 0x4100);
+        assert_eq!(stack.frames().len(), 1);
+        assert!(stack.exit(0xCFFE, 0x0153).is_none());
+    }
+
+    #[test]
+    fn return_without_frames() {
+        let mut stack = CallStack::new();
+        let imbalance = stack.exit(0xFFFE, 0x0100).unwrap();
+        assert!(imbalance.expected.is_none());
+    }
+
+    #[test]
+    fn deep_stacks_drop_the_oldest_frames() {
+        let mut stack = CallStack::new();
+        for depth in 0 ..= MAX_FRAMES as u16 {
+            call(&mut stack, depth, 0xDFFE - depth * 2);
+        }
+        assert_eq!(stack.frames().len(), MAX_FRAMES);
+        assert_eq!(stack.frames()[0].caller, 1);
+    }
+}
@@ -0,0 +1,470 @@
+use super::instructions::{
+    Instruction, ArithmeticTarget, ADDHLTarget, IncDecTarget, PrefixTarget, BitPosition, JumpTest,
+    LoadType, LoadByteTarget, LoadByteSource, LoadWordTarget, Indirect, StackTarget
+};
+
+// One decoded instruction in RGBDS syntax
+pub struct Disassembly {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // T-cycles, for conditional instructions when the condition fails
+    pub cycles: u8,
+    // T-cycles of conditional instructions when the branch is taken
+    pub branch_cycles: Option<u8>
+}
+
+impl Disassembly {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn cycles_text(&self) -> String {
+        match self.branch_cycles {
+            Some(taken) => format!("{}/{}", taken, self.cycles),
+            None => format!("{}", self.cycles)
+        }
+    }
+}
+
+// Names from hardware.inc, used for ldh operands and IO addresses
+pub fn io_register_name(address: u16) -> Option<&'static str> {
+    let name = match address {
+        0xFF00 => "rP1",
+        0xFF01 => "rSB",
+        0xFF02 => "rSC",
+        0xFF04 => "rDIV",
+        0xFF05 => "rTIMA",
+        0xFF06 => "rTMA",
+        0xFF07 => "rTAC",
+        0xFF0F => "rIF",
+        0xFF10 => "rNR10",
+        0xFF11 => "rNR11",
+        0xFF12 => "rNR12",
+        0xFF13 => "rNR13",
+        0xFF14 => "rNR14",
+        0xFF16 => "rNR21",
+        0xFF17 => "rNR22",
+        0xFF18 => "rNR23",
+        0xFF19 => "rNR24",
+        0xFF1A => "rNR30",
+        0xFF1B => "rNR31",
+        0xFF1C => "rNR32",
+        0xFF1D => "rNR33",
+        0xFF1E => "rNR34",
+        0xFF20 => "rNR41",
+        0xFF21 => "rNR42",
+        0xFF22 => "rNR43",
+        0xFF23 => "rNR44",
+        0xFF24 => "rNR50",
+        0xFF25 => "rNR51",
+        0xFF26 => "rNR52",
+        0xFF40 => "rLCDC",
+        0xFF41 => "rSTAT",
+        0xFF42 => "rSCY",
+        0xFF43 => "rSCX",
+        0xFF44 => "rLY",
+        0xFF45 => "rLYC",
+        0xFF46 => "rDMA",
+        0xFF47 => "rBGP",
+        0xFF48 => "rOBP0",
+        0xFF49 => "rOBP1",
+        0xFF4A => "rWY",
+        0xFF4B => "rWX",
+        0xFF4C => "rKEY0",
+        0xFF4D => "rKEY1",
+        0xFF4F => "rVBK",
+        0xFF50 => "rBANK",
+        0xFF51 => "rHDMA1",
+        0xFF52 => "rHDMA2",
+        0xFF53 => "rHDMA3",
+        0xFF54 => "rHDMA4",
+        0xFF55 => "rHDMA5",
+        0xFF56 => "rRP",
+        0xFF68 => "rBCPS",
+        0xFF69 => "rBCPD",
+        0xFF6A => "rOCPS",
+        0xFF6B => "rOCPD",
+        0xFF6C => "rOPRI",
+        0xFF70 => "rSVBK",
+        0xFF76 => "rPCM12",
+        0xFF77 => "rPCM34",
+        0xFFFF => "rIE",
+        _ => return None
+    };
+
+    Some(name)
+}
+
+// Decodes the instruction at address, reading memory through read
+pub fn disassemble(read: impl Fn(u16) -> u8, address: u16) -> Disassembly {
+    disassemble_with_labels(read, address, |_| None)
+}
+
+// Like disassemble, with jump targets and memory operands named by label where it has one
+pub fn disassemble_with_labels(read: impl Fn(u16) -> u8, address: u16, label: impl Fn(u16) -> Option<String>) -> Disassembly {
+    let opcode = read(address);
+    let prefixed = opcode == 0xCB;
+    let instruction_byte = if prefixed { read(address.wrapping_add(1)) } else { opcode };
+
+    let byte = read(address.wrapping_add(1));
+    let word = (byte as u16) | ((read(address.wrapping_add(2)) as u16) << 8);
+
+    let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) else {
+        return Disassembly { address, bytes: vec![opcode], text: format!("db ${:02X}", opcode), cycles: 4, branch_cycles: None }
+    };
+
+    let (text, length, cycles, branch_cycles) = if prefixed {
+        let (text, cycles) = prefixed_text(&instruction);
+        (text, 2, cycles, None)
+    } else {
+        let target = |target: u16| label(target).unwrap_or_else(|| format!("${:04X}", target));
+        describe(&instruction, address, byte, word, &target)
+    };
+
+    let bytes = (0 .. length).map(|offset| read(address.wrapping_add(offset))).collect();
+
+    Disassembly { address, bytes, text, cycles, branch_cycles }
+}
+
+fn describe(instruction: &Instruction, address: u16, byte: u8, word: u16, target: &dyn Fn(u16) -> String) -> (String, u16, u8, Option<u8>) {
+    let relative = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+
+    match instruction {
+        Instruction::ADD(target) => arithmetic("add a, ", target, byte),
+        Instruction::ADC(target) => arithmetic("adc a, ", target, byte),
+        Instruction::SUB(target) => arithmetic("sub a, ", target, byte),
+        Instruction::SBC(target) => arithmetic("sbc a, ", target, byte),
+        Instruction::AND(target) => arithmetic("and a, ", target, byte),
+        Instruction::OR(target) => arithmetic("or a, ", target, byte),
+        Instruction::XOR(target) => arithmetic("xor a, ", target, byte),
+        Instruction::CP(target) => arithmetic("cp a, ", target, byte),
+        Instruction::ADDHL(target) => {
+            let register = match target {
+                ADDHLTarget::BC => "bc",
+                ADDHLTarget::DE => "de",
+                ADDHLTarget::HL => "hl",
+                ADDHLTarget::SP => "sp"
+            };
+            (format!("add hl, {}", register), 1, 8, None)
+        }
+        Instruction::ADDSP => (format!("add sp, {}", byte as i8), 2, 16, None),
+        Instruction::INC(target) => inc_dec("inc", target),
+        Instruction::DEC(target) => inc_dec("dec", target),
+        Instruction::CCF => ("ccf".to_string(), 1, 4, None),
+        Instruction::SCF => ("scf".to_string(), 1, 4, None),
+        Instruction::CPL => ("cpl".to_string(), 1, 4, None),
+        Instruction::DAA => ("daa".to_string(), 1, 4, None),
+        Instruction::RLCA => ("rlca".to_string(), 1, 4, None),
+        Instruction::RLA => ("rla".to_string(), 1, 4, None),
+        Instruction::RRCA => ("rrca".to_string(), 1, 4, None),
+        Instruction::RRA => ("rra".to_string(), 1, 4, None),
+        Instruction::JP(test) => match condition(test) {
+            Some(condition) => (format!("jp {}, {}", condition, target(word)), 3, 12, Some(16)),
+            None => (format!("jp {}", target(word)), 3, 16, None)
+        },
+        Instruction::JR(test) => match condition(test) {
+            Some(condition) => (format!("jr {}, {}", condition, target(relative)), 2, 8, Some(12)),
+            None => (format!("jr {}", target(relative)), 2, 12, None)
+        },
+        Instruction::JPI => ("jp hl".to_string(), 1, 4, None),
+        Instruction::LD(load) => load_text(load, byte, word, target),
+        Instruction::PUSH(target) => (format!("push {}", stack_register(target)), 1, 16, None),
+        Instruction::POP(target) => (format!("pop {}", stack_register(target)), 1, 12, None),
+        Instruction::CALL(test) => match condition(test) {
+            Some(condition) => (format!("call {}, {}", condition, target(word)), 3, 12, Some(24)),
+            None => (format!("call {}", target(word)), 3, 24, None)
+        },
+        Instruction::RET(test) => match condition(test) {
+            Some(condition) => (format!("ret {}", condition), 1, 8, Some(20)),
+            None => ("ret".to_string(), 1, 16, None)
+        },
+        Instruction::RST(location) => (format!("rst ${:02X}", location.to_hex()), 1, 16, None),
+        Instruction::HALT => ("halt".to_string(), 1, 4, None),
+        Instruction::STOP => ("stop".to_string(), 2, 4, None),
+        Instruction::NOP => ("nop".to_string(), 1, 4, None),
+        Instruction::DI => ("di".to_string(), 1, 4, None),
+        Instruction::EI => ("ei".to_string(), 1, 4, None),
+        Instruction::RETI => ("reti".to_string(), 1, 16, None),
+        // Only decoded after a 0xCB prefix
+        _ => {
+            let (text, cycles) = prefixed_text(instruction);
+            (text, 2, cycles, None)
+        }
+    }
+}
+
+fn prefixed_text(instruction: &Instruction) -> (String, u8) {
+    let (mnemonic, target, bit) = match instruction {
+        Instruction::BIT(target, bit) => ("bit", target, Some(bit)),
+        Instruction::RES(target, bit) => ("res", target, Some(bit)),
+        Instruction::SET(target, bit) => ("set", target, Some(bit)),
+        Instruction::SRL(target) => ("srl", target, None),
+        Instruction::RR(target) => ("rr", target, None),
+        Instruction::RL(target) => ("rl", target, None),
+        Instruction::RRC(target) => ("rrc", target, None),
+        Instruction::RLC(target) => ("rlc", target, None),
+        Instruction::SRA(target) => ("sra", target, None),
+        Instruction::SLA(target) => ("sla", target, None),
+        Instruction::SWAP(target) => ("swap", target, None),
+        _ => return (format!("{:?}", instruction), 8)
+    };
+
+    let operand = match target {
+        PrefixTarget::A => "a",
+        PrefixTarget::B => "b",
+        PrefixTarget::C => "c",
+        PrefixTarget::D => "d",
+        PrefixTarget::E => "e",
+        PrefixTarget::H => "h",
+        PrefixTarget::L => "l",
+        PrefixTarget::HL => "[hl]"
+    };
+    let memory = matches!(target, PrefixTarget::HL);
+    // BIT only reads [hl], the others write it back
+    let cycles = match (memory, bit.is_some() && mnemonic == "bit") {
+        (false, _) => 8,
+        (true, true) => 12,
+        (true, false) => 16
+    };
+
+    match bit {
+        Some(bit) => (format!("{} {}, {}", mnemonic, bit_number(bit), operand), cycles),
+        None => (format!("{} {}", mnemonic, operand), cycles)
+    }
+}
+
+fn bit_number(bit: &BitPosition) -> u8 {
+    match bit {
+        BitPosition::B0 => 0,
+        BitPosition::B1 => 1,
+        BitPosition::B2 => 2,
+        BitPosition::B3 => 3,
+        BitPosition::B4 => 4,
+        BitPosition::B5 => 5,
+        BitPosition::B6 => 6,
+        BitPosition::B7 => 7
+    }
+}
+
+fn arithmetic(mnemonic: &str, target: &ArithmeticTarget, byte: u8) -> (String, u16, u8, Option<u8>) {
+    let (operand, length, cycles) = match target {
+        ArithmeticTarget::A => ("a".to_string(), 1, 4),
+        ArithmeticTarget::B => ("b".to_string(), 1, 4),
+        ArithmeticTarget::C => ("c".to_string(), 1, 4),
+        ArithmeticTarget::D => ("d".to_string(), 1, 4),
+        ArithmeticTarget::E => ("e".to_string(), 1, 4),
+        ArithmeticTarget::H => ("h".to_string(), 1, 4),
+        ArithmeticTarget::L => ("l".to_string(), 1, 4),
+        ArithmeticTarget::HL => ("[hl]".to_string(), 1, 8),
+        ArithmeticTarget::D8 => (format!("${:02X}", byte), 2, 8)
+    };
+
+    (format!("{}{}", mnemonic, operand), length, cycles, None)
+}
+
+fn inc_dec(mnemonic: &str, target: &IncDecTarget) -> (String, u16, u8, Option<u8>) {
+    let (operand, cycles) = match target {
+        IncDecTarget::A => ("a", 4),
+        IncDecTarget::B => ("b", 4),
+        IncDecTarget::C => ("c", 4),
+        IncDecTarget::D => ("d", 4),
+        IncDecTarget::E => ("e", 4),
+        IncDecTarget::H => ("h", 4),
+        IncDecTarget::L => ("l", 4),
+        IncDecTarget::BC => ("bc", 8),
+        IncDecTarget::DE => ("de", 8),
+        IncDecTarget::HL => ("hl", 8),
+        IncDecTarget::SP => ("sp", 8),
+        IncDecTarget::HLI => ("[hl]", 12)
+    };
+
+    (format!("{} {}", mnemonic, operand), 1, cycles, None)
+}
+
+fn condition(test: &JumpTest) -> Option<&'static str> {
+    match test {
+        JumpTest::NotZero => Some("nz"),
+        JumpTest::Zero => Some("z"),
+        JumpTest::NotCarry => Some("nc"),
+        JumpTest::Carry => Some("c"),
+        JumpTest::Always => None
+    }
+}
+
+fn stack_register(target: &StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl"
+    }
+}
+
+// ldh operands use the register name when there is one
+fn high_address(byte: u8) -> String {
+    let address = 0xFF00 | byte as u16;
+    match io_register_name(address) {
+        Some(name) => format!("[{}]", name),
+        None => format!("[${:04X}]", address)
+    }
+}
+
+fn absolute_address(word: u16, target: &dyn Fn(u16) -> String) -> String {
+    match io_register_name(word) {
+        Some(name) => format!("[{}]", name),
+        None => format!("[{}]", target(word))
+    }
+}
+
+fn indirect(source: &Indirect, word: u16, target: &dyn Fn(u16) -> String) -> (String, u16) {
+    match source {
+        Indirect::BCIndirect => ("[bc]".to_string(), 1),
+        Indirect::DEIndirect => ("[de]".to_string(), 1),
+        Indirect::HLIndirectMinus => ("[hl-]".to_string(), 1),
+        Indirect::HLIndirectPlus => ("[hl+]".to_string(), 1),
+        Indirect::WordIndirect => (absolute_address(word, target), 3),
+        Indirect::LastByteIndirect => ("[c]".to_string(), 1)
+    }
+}
+
+fn load_text(load: &LoadType, byte: u8, word: u16, target: &dyn Fn(u16) -> String) -> (String, u16, u8, Option<u8>) {
+    match load {
+        LoadType::Byte(destination, source) => {
+            let target_text = match destination {
+                LoadByteTarget::A => "a",
+                LoadByteTarget::B => "b",
+                LoadByteTarget::C => "c",
+                LoadByteTarget::D => "d",
+                LoadByteTarget::E => "e",
+                LoadByteTarget::H => "h",
+                LoadByteTarget::L => "l",
+                LoadByteTarget::HL => "[hl]"
+            };
+            let (source_text, length) = match source {
+                LoadByteSource::A => ("a".to_string(), 1),
+                LoadByteSource::B => ("b".to_string(), 1),
+                LoadByteSource::C => ("c".to_string(), 1),
+                LoadByteSource::D => ("d".to_string(), 1),
+                LoadByteSource::E => ("e".to_string(), 1),
+                LoadByteSource::H => ("h".to_string(), 1),
+                LoadByteSource::L => ("l".to_string(), 1),
+                LoadByteSource::HL => ("[hl]".to_string(), 1),
+                LoadByteSource::D8 => (format!("${:02X}", byte), 2)
+            };
+            let memory = matches!(destination, LoadByteTarget::HL) || matches!(source, LoadByteSource::HL);
+            let cycles = match (memory, length) {
+                (false, 1) => 4,
+                (true, 2) => 12,
+                _ => 8
+            };
+
+            (format!("ld {}, {}", target_text, source_text), length, cycles, None)
+        }
+        LoadType::Word(register) => {
+            let register = match register {
+                LoadWordTarget::BC => "bc",
+                LoadWordTarget::DE => "de",
+                LoadWordTarget::HL => "hl",
+                LoadWordTarget::SP => "sp"
+            };
+            (format!("ld {}, ${:04X}", register, word), 3, 12, None)
+        }
+        LoadType::AFromIndirect(source) => {
+            let (operand, length) = indirect(source, word, target);
+            let mnemonic = if matches!(source, Indirect::LastByteIndirect) { "ldh" } else { "ld" };
+            (format!("{} a, {}", mnemonic, operand), length, if length == 3 { 16 } else { 8 }, None)
+        }
+        LoadType::IndirectFromA(destination) => {
+            let (operand, length) = indirect(destination, word, target);
+            let mnemonic = if matches!(destination, Indirect::LastByteIndirect) { "ldh" } else { "ld" };
+            (format!("{} {}, a", mnemonic, operand), length, if length == 3 { 16 } else { 8 }, None)
+        }
+        LoadType::AFromByteAddress => (format!("ldh a, {}", high_address(byte)), 2, 12, None),
+        LoadType::ByteAddressFromA => (format!("ldh {}, a", high_address(byte)), 2, 12, None),
+        LoadType::SPFromHL => ("ld sp, hl".to_string(), 1, 8, None),
+        LoadType::WordFromSP => (format!("ld [{}], sp", target(word)), 3, 20, None),
+        LoadType::HLFromSPPlus => {
+            let offset = byte as i8;
+            let sign = if offset < 0 { "-" } else { "+" };
+            (format!("ld hl, sp{}{}", sign, offset.unsigned_abs()), 2, 12, None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Disassembles the bytes as if they were at address
+    fn disassemble_at(address: u16, bytes: &[u8]) -> Disassembly {
+        disassemble(|at| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0), address)
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        disassemble_at(0x0150, bytes).text
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(text(&[0x00]), "nop");
+        assert_eq!(text(&[0x3E, 0x05]), "ld a, $05");
+        assert_eq!(text(&[0x31, 0xFF, 0xDF]), "ld sp, $DFFF");
+        assert_eq!(text(&[0xAF]), "xor a, a");
+        assert_eq!(text(&[0xFE, 0x90]), "cp a, $90");
+        assert_eq!(text(&[0x34]), "inc [hl]");
+        assert_eq!(text(&[0xF5]), "push af");
+        assert_eq!(text(&[0xE8, 0xFE]), "add sp, -2");
+    }
+
+    #[test]
+    fn io_registers_by_name() {
+        assert_eq!(text(&[0xE0, 0x40]), "ldh [rLCDC], a");
+        assert_eq!(text(&[0xF0, 0x44]), "ldh a, [rLY]");
+        assert_eq!(text(&[0xE0, 0x80]), "ldh [$FF80], a");
+    }
+
+    #[test]
+    fn branch_targets() {
+        assert_eq!(text(&[0xC3, 0x00, 0x40]), "jp $4000");
+        // Relative to the address after the instruction
+        assert_eq!(text(&[0x18, 0xFE]), "jr $0150");
+        assert_eq!(text(&[0x20, 0x10]), "jr nz, $0162");
+        assert_eq!(text(&[0xCD, 0x34, 0x12]), "call $1234");
+        assert_eq!(text(&[0xFF]), "rst $38");
+    }
+
+    #[test]
+    fn branch_targets_by_label() {
+        let bytes = [0xCD, 0x00, 0x20];
+        let read = |at: u16| bytes.get(at as usize).copied().unwrap_or(0);
+        let label = |target: u16| (target == 0x2000).then(|| "Init".to_string());
+        assert_eq!(disassemble_with_labels(read, 0, label).text, "call Init");
+    }
+
+    #[test]
+    fn prefixed_instructions() {
+        let line = disassemble_at(0x0150, &[0xCB, 0x7C]);
+        assert_eq!(line.text, "bit 7, h");
+        assert_eq!(line.length(), 2);
+        assert_eq!(text(&[0xCB, 0x37]), "swap a");
+        assert_eq!(disassemble_at(0x0150, &[0xCB, 0x46]).cycles, 12);
+        assert_eq!(disassemble_at(0x0150, &[0xCB, 0xC6]).cycles, 16);
+    }
+
+    #[test]
+    fn lengths_and_cycles() {
+        let line = disassemble_at(0x0150, &[0xC2, 0x00, 0x40]);
+        assert_eq!(line.length(), 3);
+        assert_eq!(line.bytes, vec![0xC2, 0x00, 0x40]);
+        assert_eq!(line.cycles_text(), "16/12");
+        assert_eq!(disassemble_at(0x0150, &[0xC9]).cycles_text(), "16");
+    }
+
+    #[test]
+    fn illegal_opcodes_are_data() {
+        let line = disassemble_at(0x0150, &[0xD3]);
+        assert_eq!(line.text, "db $D3");
+        assert_eq!(line.length(), 1);
+    }
+}
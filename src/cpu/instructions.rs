@@ -55,6 +55,7 @@ pub enum Instruction {
 
     // Control Instructions
     HALT,
+    STOP,
     NOP,
     DI,
     EI,
@@ -439,7 +440,7 @@ impl Instruction {
     fn from_byte_not_prefixed(byte: u8) ->Option<Instruction> {
         match byte {
             0x00 => Some(Instruction::NOP),
-            //0x10 => Some(Instruction::STOP),
+            0x10 => Some(Instruction::STOP),
             0x20 => Some(Instruction::JR(JumpTest::NotZero)),
             0x30 => Some(Instruction::JR(JumpTest::NotCarry)),
             0x40 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::B))),
@@ -1,9 +1,16 @@
+pub mod call_stack;
+pub mod disassembler;
 pub mod flags_register;
 pub mod instructions;
 pub mod registers;
 
 use crate::memory_bus::MemoryBus;
+use crate::event::Event;
+use crate::code_data_log;
+use crate::model::Model;
+use crate::save_state::{StateWriter, StateReader};
 use self::registers::Registers;
+use self::call_stack::{CallStack, Frame, FrameKind};
 use self::instructions::Instruction;
 
 use self::instructions::ArithmeticTarget;
@@ -25,14 +32,32 @@ pub const TIMER: u16 = 0x50;
 pub const SERIAL: u16 = 0x58;
 pub const JOYPAD: u16 = 0x60;
 
+pub fn interrupt_name(vector: u16) -> &'static str {
+    match vector {
+        VBLANK => "VBlank",
+        STAT => "STAT",
+        TIMER => "Timer",
+        SERIAL => "Serial",
+        JOYPAD => "Joypad",
+        _ => "?"
+    }
+}
+
 pub struct CPU { 
     pub registers: Registers,
     pub pc: u16,
     pub sp: u16,
     pub bus: MemoryBus,
     pub is_halted: bool,
+    pub is_stopped: bool,
+    pub is_locked: bool,
     pub is_booted: bool,
-    pub ime: bool
+    pub ime: bool,
+    pub call_stack: CallStack,
+    // EI only takes effect after the instruction following it
+    ime_scheduled: bool,
+    // HALT with IME=0 and a pending interrupt fails to increment PC on the next fetch
+    halt_bug: bool
 }
 
 impl CPU {
@@ -43,31 +68,132 @@ impl CPU {
             sp: 0x00,
             bus: bus,
             is_halted: false,
+            is_stopped: false,
+            is_locked: false,
             is_booted: false,
-            ime: false
+            ime: false,
+            call_stack: CallStack::new(),
+            ime_scheduled: false,
+            halt_bug: false
         }
     }
 
+    // Halted, stopped and locked steps only run the rest of the system, no instruction
+    pub fn fetches_instruction(&self) -> bool {
+        !self.is_halted && !self.is_stopped && !self.is_locked
+    }
+
+    // Starts at 0x0100 with the registers the boot ROM of the given model hands over
+    pub fn skip_boot(&mut self, model: Model) {
+        let header_checksum = self.bus.read_byte(0x014D);
+        let cgb_game = (self.bus.read_byte(0x0143) & 0x80) != 0;
+        // DMG and MGB leave H and C set unless the header checksum is zero
+        let checksum_flags: u16 = if header_checksum != 0 { 0x30 } else { 0x00 };
+
+        let (af, bc, de, hl) = match model {
+            Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            Model::DMG => (0x0180 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::MGB => (0xFF80 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::CGB0 | Model::CGB => {
+                if cgb_game {
+                    (0x1180, 0x0000, 0xFF56, 0x000D)
+                } else {
+                    (0x1180, 0x0000, 0x0008, 0x007C)
+                }
+            }
+            // The extra inc b of the AGB boot ROM sets B and clears the flags
+            Model::AGB => {
+                if cgb_game {
+                    (0x1100, 0x0100, 0xFF56, 0x000D)
+                } else {
+                    (0x1100, 0x0100, 0x0008, 0x007C)
+                }
+            }
+        };
+
+        self.registers.set_af(af);
+        self.registers.set_bc(bc);
+        self.registers.set_de(de);
+        self.registers.set_hl(hl);
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+        self.is_booted = true;
+
+        self.bus.skip_boot(model);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for register in [self.registers.get_af(), self.registers.get_bc(), self.registers.get_de(), self.registers.get_hl(), self.pc, self.sp] {
+            state.u16(register);
+        }
+        for flag in [self.is_halted, self.is_stopped, self.is_locked, self.is_booted, self.ime, self.ime_scheduled, self.halt_bug] {
+            state.bool(flag);
+        }
+    }
+
+    // The shadow call stack isn't part of the state, it starts over from the loaded PC
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.registers.set_af(state.u16());
+        self.registers.set_bc(state.u16());
+        self.registers.set_de(state.u16());
+        self.registers.set_hl(state.u16());
+        self.pc = state.u16();
+        self.sp = state.u16();
+        self.is_halted = state.bool();
+        self.is_stopped = state.bool();
+        self.is_locked = state.bool();
+        self.is_booted = state.bool();
+        self.ime = state.bool();
+        self.ime_scheduled = state.bool();
+        self.halt_bug = state.bool();
+        self.call_stack.clear();
+    }
 
     pub fn step(&mut self) -> u8 {
         let mut next_pc = self.pc;
-        let mut cycles = 0;
+        let cycles = 4;
+        let enable_interrupts = self.ime_scheduled;
+
+        // A locked CPU never fetches again while the rest of the system keeps running
+        if self.is_locked {
+            self.bus.step(cycles);
+            return cycles
+        }
 
+        if self.is_stopped {
+            // STOP mode is only left when a selected joypad line goes low
+            if self.bus.joypad.is_any_selected_pressed() { self.is_stopped = false; }
+            self.bus.step_stopped(cycles);
+            return cycles
+        }
 
         if !self.is_halted {
+            self.bus.step_hblank_dma();
+
             let mut instruction_byte = self.bus.read_byte(self.pc);
-            if instruction_byte == 0x40 { println!("debug breakpoint"); }
+            self.bus.log_access(self.pc, code_data_log::CODE | code_data_log::OPCODE);
+            if self.halt_bug {
+                // The opcode byte is read again as the next byte
+                self.halt_bug = false;
+                self.pc = self.pc.wrapping_sub(1);
+            }
             let prefixed = instruction_byte == 0xCB;
             if prefixed {
                 let previous = instruction_byte;
-                instruction_byte = self.bus.read_byte(self.pc + 1);
+                instruction_byte = self.bus.read_byte(self.pc.wrapping_add(1));
+                self.bus.log_access(self.pc.wrapping_add(1), code_data_log::CODE);
             }
                 
-            (next_pc, cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
+            (next_pc, _) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
                 self.execute(instruction)
             } else {
-                let description = format!("0x{}{:2x}", if prefixed { "cb" } else { "" }, instruction_byte);
-                panic!("Unkown instruction found: {} at 0x{:4x}", description, self.pc);
+                // The fetch of the illegal opcode still takes its M-cycle
+                self.bus.step(cycles);
+                self.is_locked = true;
+                self.bus.push_event(Event::CpuLocked { opcode: instruction_byte, pc: self.pc });
+                return cycles
             };
         }
         
@@ -75,73 +201,92 @@ impl CPU {
         
         if self.bus.interrupted() { self.is_halted = false; }
 
-        if self.is_halted { 
-            let timer_interrupt = self.bus.timer.tick();
-                if timer_interrupt {
-                    self.bus.request_timer_interrupt();
-                }
-        }
         self.pc = next_pc;
 
-        let mut interrupted = false;
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+
         if self.ime && self.bus.interrupted() {
+            if self.halt_bug {
+                // EI followed by HALT: the interrupt returns to the HALT itself
+                self.halt_bug = false;
+                self.pc = self.pc.wrapping_sub(1);
+            }
+
             if self.bus.interrupt_enable.vblank && self.bus.interrupt_flag.vblank {
-                interrupted = true;
                 self.bus.interrupt_flag.vblank = false;
                 self.interrupt(VBLANK)
-            }
-            if self.bus.interrupt_enable.stat && self.bus.interrupt_flag.stat {
-                interrupted = true;
+            } else if self.bus.interrupt_enable.stat && self.bus.interrupt_flag.stat {
                 self.bus.interrupt_flag.stat = false;
                 self.interrupt(STAT)
-            }
-            if self.bus.interrupt_enable.timer && self.bus.interrupt_flag.timer {
-                interrupted = true;
+            } else if self.bus.interrupt_enable.timer && self.bus.interrupt_flag.timer {
                 self.bus.interrupt_flag.timer = false;
                 self.interrupt(TIMER)
-            }
-            if self.bus.interrupt_enable.serial && self.bus.interrupt_flag.serial {
-                interrupted = true;
+            } else if self.bus.interrupt_enable.serial && self.bus.interrupt_flag.serial {
                 self.bus.interrupt_flag.serial = false;
                 self.interrupt(SERIAL)
-            }
-            if self.bus.interrupt_enable.joypad && self.bus.interrupt_flag.joypad {
-                interrupted = true;
+            } else if self.bus.interrupt_enable.joypad && self.bus.interrupt_flag.joypad {
                 self.bus.interrupt_flag.joypad = false;
                 self.interrupt(JOYPAD)
             }
         }
 
-        if interrupted { cycles += 20 }
-
         cycles
     }
 
+    // Dispatching takes 5 M-cycles, all of them ticked here so step keeps returning 4
     fn interrupt(&mut self, location: u16) {
         self.ime = false;
+        self.mid_op_tick();
+        self.mid_op_tick();
+        self.mid_op_tick();
         self.push(self.pc);
+        self.enter_frame(FrameKind::Interrupt(location), self.pc, location, self.pc);
+        self.mid_op_tick();
         self.pc = location;
-        self.bus.step(20);
+        self.mid_op_tick();
     }
 
     fn push(&mut self, value: u16) {
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.bus.cpu_write(self.sp, ((value & 0xFF00) >> 8) as u8);
         
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.bus.cpu_write(self.sp, (value & 0xFF) as u8);
     }
 
     fn pop(&mut self) -> u16 {
-        let least_significant_byte = self.bus.read_byte(self.sp) as u16;
+        let least_significant_byte = self.bus.cpu_read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
         
-        let most_significant_bye = self.bus.read_byte(self.sp) as u16;
+        let most_significant_bye = self.bus.cpu_read(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
         
         (most_significant_bye << 8) | least_significant_byte
     }
 
+    fn enter_frame(&mut self, kind: FrameKind, caller: u16, target: u16, return_address: u16) {
+        self.call_stack.enter(Frame { kind, caller, target, return_address, sp: self.sp });
+        self.bus.log_access(target, code_data_log::SUBROUTINE_ENTRY);
+    }
+
+    // Pops the return address of RET and RETI, checking it against the shadow call stack
+    fn pop_return(&mut self) -> u16 {
+        let found = self.bus.read_byte(self.sp) as u16 | (self.bus.read_byte(self.sp.wrapping_add(1)) as u16) << 8;
+        if let Some(imbalance) = self.call_stack.exit(self.sp, found) {
+            self.bus.push_event(Event::StackImbalance {
+                pc: self.pc,
+                sp: imbalance.sp,
+                expected: imbalance.expected.map(|frame| frame.return_address),
+                found
+            });
+        }
+
+        self.pop()
+    }
+
     fn call(&mut self, should_jump: bool) -> (u16, u8) {
         let next_pc = self.pc.wrapping_add(3);
         self.mid_op_tick();
@@ -150,7 +295,9 @@ impl CPU {
             self.mid_op_tick();
             self.push(next_pc);
             self.mid_op_tick();
-            (self.get_d16(), 4)
+            let target = self.get_d16();
+            self.enter_frame(FrameKind::Call, self.pc, target, next_pc);
+            (target, 4)
         } else {
             self.mid_op_tick();
             (next_pc, 4)
@@ -162,52 +309,33 @@ impl CPU {
             self.mid_op_tick();
             self.mid_op_tick();
             self.mid_op_tick();
-            (self.pop(), 4)
+            (self.pop_return(), 4)
         } else if should_jump {
             self.mid_op_tick();
             self.mid_op_tick();
             self.mid_op_tick();
             self.mid_op_tick();
-            (self.pop(), 4)
+            (self.pop_return(), 4)
         } else {
             (self.pc.wrapping_add(1), 4)
         }
     }
 
     pub fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+        self.bus.read_byte(self.pc.wrapping_add(1))
     }
 
     pub fn read_next_word(&self) -> u16 {
-        ((self.bus.read_byte(self.pc + 2) as u16) << 8) | (self.bus.read_byte(self.pc + 1) as u16)     
+        ((self.bus.read_byte(self.pc.wrapping_add(2)) as u16) << 8) | (self.bus.read_byte(self.pc.wrapping_add(1)) as u16)     
     }
 
     fn mid_op_tick(&mut self) {
-        let mut t_cycles = 4;
-        
-        while t_cycles > 0 {
-            if self.bus.timer.tick() {
-                self.bus.request_timer_interrupt();
-            }
-
-            if self.bus.timer.tick() {
-                self.bus.request_timer_interrupt();
-            }
-
-            if self.bus.timer.tick() {
-                self.bus.request_timer_interrupt();
-            }
-
-            if self.bus.timer.tick() {
-                self.bus.request_timer_interrupt();
-            }
-
-            t_cycles = 0;
-        }
+        self.bus.step(4);
     }
 
     fn get_d8(&mut self) -> u8 {
         self.mid_op_tick();
+        self.bus.log_access(self.pc.wrapping_add(1), code_data_log::CODE);
 
         self.read_next_byte()
     }
@@ -215,12 +343,14 @@ impl CPU {
     fn get_hli(&mut self) -> u8 {
         self.mid_op_tick();
 
-        self.bus.read_byte(self.registers.get_hl())
+        self.bus.cpu_read(self.registers.get_hl())
     }
 
     fn get_d16(&mut self) -> u16 {
         self.mid_op_tick();
         self.mid_op_tick();
+        self.bus.log_access(self.pc.wrapping_add(1), code_data_log::CODE);
+        self.bus.log_access(self.pc.wrapping_add(2), code_data_log::CODE);
 
         self.read_next_word()
     }
@@ -231,9 +361,38 @@ impl CPU {
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::HALT => {
-                self.is_halted = true;
+                if !self.ime && self.bus.interrupted() {
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
+                }
                 (self.pc.wrapping_add(1), 4)
             }
+            Instruction::STOP => {
+                let interrupt_pending = self.bus.interrupted();
+
+                if self.bus.joypad.is_any_selected_pressed() {
+                    // A held button keeps the CPU out of STOP mode and DIV untouched
+                    if interrupt_pending {
+                        (self.pc.wrapping_add(1), 4)
+                    } else {
+                        self.is_halted = true;
+                        (self.pc.wrapping_add(2), 4)
+                    }
+                } else if self.bus.cgb_mode && self.bus.speed_switch_armed {
+                    self.bus.timer.write_div(0);
+                    self.bus.switch_speed();
+                    (self.pc.wrapping_add(2), 4)
+                } else {
+                    self.bus.timer.write_div(0);
+                    self.is_stopped = true;
+                    if interrupt_pending {
+                        (self.pc.wrapping_add(1), 4)
+                    } else {
+                        (self.pc.wrapping_add(2), 4)
+                    }
+                }
+            }
             Instruction::ADD(target) => {
                 match target {
                     ArithmeticTarget::A => {
@@ -798,7 +957,7 @@ impl CPU {
                         let value = self.get_hli();
                         let new_value = self.inc(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(hl, new_value);
+                        self.bus.cpu_write(hl, new_value);
                         (self.pc.wrapping_add(1), 4)
                     }
                 }
@@ -880,7 +1039,7 @@ impl CPU {
                         let value = self.get_hli();
                         let new_value = self.dec(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(hl, new_value);
+                        self.bus.cpu_write(hl, new_value);
                         (self.pc.wrapping_add(1), 4)
                     }
                 }
@@ -1018,7 +1177,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.bit_set(value, bit_position);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }
@@ -1072,7 +1231,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.bit_reset(value, bit_position);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }
@@ -1134,7 +1293,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.swap(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(hl, new_value);
+                        self.bus.cpu_write(hl, new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }
@@ -1196,7 +1355,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.shift_l(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(hl, new_value);
+                        self.bus.cpu_write(hl, new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1258,7 +1417,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.shift_r(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(hl, new_value);
+                        self.bus.cpu_write(hl, new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1318,7 +1477,7 @@ impl CPU {
                         let value = self.get_hli();
                         let new_value = self.shift_r_logical(value);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         self.mid_op_tick();
                         (self.pc.wrapping_add(2), 4)
                     }
@@ -1380,7 +1539,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.rotate_r_flag(value, true);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1441,7 +1600,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.rotate_l_flag(value, true);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1502,7 +1661,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.rotate_r(value, true);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1563,7 +1722,7 @@ impl CPU {
                         self.mid_op_tick();
                         let new_value = self.rotate_l(value, true);
                         self.mid_op_tick();
-                        self.bus.write_byte(self.registers.get_hl(), new_value);
+                        self.bus.cpu_write(self.registers.get_hl(), new_value);
                         (self.pc.wrapping_add(2), 4)
                     }
                 }   
@@ -1620,7 +1779,7 @@ impl CPU {
                             LoadByteTarget::L => self.registers.l = source_value,
                             LoadByteTarget::HL => {
                                 self.mid_op_tick();
-                                self.bus.write_byte(self.registers.get_hl(), source_value)
+                                self.bus.cpu_write(self.registers.get_hl(), source_value)
                             }
                         }
 
@@ -1647,22 +1806,24 @@ impl CPU {
                         self.mid_op_tick();
 
                         self.registers.a = match source {
-                            Indirect::BCIndirect => self.bus.read_byte(self.registers.get_bc()),
-                            Indirect::DEIndirect => self.bus.read_byte(self.registers.get_de()),
+                            Indirect::BCIndirect => self.bus.cpu_read(self.registers.get_bc()),
+                            Indirect::DEIndirect => self.bus.cpu_read(self.registers.get_de()),
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.read_byte(hl)
+                                self.bus.trigger_oam_bug(hl);
+                                self.bus.cpu_read(hl)
                             },
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.read_byte(hl)
+                                self.bus.trigger_oam_bug(hl);
+                                self.bus.cpu_read(hl)
                             },
-                            Indirect::LastByteIndirect => self.bus.read_byte(0xFF00 + self.registers.c as u16),
+                            Indirect::LastByteIndirect => self.bus.cpu_read(0xFF00 + self.registers.c as u16),
                             Indirect::WordIndirect => {
                                 let word = self.get_d16();
-                                self.bus.read_byte(word)
+                                self.bus.cpu_read(word)
                             }
                         };
                     
@@ -1679,29 +1840,31 @@ impl CPU {
                         match target {
                             Indirect::BCIndirect => {
                                 let bc = self.registers.get_bc();
-                                self.bus.write_byte(bc, a)
+                                self.bus.cpu_write(bc, a)
                             }
                             Indirect::DEIndirect => {
                                 let de = self.registers.get_de();
-                                self.bus.write_byte(de, a)
+                                self.bus.cpu_write(de, a)
                             }
                             Indirect::HLIndirectMinus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_sub(1));
-                                self.bus.write_byte(hl, a);
+                                self.bus.trigger_oam_bug(hl);
+                                self.bus.cpu_write(hl, a);
                             }
                             Indirect::HLIndirectPlus => {
                                 let hl = self.registers.get_hl();
                                 self.registers.set_hl(hl.wrapping_add(1));
-                                self.bus.write_byte(hl, a);
+                                self.bus.trigger_oam_bug(hl);
+                                self.bus.cpu_write(hl, a);
                             }
                             Indirect::WordIndirect => {
                                 let word = self.get_d16();
-                                self.bus.write_byte(word, a);
+                                self.bus.cpu_write(word, a);
                             }
                             Indirect::LastByteIndirect => {
                                 let c = self.registers.c as u16;
-                                self.bus.write_byte(0xFF00 + c, a);
+                                self.bus.cpu_write(0xFF00 + c, a);
                             }
                         }
 
@@ -1713,7 +1876,7 @@ impl CPU {
 
                     LoadType::AFromByteAddress => {
                         let offset = self.get_d8() as u16;
-                        self.registers.a = self.bus.read_byte(0xFF00 + offset);
+                        self.registers.a = self.bus.cpu_read(0xFF00 + offset);
                         self.mid_op_tick();
                         (self.pc.wrapping_add(2), 4)
                     }
@@ -1721,7 +1884,7 @@ impl CPU {
                     LoadType::ByteAddressFromA => {
                         let offset = self.get_d8() as u16;
                         self.mid_op_tick();
-                        self.bus.write_byte(0xFF00 + offset, self.registers.a);
+                        self.bus.cpu_write(0xFF00 + offset, self.registers.a);
                         (self.pc.wrapping_add(2), 4)
                     }
 
@@ -1735,9 +1898,9 @@ impl CPU {
                         let address = self.get_d16();
                         let sp = self.sp;
                         
-                        self.bus.write_byte(address, (sp & 0x00FF) as u8);
+                        self.bus.cpu_write(address, (sp & 0x00FF) as u8);
                         self.mid_op_tick();
-                        self.bus.write_byte(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
+                        self.bus.cpu_write(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
                         self.mid_op_tick();
 
                         (self.pc.wrapping_add(3), 4)
@@ -1815,10 +1978,11 @@ impl CPU {
             }
             Instruction::DI => {
                 self.ime = false;
+                self.ime_scheduled = false;
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::EI => {
-                self.ime = true;
+                if !self.ime { self.ime_scheduled = true; }
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::DAA => {
@@ -1846,6 +2010,7 @@ impl CPU {
 
                 self.mid_op_tick();
                 self.push(self.pc.wrapping_add(1));
+                self.enter_frame(FrameKind::Rst, self.pc, target.to_hex(), self.pc.wrapping_add(1));
                 self.mid_op_tick();
 
                 (target.to_hex(), 4)
@@ -1856,7 +2021,7 @@ impl CPU {
 
                 self.mid_op_tick();
 
-                let new_pc = self.pop();
+                let new_pc = self.pop_return();
                 self.mid_op_tick();
 
                 (new_pc, 4)
@@ -1977,6 +2142,7 @@ impl CPU {
 
     pub fn inc16(&mut self, value: u16) -> u16 {
         let new_value = value.wrapping_add(1);
+        self.bus.trigger_oam_bug(value);
 
         new_value
     }
@@ -1993,6 +2159,7 @@ impl CPU {
 
     pub fn dec16(&mut self, value: u16) -> u16 {
         let new_value = value.wrapping_sub(1);
+        self.bus.trigger_oam_bug(value);
 
         new_value
     }
@@ -0,0 +1,399 @@
+use crate::memory_bus::MemoryBus;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// Text is drawn with a 3x5 font in 4x6 cells
+const CHAR_WIDTH: usize = 4;
+const LINE_HEIGHT: usize = 6;
+
+const BACKGROUND: u32 = 0x202020;
+const TEXT: u32 = 0xE0E0E0;
+const HIGHLIGHT: u32 = 0xFFD040;
+// Stands in for color 0 of sprites, which is transparent
+const TRANSPARENT: u32 = 0x404040;
+const VIEWPORT: u32 = 0xFF3030;
+const WINDOW: u32 = 0x3080FF;
+
+const MEMORY_ROWS: usize = 32;
+const TILES_PER_ROW: usize = 16;
+const TILE_ROWS: usize = 24;
+
+// Pixels of a debug view as 0x00RRGGBB, like the PPU framebuffer
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image { width, height, pixels: vec![BACKGROUND; width * height] }
+    }
+
+    fn clear(&mut self) {
+        self.pixels.fill(BACKGROUND);
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height { self.pixels[y * self.width + x] = color; }
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        for row in y .. y + height {
+            for column in x .. x + width { self.set(column, row, color); }
+        }
+    }
+
+    fn text(&mut self, x: usize, y: usize, text: &str, color: u32) {
+        for (index, character) in text.chars().enumerate() {
+            let rows = glyph(character);
+            for (row, bits) in rows.iter().enumerate() {
+                for column in 0 .. 3 {
+                    if (bits >> (2 - column)) & 1 != 0 { self.set(x + index * CHAR_WIDTH + column, y + row, color); }
+                }
+            }
+        }
+    }
+}
+
+// Keys a debug window understands, mapped from the keyboard by the frontend
+pub enum ViewKey {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    NextBank,
+    PreviousBank,
+    HexDigit(u8),
+    Confirm,
+    Cancel
+}
+
+pub enum View {
+    // Hex dump from address, of the given bank or whichever is mapped
+    Memory { address: u16, bank: Option<u16>, input: String },
+    // All 384 tiles of both VRAM banks, in the CGB palette with the given index, 8-15 are OBJ palettes
+    Tiles { palette: usize },
+    TileMaps,
+    Oam,
+    Palettes
+}
+
+impl View {
+    pub fn title(&self) -> &'static str {
+        match self {
+            View::Memory { .. } => "Memory",
+            View::Tiles { .. } => "Tiles",
+            View::TileMaps => "BG and window maps",
+            View::Oam => "OAM",
+            View::Palettes => "Palettes"
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            View::Memory { .. } => (74 * CHAR_WIDTH, (MEMORY_ROWS + 2) * LINE_HEIGHT),
+            View::Tiles { .. } => (TILES_PER_ROW * 8 * 2 + 8, TILE_ROWS * 8 + LINE_HEIGHT * 2),
+            View::TileMaps => (256 * 2 + 8, 256 + LINE_HEIGHT * 2),
+            View::Oam => (2 * 104, 20 * 18 + LINE_HEIGHT * 2),
+            View::Palettes => (40 * CHAR_WIDTH, 21 * 12)
+        }
+    }
+
+    pub fn handle_key(&mut self, bus: &MemoryBus, key: ViewKey) {
+        match self {
+            View::Memory { address, bank, input } => match key {
+                ViewKey::Up => *address = address.wrapping_sub(0x10),
+                ViewKey::Down => *address = address.wrapping_add(0x10),
+                ViewKey::PageUp => *address = address.wrapping_sub((MEMORY_ROWS * 0x10) as u16),
+                ViewKey::PageDown => *address = address.wrapping_add((MEMORY_ROWS * 0x10) as u16),
+                ViewKey::NextBank | ViewKey::PreviousBank => {
+                    let banks = bus.banks_at(*address);
+                    let current = bank.unwrap_or_else(|| bus.bank_at(*address)).clamp(*banks.start(), *banks.end());
+                    let next = match key {
+                        ViewKey::NextBank if current == *banks.end() => *banks.start(),
+                        ViewKey::NextBank => current + 1,
+                        _ if current == *banks.start() => *banks.end(),
+                        _ => current - 1
+                    };
+                    *bank = Some(next);
+                }
+                ViewKey::HexDigit(digit) => {
+                    if input.len() == 4 { input.clear(); }
+                    input.push(char::from_digit(digit as u32, 16).unwrap_or('0').to_ascii_uppercase());
+                }
+                ViewKey::Confirm => {
+                    if let Ok(target) = u16::from_str_radix(input, 16) { *address = target & 0xFFF0; }
+                    input.clear();
+                }
+                // Back to following the mapped bank
+                ViewKey::Cancel => {
+                    input.clear();
+                    *bank = None;
+                }
+            },
+            View::Tiles { palette } => match key {
+                ViewKey::NextBank => *palette = (*palette + 1) % 16,
+                ViewKey::PreviousBank => *palette = (*palette + 15) % 16,
+                _ => { }
+            },
+            _ => { }
+        }
+    }
+
+    pub fn render(&self, bus: &MemoryBus, image: &mut Image) {
+        image.clear();
+        match self {
+            View::Memory { address, bank, input } => render_memory(bus, *address, *bank, input, image),
+            View::Tiles { palette } => render_tiles(bus, *palette, image),
+            View::TileMaps => render_tile_maps(bus, image),
+            View::Oam => render_oam(bus, image),
+            View::Palettes => render_palettes(bus, image)
+        }
+    }
+}
+
+fn render_memory(bus: &MemoryBus, address: u16, bank: Option<u16>, input: &str, image: &mut Image) {
+    let bank_text = match bank {
+        Some(bank) => format!("{:02X}", bank),
+        None => "MAPPED".to_string()
+    };
+    image.text(0, 0, &format!("BANK {:<6} GOTO {:_<4}   [ ] BANK  ESC MAPPED", bank_text, input), HIGHLIGHT);
+
+    for row in 0 .. MEMORY_ROWS {
+        let row_address = address.wrapping_add((row * 0x10) as u16);
+        let row_bank = bank.filter(|bank| bus.banks_at(row_address).contains(bank)).unwrap_or_else(|| bus.bank_at(row_address));
+        let bytes: Vec<u8> = (0 .. 0x10).map(|offset| bus.read_banked(row_bank, row_address.wrapping_add(offset))).collect();
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = bytes.iter().map(|&byte| if (0x20 .. 0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+        image.text(0, (row + 2) * LINE_HEIGHT, &format!("{:02X}:{:04X} {} {}", row_bank, row_address, hex.join(" "), text), TEXT);
+    }
+}
+
+fn render_tiles(bus: &MemoryBus, palette: usize, image: &mut Image) {
+    let ppu = &bus.ppu;
+    let palette_text = if !ppu.cgb_hardware {
+        "BGP".to_string()
+    } else if palette < 8 {
+        format!("BG{}", palette)
+    } else {
+        format!("OBJ{}", palette - 8)
+    };
+    image.text(0, 0, &format!("BANK 0  PALETTE {}  [ ]", palette_text), HIGHLIGHT);
+    image.text(TILES_PER_ROW * 8 + 8, 0, "BANK 1", HIGHLIGHT);
+
+    let top = LINE_HEIGHT * 2;
+    for bank in 0 .. 2 {
+        let left = bank * (TILES_PER_ROW * 8 + 8);
+        for tile in 0 .. TILES_PER_ROW * TILE_ROWS {
+            let (tile_x, tile_y) = (left + (tile % TILES_PER_ROW) * 8, top + (tile / TILES_PER_ROW) * 8);
+            for row in 0 .. 8 {
+                for column in 0 .. 8 {
+                    let color = ppu.tile_pixel(bank, tile, row, column);
+                    let pixel = if !ppu.cgb_hardware {
+                        ppu.background_color(color, 0)
+                    } else if palette < 8 {
+                        crate::ppu::rgb555_to_rgb888(ppu.bg_palette_entry(palette, color))
+                    } else {
+                        crate::ppu::rgb555_to_rgb888(ppu.obj_palette_entry(palette - 8, color))
+                    };
+                    image.set(tile_x + column, tile_y + row, pixel);
+                }
+            }
+        }
+    }
+}
+
+fn render_tile_maps(bus: &MemoryBus, image: &mut Image) {
+    let ppu = &bus.ppu;
+    let bg_map = if (ppu.lcdc & 0b00001000) != 0 { 0x1C00 } else { 0x1800 };
+    let window_map = if (ppu.lcdc & 0b01000000) != 0 { 0x1C00 } else { 0x1800 };
+    let window_enabled = (ppu.lcdc & 0b00100000) != 0;
+
+    let top = LINE_HEIGHT * 2;
+    for (index, map) in [0x1800, 0x1C00].into_iter().enumerate() {
+        let left = index * (256 + 8);
+        let mut label = format!("{:04X}", 0x8000 + map);
+        if map == bg_map { label.push_str(" BG"); }
+        if map == window_map && window_enabled { label.push_str(" WINDOW"); }
+        image.text(left, 0, &label, HIGHLIGHT);
+
+        for y in 0 .. 256 {
+            for x in 0 .. 256 {
+                let (color, attributes) = ppu.map_pixel(map, x, y);
+                image.set(left + x, top + y, ppu.background_color(color, attributes));
+            }
+        }
+
+        // The part of the map on screen, wrapping around its edges
+        if map == bg_map {
+            outline(image, left, top, (ppu.scx as usize, ppu.scy as usize, SCREEN_WIDTH, SCREEN_HEIGHT), VIEWPORT);
+        }
+        if map == window_map && window_enabled && ppu.wx <= 166 && ppu.wy < SCREEN_HEIGHT as u8 {
+            let width = SCREEN_WIDTH + 7 - ppu.wx.max(7) as usize;
+            let height = SCREEN_HEIGHT - ppu.wy as usize;
+            outline(image, left, top, (0, 0, width, height), WINDOW);
+        }
+    }
+}
+
+// Draws a rectangle as x, y, width, height on the 256x256 map at left, top, wrapping around at its edges
+fn outline(image: &mut Image, left: usize, top: usize, (x, y, width, height): (usize, usize, usize, usize), color: u32) {
+    for offset in 0 .. width {
+        image.set(left + (x + offset) % 256, top + y % 256, color);
+        image.set(left + (x + offset) % 256, top + (y + height - 1) % 256, color);
+    }
+    for offset in 0 .. height {
+        image.set(left + x % 256, top + (y + offset) % 256, color);
+        image.set(left + (x + width - 1) % 256, top + (y + offset) % 256, color);
+    }
+}
+
+fn render_oam(bus: &MemoryBus, image: &mut Image) {
+    let ppu = &bus.ppu;
+    let tall = (ppu.lcdc & 0b00000100) != 0;
+    image.text(0, 0, &format!("8X{} SPRITES   X/Y FLIP  P BEHIND BG", if tall { 16 } else { 8 }), HIGHLIGHT);
+
+    for sprite in 0 .. 40 {
+        let (left, top) = ((sprite / 20) * 104, LINE_HEIGHT * 2 + (sprite % 20) * 18);
+        let y = ppu.read_oam(sprite * 4);
+        let x = ppu.read_oam(sprite * 4 + 1);
+        let tile = ppu.read_oam(sprite * 4 + 2);
+        let attributes = ppu.read_oam(sprite * 4 + 3);
+
+        let bank = if ppu.cgb_mode { ((attributes & 0b00001000) >> 3) as usize } else { 0 };
+        let first_tile = if tall { tile & 0xFE } else { tile } as usize;
+        let height = if tall { 16 } else { 8 };
+        for row in 0 .. height {
+            for column in 0 .. 8 {
+                let color = ppu.tile_pixel(bank, first_tile + row / 8, row % 8, column);
+                let pixel = if color == 0 { TRANSPARENT } else { ppu.object_color(color, attributes) };
+                image.set(left + column, top + row, pixel);
+            }
+        }
+
+        // Sprites only show within these coordinates
+        let visible = (1 .. 168).contains(&x) && (1 .. 160).contains(&y);
+        let flags = format!(
+            "{}{}{} PAL {}{}",
+            if (attributes & 0b00100000) != 0 { 'X' } else { '-' },
+            if (attributes & 0b01000000) != 0 { 'Y' } else { '-' },
+            if (attributes & 0b10000000) != 0 { 'P' } else { '-' },
+            if ppu.cgb_mode { attributes & 0b111 } else { (attributes & 0b00010000) >> 4 },
+            if ppu.cgb_mode { format!(" BANK {}", bank) } else { String::new() }
+        );
+        let color = if visible { TEXT } else { TRANSPARENT };
+        image.text(left + 12, top + 1, &format!("{:02} X{:02X} Y{:02X} T{:02X}", sprite, x, y, tile), color);
+        image.text(left + 12, top + 1 + LINE_HEIGHT, &flags, color);
+    }
+}
+
+fn render_palettes(bus: &MemoryBus, image: &mut Image) {
+    let ppu = &bus.ppu;
+    let dmg_colors = |value: u8| -> [(u32, String); 4] {
+        std::array::from_fn(|color| {
+            let shade = (value >> (color * 2)) & 0b11;
+            (crate::ppu::DMG_COLORS[shade as usize], format!("{}", shade))
+        })
+    };
+    let cgb_colors = |entry: &dyn Fn(u8) -> u16| -> [(u32, String); 4] {
+        std::array::from_fn(|color| {
+            let value = entry(color as u8);
+            (crate::ppu::rgb555_to_rgb888(value), format!("{:04X}", value))
+        })
+    };
+
+    let mut rows: Vec<(String, [(u32, String); 4])> = vec![
+        ("BGP".to_string(), dmg_colors(ppu.bgp)),
+        ("OBP0".to_string(), dmg_colors(ppu.obp0)),
+        ("OBP1".to_string(), dmg_colors(ppu.obp1))
+    ];
+    // DMG games on a CGB still get their colors from palette RAM
+    if ppu.cgb_hardware {
+        for palette in 0 .. 8 {
+            rows.push((format!("BG{}", palette), cgb_colors(&|color| ppu.bg_palette_entry(palette, color))));
+        }
+        for palette in 0 .. 8 {
+            rows.push((format!("OBJ{}", palette), cgb_colors(&|color| ppu.obj_palette_entry(palette, color))));
+        }
+    }
+
+    for (index, (name, colors)) in rows.iter().enumerate() {
+        // A gap between the DMG registers and palette RAM
+        let top = index * 12 + if index >= 3 { 12 } else { 0 };
+        image.text(0, top + 2, name, HIGHLIGHT);
+        for (color, (pixel, text)) in colors.iter().enumerate() {
+            let left = 5 * CHAR_WIDTH + color * 34;
+            image.fill_rect(left, top, 10, 10, *pixel);
+            image.text(left + 13, top + 2, text, TEXT);
+        }
+    }
+}
+
+// Rows of a 3x5 glyph, lowercase letters look like uppercase ones
+fn glyph(character: char) -> [u8; 5] {
+    match character.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b011, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ';' => [0b000, 0b010, 0b000, 0b010, 0b100],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '*' => [0b000, 0b101, 0b010, 0b101, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '?' => [0b111, 0b001, 0b011, 0b000, 0b010],
+        '|' => [0b010, 0b010, 0b010, 0b010, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '"' => [0b101, 0b101, 0b000, 0b000, 0b000],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+        ' ' => [0; 5],
+        // Anything else is a block so it isn't mistaken for a character
+        _ => [0b111; 5]
+    }
+}
@@ -0,0 +1,727 @@
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::cpu::{self, CPU};
+use crate::cpu::call_stack::FrameKind;
+use crate::cpu::disassembler::{self, Disassembly};
+use crate::config::parse_hex;
+use crate::error::EmulatorError;
+use crate::event::Event;
+use crate::save_state::{self, SaveStates};
+use crate::symbols::Symbols;
+
+const HELP: &str = "Commands (all numbers are hex, addresses can also be labels like Main or Main.loop+3):
+    c, continue                      Run until something breaks
+    s, step [count]                  Execute one or more instructions
+    n, next                          Step over calls and rst
+    out, finish                      Run until the current function returns
+    frame                            Run until the next VBlank
+    bt, backtrace                    Show the calls and interrupts that led here
+    stackcheck [on|off]              Break when a return pops something that isn't a return address
+    b, break [[bank:]addr] [if <cond>]
+                                     Break at an address, when a condition holds or both.
+                                     Conditions compare a register: a == 12, hl >= c000, ...
+    w, watch [r|w|rw|x] <addr>[-end] Break on reads, writes (default) or execution in a range
+    d, delete [id]                   Delete a breakpoint or watchpoint, all without an id
+    l, list                          List breakpoints and watchpoints
+    r, regs                          Show the registers
+    set <reg> <value>                Change a register: a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc, ime
+    x, mem <addr> [length]           Dump memory
+    write <addr> <byte>...           Write bytes to memory
+    io [name]                        Show IO registers, optionally only those matching name
+    dis [addr] [count]               Disassemble, from PC by default
+    sym <text>                       Look up a label, an address or labels containing the text
+    save [slot]                      Save the state to a slot, 0 by default
+    load [slot]                      Load the state from a slot, 0 by default
+    states                           List the save state slots
+    q, quit                          Exit the emulator
+An empty line repeats the last command";
+
+// CPU cycles per frame at normal speed, for run-to-frame with the LCD off
+const CYCLES_PER_FRAME: u64 = 70224;
+
+pub struct Watchpoint {
+    pub id: u32,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+impl Watchpoint {
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start ..= self.end).contains(&address)
+    }
+
+    pub fn matches(&self, address: u16, write: bool) -> bool {
+        self.contains(address) && if write { self.write } else { self.read }
+    }
+
+    fn describe(&self) -> String {
+        let access = match (self.read, self.write, self.execute) {
+            (true, true, _) => "rw",
+            (true, false, _) => "r",
+            (false, true, _) => "w",
+            _ => "x"
+        };
+        if self.start == self.end {
+            format!("watch {} {:04X}", access, self.start)
+        } else {
+            format!("watch {} {:04X}-{:04X}", access, self.start, self.end)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Register { A, F, B, C, D, E, H, L, AF, BC, DE, HL, SP, PC }
+
+impl Register {
+    fn from_name(name: &str) -> Option<Register> {
+        match name.to_ascii_lowercase().as_str() {
+            "a" => Some(Register::A),
+            "f" => Some(Register::F),
+            "b" => Some(Register::B),
+            "c" => Some(Register::C),
+            "d" => Some(Register::D),
+            "e" => Some(Register::E),
+            "h" => Some(Register::H),
+            "l" => Some(Register::L),
+            "af" => Some(Register::AF),
+            "bc" => Some(Register::BC),
+            "de" => Some(Register::DE),
+            "hl" => Some(Register::HL),
+            "sp" => Some(Register::SP),
+            "pc" => Some(Register::PC),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::F => "f",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::H => "h",
+            Register::L => "l",
+            Register::AF => "af",
+            Register::BC => "bc",
+            Register::DE => "de",
+            Register::HL => "hl",
+            Register::SP => "sp",
+            Register::PC => "pc"
+        }
+    }
+
+    fn get(&self, cpu: &CPU) -> u16 {
+        match self {
+            Register::A => cpu.registers.a as u16,
+            Register::F => u8::from(cpu.registers.f) as u16,
+            Register::B => cpu.registers.b as u16,
+            Register::C => cpu.registers.c as u16,
+            Register::D => cpu.registers.d as u16,
+            Register::E => cpu.registers.e as u16,
+            Register::H => cpu.registers.h as u16,
+            Register::L => cpu.registers.l as u16,
+            Register::AF => cpu.registers.get_af(),
+            Register::BC => cpu.registers.get_bc(),
+            Register::DE => cpu.registers.get_de(),
+            Register::HL => cpu.registers.get_hl(),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc
+        }
+    }
+
+    fn set(&self, cpu: &mut CPU, value: u16) {
+        match self {
+            Register::A => cpu.registers.a = value as u8,
+            Register::F => cpu.registers.f = (value as u8 & 0xF0).into(),
+            Register::B => cpu.registers.b = value as u8,
+            Register::C => cpu.registers.c = value as u8,
+            Register::D => cpu.registers.d = value as u8,
+            Register::E => cpu.registers.e = value as u8,
+            Register::H => cpu.registers.h = value as u8,
+            Register::L => cpu.registers.l = value as u8,
+            Register::AF => cpu.registers.set_af(value & 0xFFF0),
+            Register::BC => cpu.registers.set_bc(value),
+            Register::DE => cpu.registers.set_de(value),
+            Register::HL => cpu.registers.set_hl(value),
+            Register::SP => cpu.sp = value,
+            Register::PC => cpu.pc = value
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Comparison { Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual }
+
+impl Comparison {
+    // Two character operators first so <= isn't read as <
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessEqual),
+        (">=", Comparison::GreaterEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater)
+    ];
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterEqual => ">="
+        }
+    }
+}
+
+struct Condition {
+    register: Register,
+    comparison: Comparison,
+    value: u16
+}
+
+impl Condition {
+    fn parse(text: &str) -> Result<Condition, String> {
+        let (symbol, comparison) = Comparison::ALL.iter()
+            .find(|(symbol, _)| text.contains(symbol))
+            .ok_or_else(|| format!("'{}' is not a condition", text))?;
+        let (name, value) = text.split_once(symbol).unwrap_or((text, ""));
+
+        let register = Register::from_name(name.trim()).ok_or_else(|| format!("unknown register '{}'", name.trim()))?;
+        let value = parse_hex(value.trim()).map_err(|error| error.to_string())?;
+
+        Ok(Condition { register, comparison: *comparison, value })
+    }
+
+    fn holds(&self, cpu: &CPU) -> bool {
+        let register = self.register.get(cpu);
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterEqual => register >= self.value
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} {} {:X}", self.register.name(), self.comparison.symbol(), self.value)
+    }
+}
+
+struct Breakpoint {
+    id: u32,
+    // Matches any bank when not given
+    bank: Option<u16>,
+    address: Option<u16>,
+    condition: Option<Condition>,
+    // Breaks without an address only fire when their condition becomes true
+    was_true: bool
+}
+
+impl Breakpoint {
+    fn describe(&self, symbols: &Symbols) -> String {
+        let location = match (self.bank, self.address) {
+            (Some(bank), Some(address)) => symbols.format_location(bank, address),
+            (None, Some(address)) => format!("{:04X}", address),
+            _ => String::new()
+        };
+        match &self.condition {
+            Some(condition) if self.address.is_some() => format!("break {} if {}", location, condition.describe()),
+            Some(condition) => format!("break {}", condition.describe()),
+            None => format!("break {}", location)
+        }
+    }
+}
+
+// What the emulator does until the next prompt
+enum RunState {
+    Running,
+    Paused,
+    Steps(u32),
+    // Until the instruction after a call, with the stack back where it was
+    StepOver { address: u16, sp: u16 },
+    // Until a return pops the current frame
+    StepOut { sp: u16 },
+    Frame { last_ly: u8, start_cycles: u64 }
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: u32,
+    state: RunState,
+    // The instruction about to execute is a return, for step out
+    returning: bool,
+    last_command: String,
+    break_on_imbalance: bool,
+    symbols: Rc<Symbols>,
+    states: SaveStates,
+    // Loading a state drops pending events, so loads are reported once the emulator resumes
+    loaded_states: u32
+}
+
+impl Debugger {
+    pub fn new(symbols: Rc<Symbols>, states: SaveStates) -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            next_id: 1,
+            state: RunState::Running,
+            returning: false,
+            last_command: String::new(),
+            break_on_imbalance: false,
+            symbols,
+            states,
+            loaded_states: 0
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.state = RunState::Paused;
+    }
+
+    pub fn watchpoint_hit(&mut self, id: u32, address: u16, value: u8, write: bool) {
+        let access = if write { "write" } else { "read" };
+        println!("Watchpoint {}: {} of {:02X} at {:04X}", id, access, value, address);
+        self.pause();
+    }
+
+    // Jump tables and other stack tricks return this way on purpose, so it only stops with stackcheck on
+    pub fn stack_imbalance(&mut self, cpu: &CPU, pc: u16, sp: u16, expected: Option<u16>, found: u16) {
+        if !self.break_on_imbalance { return }
+
+        let location = |address: u16| self.symbols.format_location(cpu.bus.bank_at(address), address);
+        match expected {
+            Some(expected) => println!("Stack imbalance: return at {} popped {} from {:04X}, expected {}", location(pc), location(found), sp, location(expected)),
+            None => println!("Stack imbalance: return at {} popped {} from {:04X} outside of any call", location(pc), location(found), sp)
+        }
+        self.pause();
+    }
+
+    // Called before every instruction, opens the prompt when something stops the emulator here.
+    // Returns false once the user quits
+    pub fn update(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        if matches!(self.state, RunState::Running) && self.breakpoints.is_empty() && cpu.bus.watchpoints.is_empty() {
+            return Ok(true)
+        }
+
+        // A halted CPU steps without running instructions, those don't count or hit breakpoints
+        let fetching = cpu.fetches_instruction();
+        let mut stop = match &mut self.state {
+            RunState::Running => false,
+            RunState::Paused => true,
+            RunState::Steps(count) => {
+                if fetching { *count -= 1; }
+                *count == 0
+            }
+            RunState::StepOver { address, sp } => cpu.pc == *address && cpu.sp >= *sp,
+            RunState::StepOut { sp } => self.returning && cpu.sp > *sp,
+            RunState::Frame { last_ly, start_cycles } => {
+                let ly = cpu.bus.ppu.ly;
+                let entered_vblank = ly == 144 && *last_ly != 144;
+                *last_ly = ly;
+                let frame_cycles = if cpu.bus.double_speed { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME };
+                entered_vblank || (!cpu.bus.ppu.is_lcd_enabled() && cpu.bus.cycles - *start_cycles >= frame_cycles)
+            }
+        };
+
+        if fetching {
+            stop |= self.check_breakpoints(cpu);
+        }
+        self.returning = matches!(cpu.bus.read_byte(cpu.pc), 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+
+        if !stop { return Ok(true) }
+
+        self.state = RunState::Paused;
+        self.print_location(cpu);
+        self.prompt(cpu)
+    }
+
+    fn check_breakpoints(&mut self, cpu: &CPU) -> bool {
+        let mut stop = false;
+
+        for breakpoint in &mut self.breakpoints {
+            let at_address = match breakpoint.address {
+                Some(address) => cpu.pc == address && breakpoint.bank.is_none_or(|bank| cpu.bus.bank_at(address) == bank),
+                None => true
+            };
+            let condition = breakpoint.condition.as_ref().is_none_or(|condition| condition.holds(cpu));
+
+            let hit = if breakpoint.address.is_some() {
+                at_address && condition
+            } else {
+                let became_true = condition && !breakpoint.was_true;
+                breakpoint.was_true = condition;
+                became_true
+            };
+            if hit {
+                println!("Breakpoint {}: {}", breakpoint.id, breakpoint.describe(&self.symbols));
+                stop = true;
+            }
+        }
+
+        for watchpoint in cpu.bus.watchpoints.iter().filter(|watchpoint| watchpoint.execute && watchpoint.contains(cpu.pc)) {
+            println!("Watchpoint {}: execute at {}", watchpoint.id, self.symbols.format_location(cpu.bus.bank_at(cpu.pc), cpu.pc));
+            stop = true;
+        }
+
+        stop
+    }
+
+    fn prompt(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        loop {
+            print!("(gb) ");
+            std::io::stdout().flush().map_err(|error| EmulatorError::Io { path: "stdout".to_string(), error })?;
+
+            let mut line = String::new();
+            let read = std::io::stdin().read_line(&mut line).map_err(|error| EmulatorError::Io { path: "stdin".to_string(), error })?;
+            // End of input quits like the quit command
+            if read == 0 { return Ok(false) }
+
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+
+            match self.execute(cpu, &line) {
+                Ok(Some(resume)) => {
+                    if !resume { return Ok(false) }
+                    for _ in 0 .. std::mem::take(&mut self.loaded_states) { cpu.bus.push_event(Event::StateLoaded); }
+                    return Ok(true)
+                }
+                Ok(None) => { }
+                Err(message) => println!("{}", message)
+            }
+        }
+    }
+
+    // Some(true) resumes the emulator, Some(false) quits, None stays at the prompt
+    fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<Option<bool>, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(None) };
+        let arguments: Vec<&str> = words.collect();
+
+        match command {
+            "h" | "help" => println!("{}", HELP),
+            "c" | "continue" => {
+                self.state = RunState::Running;
+                return Ok(Some(true))
+            }
+            "s" | "step" => {
+                let count = match arguments.first() {
+                    Some(count) => hex(count)? as u32,
+                    None => 1
+                };
+                self.state = RunState::Steps(count.max(1));
+                return Ok(Some(true))
+            }
+            "n" | "next" => {
+                let instruction = self.disassemble(cpu, cpu.pc);
+                let opcode = instruction.bytes[0];
+                let is_call = matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || (opcode & 0xC7) == 0xC7;
+                self.state = if is_call {
+                    RunState::StepOver { address: cpu.pc.wrapping_add(instruction.length()), sp: cpu.sp }
+                } else {
+                    RunState::Steps(1)
+                };
+                return Ok(Some(true))
+            }
+            "out" | "finish" => {
+                self.state = RunState::StepOut { sp: cpu.sp };
+                return Ok(Some(true))
+            }
+            "frame" => {
+                self.state = RunState::Frame { last_ly: cpu.bus.ppu.ly, start_cycles: cpu.bus.cycles };
+                return Ok(Some(true))
+            }
+            "bt" | "backtrace" => self.print_backtrace(cpu),
+            "stackcheck" => {
+                match arguments.first().copied() {
+                    Some("on") => self.break_on_imbalance = true,
+                    Some("off") => self.break_on_imbalance = false,
+                    None => { }
+                    Some(argument) => return Err(format!("Usage: stackcheck [on|off], not '{}'", argument))
+                }
+                println!("Breaking on stack imbalances is {}", if self.break_on_imbalance { "on" } else { "off" });
+            }
+            "b" | "break" => self.add_breakpoint(cpu, &arguments)?,
+            "w" | "watch" => self.add_watchpoint(cpu, &arguments)?,
+            "d" | "delete" => match arguments.first() {
+                Some(id) => {
+                    let id: u32 = id.parse().map_err(|_| format!("'{}' is not an id", id))?;
+                    let count = self.breakpoints.len() + cpu.bus.watchpoints.len();
+                    self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+                    cpu.bus.watchpoints.retain(|watchpoint| watchpoint.id != id);
+                    if count == self.breakpoints.len() + cpu.bus.watchpoints.len() {
+                        return Err(format!("No breakpoint or watchpoint {}", id))
+                    }
+                }
+                None => {
+                    self.breakpoints.clear();
+                    cpu.bus.watchpoints.clear();
+                }
+            },
+            "l" | "list" => {
+                for breakpoint in &self.breakpoints {
+                    println!("{:>3}  {}", breakpoint.id, breakpoint.describe(&self.symbols));
+                }
+                for watchpoint in &cpu.bus.watchpoints {
+                    println!("{:>3}  {}", watchpoint.id, watchpoint.describe());
+                }
+            }
+            "r" | "regs" => print_registers(cpu),
+            "set" => {
+                let [name, value] = arguments[..] else { return Err("Usage: set <reg> <value>".to_string()) };
+                let value = hex(value)?;
+                if name.eq_ignore_ascii_case("ime") {
+                    cpu.ime = value != 0;
+                } else {
+                    Register::from_name(name).ok_or_else(|| format!("unknown register '{}'", name))?.set(cpu, value);
+                }
+                print_registers(cpu);
+            }
+            "x" | "mem" => {
+                let (_, start) = self.parse_location(arguments.first().ok_or("Usage: mem <addr> [length]")?)?;
+                let length = match arguments.get(1) {
+                    Some(length) => hex(length)? as u32,
+                    None => 0x40
+                };
+                for row in (0 .. length).step_by(16) {
+                    let address = start.wrapping_add(row as u16);
+                    let bytes: Vec<u8> = (0 .. (length - row).min(16)).map(|offset| cpu.bus.read_byte(address.wrapping_add(offset as u16))).collect();
+                    let hex_bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    let text: String = bytes.iter().map(|&byte| if (0x20 .. 0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+                    println!("{:02X}:{:04X}  {:<47}  {}", cpu.bus.bank_at(address), address, hex_bytes.join(" "), text);
+                }
+            }
+            "write" => {
+                let (address, bytes) = arguments.split_first().ok_or("Usage: write <addr> <byte>...")?;
+                let (_, address) = self.parse_location(address)?;
+                for (offset, byte) in bytes.iter().enumerate() {
+                    let byte = hex(byte)?;
+                    if byte > 0xFF { return Err(format!("{:X} does not fit in a byte", byte)) }
+                    cpu.bus.write_byte(address.wrapping_add(offset as u16), byte as u8);
+                }
+            }
+            "io" => {
+                let filter = arguments.first().map(|name| name.trim_start_matches('r').to_ascii_lowercase());
+                let registers = (0xFF00 ..= 0xFF7F).chain(std::iter::once(0xFFFF))
+                    .filter_map(|address| disassembler::io_register_name(address).map(|name| (address, name)))
+                    .filter(|(_, name)| filter.as_ref().is_none_or(|filter| name[1..].to_ascii_lowercase().contains(filter.as_str())));
+                for (address, name) in registers {
+                    println!("{:<7} {:04X} = {:02X}", name, address, cpu.bus.read_byte(address));
+                }
+            }
+            "dis" => {
+                let mut address = match arguments.first() {
+                    Some(address) => self.parse_location(address)?.1,
+                    None => cpu.pc
+                };
+                let count = match arguments.get(1) {
+                    Some(count) => hex(count)?,
+                    None => 0x10
+                };
+                for _ in 0 .. count {
+                    let instruction = self.disassemble(cpu, address);
+                    let marker = if address == cpu.pc { ">" } else { " " };
+                    let bank = cpu.bus.bank_at(address);
+                    if let Some(label) = self.symbols.label(bank, address) { println!("  {}:", label); }
+                    println!("{} {:02X}:{:04X}  {}", marker, bank, address, instruction.text);
+                    address = address.wrapping_add(instruction.length());
+                }
+            }
+            "sym" => {
+                let text = arguments.first().ok_or("Usage: sym <text>")?;
+                if let Some((bank, address)) = self.symbols.lookup(text) {
+                    println!("{:02X}:{:04X}  {}", bank, address, text);
+                } else if let Ok(address) = parse_hex(text) {
+                    println!("{}", self.symbols.format_location(cpu.bus.bank_at(address), address));
+                } else {
+                    let matches = self.symbols.search(text);
+                    if matches.is_empty() { return Err(format!("No labels containing '{}'", text)) }
+                    for (bank, address, name) in matches {
+                        println!("{:02X}:{:04X}  {}", bank, address, name);
+                    }
+                }
+            }
+            "save" => {
+                let slot = parse_slot(arguments.first().copied())?;
+                self.states.save(cpu, slot).map_err(|error| error.to_string())?;
+                println!("Saved slot {} to {}", slot, self.states.path(slot));
+            }
+            "load" => {
+                let slot = parse_slot(arguments.first().copied())?;
+                self.states.load(cpu, slot).map_err(|error| error.to_string())?;
+                self.loaded_states += 1;
+                println!("Loaded slot {}, at {}", slot, self.symbols.format_location(cpu.bus.bank_at(cpu.pc), cpu.pc));
+            }
+            "states" => {
+                for slot in 0 .. save_state::SLOTS {
+                    match self.states.info(slot) {
+                        Some(Ok(info)) => println!("{}  {} cycles, saved {} (version {})", slot, info.cycles, format_age(info.saved_at), info.version),
+                        Some(Err(error)) => println!("{}  {}", slot, error),
+                        None => println!("{}  empty", slot)
+                    }
+                }
+            }
+            "q" | "quit" => return Ok(Some(false)),
+            _ => return Err(format!("Unknown command '{}', try help", command))
+        }
+
+        Ok(None)
+    }
+
+    fn print_backtrace(&self, cpu: &CPU) {
+        let location = |address: u16| self.symbols.format_location(cpu.bus.bank_at(address), address);
+        let frames = cpu.call_stack.frames();
+        println!("#0   {}", location(cpu.pc));
+        for (depth, frame) in frames.iter().rev().enumerate() {
+            let entry = match frame.kind {
+                FrameKind::Call => format!("call {}", location(frame.target)),
+                FrameKind::Rst => format!("rst ${:02X}", frame.target),
+                FrameKind::Interrupt(vector) => format!("interrupt {}", cpu::interrupt_name(vector))
+            };
+            // The return address sits on the stack, a mismatch means it was overwritten
+            let stored = cpu.bus.read_byte(frame.sp) as u16 | (cpu.bus.read_byte(frame.sp.wrapping_add(1)) as u16) << 8;
+            let warning = if stored != frame.return_address { format!("  (stack holds {:04X})", stored) } else { String::new() };
+            println!("#{:<3} {}  {}  sp {:04X}{}", depth + 1, location(frame.caller), entry, frame.sp, warning);
+        }
+
+        let nesting = cpu.call_stack.interrupt_depth();
+        if nesting > 0 { println!("Inside {} interrupt handler{}", nesting, if nesting == 1 { "" } else { "s" }); }
+    }
+
+    fn add_breakpoint(&mut self, cpu: &CPU, arguments: &[&str]) -> Result<(), String> {
+        let text = arguments.join(" ");
+        let (location, condition) = match text.split_once(" if ") {
+            Some((location, condition)) => (location.trim(), Some(condition)),
+            None if Comparison::ALL.iter().any(|(symbol, _)| text.contains(symbol)) => ("", Some(text.as_str())),
+            None => (text.trim(), None)
+        };
+
+        let (bank, address) = match location {
+            "" if condition.is_none() => (None, Some(cpu.pc)),
+            "" => (None, None),
+            _ => {
+                let (bank, address) = self.parse_location(location)?;
+                // Without an MBC the switchable ROM region only ever holds bank 1
+                if let Some(bank) = bank.filter(|bank| !cpu.bus.banks_at(address).contains(bank)) {
+                    return Err(format!("Bank {:02X} is never mapped at {:04X}", bank, address))
+                }
+                (bank, Some(address))
+            }
+        };
+        let condition = condition.map(Condition::parse).transpose()?;
+
+        let breakpoint = Breakpoint { id: self.next_id, bank, address, condition, was_true: false };
+        println!("Breakpoint {}: {}", breakpoint.id, breakpoint.describe(&self.symbols));
+        self.breakpoints.push(breakpoint);
+        self.next_id += 1;
+
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, cpu: &mut CPU, arguments: &[&str]) -> Result<(), String> {
+        let (access, range) = match arguments {
+            [range] => ("w", *range),
+            [access, range] => (*access, *range),
+            _ => return Err("Usage: watch [r|w|rw|x] <addr>[-end]".to_string())
+        };
+        let (read, write, execute) = match access {
+            "r" => (true, false, false),
+            "w" => (false, true, false),
+            "rw" => (true, true, false),
+            "x" => (false, false, true),
+            _ => return Err(format!("Unknown access '{}', expected r, w, rw or x", access))
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (self.parse_location(start)?.1, self.parse_location(end)?.1),
+            None => (self.parse_location(range)?.1, self.parse_location(range)?.1)
+        };
+        if end < start { return Err(format!("Range ends at {:04X} before it starts at {:04X}", end, start)) }
+
+        let watchpoint = Watchpoint { id: self.next_id, start, end, read, write, execute };
+        println!("Watchpoint {}: {}", watchpoint.id, watchpoint.describe());
+        cpu.bus.watchpoints.push(watchpoint);
+        self.next_id += 1;
+
+        Ok(())
+    }
+
+    fn print_location(&self, cpu: &CPU) {
+        let instruction = self.disassemble(cpu, cpu.pc);
+        let bank = cpu.bus.bank_at(cpu.pc);
+        match self.symbols.describe(bank, cpu.pc) {
+            Some(label) => println!("{:02X}:{:04X} {}  {}", bank, cpu.pc, label, instruction.text),
+            None => println!("{:02X}:{:04X}  {}", bank, cpu.pc, instruction.text)
+        }
+    }
+
+    fn disassemble(&self, cpu: &CPU, address: u16) -> Disassembly {
+        disassembler::disassemble_with_labels(|address| cpu.bus.read_byte(address), address, |target| self.symbols.describe(cpu.bus.bank_at(target), target))
+    }
+
+    // A label with an optional +offset, bank:address or a plain address, all in hex
+    fn parse_location(&self, text: &str) -> Result<(Option<u16>, u16), String> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, hex(offset)?),
+            None => (text, 0)
+        };
+        if let Some((bank, address)) = self.symbols.lookup(name) {
+            return Ok((Some(bank), address.wrapping_add(offset)))
+        }
+
+        match text.split_once(':') {
+            Some((bank, address)) => Ok((Some(hex(bank)?), hex(address)?)),
+            None => Ok((None, hex(text)?))
+        }
+    }
+}
+
+fn hex(value: &str) -> Result<u16, String> {
+    parse_hex(value).map_err(|error| error.to_string())
+}
+
+fn print_registers(cpu: &CPU) {
+    let flags = cpu.registers.f;
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    println!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: {:02X}:{:04X}  [{}{}{}{}] IME: {}{}",
+        cpu.registers.a, u8::from(flags), cpu.registers.b, cpu.registers.c, cpu.registers.d, cpu.registers.e, cpu.registers.h, cpu.registers.l,
+        cpu.sp, cpu.bus.bank_at(cpu.pc), cpu.pc,
+        flag(flags.zero, 'Z'), flag(flags.subtract, 'N'), flag(flags.half_carry, 'H'), flag(flags.carry, 'C'),
+        cpu.ime as u8, if cpu.is_halted { " (halted)" } else if cpu.is_locked { " (locked)" } else { "" });
+    println!("LY: {:02X} LCDC: {:02X} STAT: {:02X} IE: {:02X} IF: {:02X} cycles: {}",
+        cpu.bus.ppu.ly, cpu.bus.ppu.lcdc, cpu.bus.read_byte(0xFF41), cpu.bus.interrupt_enable.to_byte(), cpu.bus.interrupt_flag.to_byte(), cpu.bus.cycles);
+}
+
+fn parse_slot(argument: Option<&str>) -> Result<u8, String> {
+    match argument {
+        None => Ok(0),
+        Some(text) => match text.parse::<u8>() {
+            Ok(slot) if slot < save_state::SLOTS => Ok(slot),
+            _ => Err(format!("'{}' is not a slot from 0 to {}", text, save_state::SLOTS - 1))
+        }
+    }
+}
+
+// Time since a Unix timestamp, like "5 minutes ago"
+fn format_age(timestamp: u64) -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let seconds = now.saturating_sub(timestamp);
+    let (amount, unit) = match seconds {
+        0 ..= 59 => (seconds, "second"),
+        60 ..= 3599 => (seconds / 60, "minute"),
+        3600 ..= 86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day")
+    };
+    format!("{} {}{} ago", amount, unit, if amount == 1 { "" } else { "s" })
+}
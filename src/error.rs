@@ -0,0 +1,62 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    // Loading and construction
+    Io { path: String, error: std::io::Error },
+    InvalidBootRomSize { found: usize },
+    InvalidGameRomSize { minimum: usize, found: usize },
+    Video(String),
+    InvalidArgument(String),
+    InvalidSymbolFile { path: String, line: usize },
+    InvalidSaveState { path: String, reason: String },
+    InvalidMovie { path: String, reason: String },
+
+    // Reported by the diff mode
+    TraceDiverged { line: usize },
+
+    // Reported by the test mode, which exits with their exit codes
+    TestFailed(String),
+    TestTimedOut { seconds: u64 }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::Io { path, error } => write!(f, "Could not access {}: {}", path, error),
+            EmulatorError::InvalidBootRomSize { found } => {
+                write!(f, "Invalid boot rom, expected 256 (DMG) or 2304 (CGB) bytes but found {}", found)
+            }
+            EmulatorError::InvalidGameRomSize { minimum, found } => {
+                write!(f, "Invalid game rom, expected at least {} bytes but found {}", minimum, found)
+            }
+            EmulatorError::Video(message) => write!(f, "Could not initialise video: {}", message),
+            EmulatorError::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            EmulatorError::InvalidSymbolFile { path, line } => write!(f, "Invalid symbol file {}: malformed line {}", path, line),
+            EmulatorError::InvalidSaveState { path, reason } => write!(f, "Could not load save state {}: {}", path, reason),
+            EmulatorError::InvalidMovie { path, reason } => write!(f, "Could not play movie {}: {}", path, reason),
+            EmulatorError::TraceDiverged { line } => write!(f, "Trace diverged from the reference at line {}", line),
+            EmulatorError::TestFailed(reason) => write!(f, "Test failed: {}", reason),
+            EmulatorError::TestTimedOut { seconds } => write!(f, "Test timed out after {} emulated seconds", seconds),
+        }
+    }
+}
+
+impl EmulatorError {
+    // 1 for failures and errors alike, only a timeout of the test mode is told apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EmulatorError::TestTimedOut { .. } => 2,
+            _ => 1
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::Io { error, .. } => Some(error),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,17 @@
+// Things the emulated machine reports back to whoever is driving it
+#[derive(Debug)]
+pub enum Event {
+    // The CPU fetched one of the unused opcodes and hung, only the rest of the system keeps running
+    CpuLocked { opcode: u8, pc: u16 },
+    // A data access of the CPU hit a debugger watchpoint
+    Watchpoint { id: u32, address: u16, value: u8, write: bool },
+    // RET or RETI at pc popped something other than the return address of the innermost call
+    StackImbalance { pc: u16, sp: u16, expected: Option<u16>, found: u16 },
+    // The player asked to save or load the given save state slot
+    SaveState { slot: u8 },
+    LoadState { slot: u8 },
+    // The debugger loaded a state itself, sent once per load so movies count the rerecords
+    StateLoaded,
+    // The rewind key is held, sent once per frame
+    Rewind
+}
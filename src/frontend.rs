@@ -0,0 +1,322 @@
+extern crate sdl2;
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::{EventPump, VideoSubsystem};
+
+use crate::debug_views::{Image, View, ViewKey};
+use crate::error::EmulatorError;
+use crate::event;
+use crate::joypad::Button;
+use crate::memory_bus::MemoryBus;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::save_state::SaveStates;
+use crate::sgb::{BORDER_WIDTH, BORDER_HEIGHT, SCREEN_X, SCREEN_Y};
+
+const SCALE: u32 = 3;
+const DEBUG_SCALE: u32 = 2;
+// CPU cycles per frame at normal speed
+const CYCLES_PER_FRAME: u64 = 70224;
+const FRAME_DURATION: std::time::Duration = std::time::Duration::from_nanos(16_742_706);
+// Updates without a frame between looks at the wall clock, to still poll input once per frame
+const POLL_INTERVAL: u32 = 4096;
+// How long the thumbnail of a newly selected save state slot replaces the screen
+const THUMBNAIL_DURATION: std::time::Duration = std::time::Duration::from_secs(2);
+
+// A memory or PPU view in a window of its own, redrawn every frame
+struct DebugWindow {
+    view: View,
+    canvas: Canvas<Window>,
+    image: Image
+}
+
+pub struct Frontend {
+    canvas: Canvas<Window>,
+    event_pump: EventPump,
+    video: VideoSubsystem,
+    // Opened and closed with F1-F5
+    debug_windows: Vec<DebugWindow>,
+    // SGB hardware shows the game inside a 256x224 border
+    sgb_layout: bool,
+    // F6 saves to and F7 loads from the slot picked with 0-9
+    states: SaveStates,
+    slot: u8,
+    thumbnail: Option<(Vec<u32>, std::time::Instant)>,
+    // R is held to go back in time
+    rewinding: bool,
+    // Buttons held on the keyboard, passed on to the joypad directly or through a movie
+    held: u8,
+    frame: Vec<u32>,
+    last_frame_cycles: u64,
+    last_frame_time: std::time::Instant,
+    last_poll_time: std::time::Instant,
+    updates_without_frame: u32
+}
+
+impl Frontend {
+    pub fn new(sgb_layout: bool, states: SaveStates) -> Result<Frontend, EmulatorError> {
+        let sdl_context = sdl2::init().map_err(EmulatorError::Video)?;
+        let video_subsys = sdl_context.video().map_err(EmulatorError::Video)?;
+
+        let (width, height) = if sgb_layout { (BORDER_WIDTH, BORDER_HEIGHT) } else { (SCREEN_WIDTH, SCREEN_HEIGHT) };
+        let window = video_subsys.window("Gameboy DMG-01", width as u32 * SCALE, height as u32 * SCALE)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+        let mut canvas = window.into_canvas().build().map_err(|error| EmulatorError::Video(error.to_string()))?;
+        canvas.set_logical_size(width as u32, height as u32).map_err(|error| EmulatorError::Video(error.to_string()))?;
+        let event_pump = sdl_context.event_pump().map_err(EmulatorError::Video)?;
+
+        Ok(Frontend {
+            canvas,
+            event_pump,
+            video: video_subsys,
+            debug_windows: Vec::new(),
+            sgb_layout,
+            states,
+            slot: 0,
+            thumbnail: None,
+            rewinding: false,
+            held: 0,
+            frame: vec![0; width * height],
+            last_frame_cycles: 0,
+            last_frame_time: std::time::Instant::now(),
+            last_poll_time: std::time::Instant::now(),
+            updates_without_frame: 0
+        })
+    }
+
+    // Called after every instruction, presents finished frames and handles input.
+    // Returns false once the window is closed
+    pub fn update(&mut self, bus: &mut MemoryBus) -> Result<bool, EmulatorError> {
+        // With the LCD off there are no frames, but the window still has to respond. Loading
+        // a state can take the cycle count back, which wraps around to a new frame
+        let cycles_per_frame = if bus.double_speed { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME };
+        if !bus.ppu.frame_ready && bus.cycles.wrapping_sub(self.last_frame_cycles) < cycles_per_frame {
+            // Input is still polled when no frame comes, the button press ending STOP among it
+            self.updates_without_frame += 1;
+            if self.updates_without_frame < POLL_INTERVAL { return Ok(true) }
+            self.updates_without_frame = 0;
+            if self.last_poll_time.elapsed() < FRAME_DURATION { return Ok(true) }
+            return self.poll_input(bus)
+        }
+
+        bus.ppu.frame_ready = false;
+        self.last_frame_cycles = bus.cycles;
+        self.updates_without_frame = 0;
+
+        self.present(bus)?;
+        self.present_debug_windows(bus)?;
+        if !self.poll_input(bus)? { return Ok(false) }
+
+        let elapsed = self.last_frame_time.elapsed();
+        if elapsed < FRAME_DURATION { std::thread::sleep(FRAME_DURATION - elapsed); }
+        self.last_frame_time = std::time::Instant::now();
+
+        Ok(true)
+    }
+
+    fn poll_input(&mut self, bus: &mut MemoryBus) -> Result<bool, EmulatorError> {
+        self.last_poll_time = std::time::Instant::now();
+        if !self.handle_input(bus)? { return Ok(false) }
+        if self.rewinding { bus.push_event(event::Event::Rewind); }
+        Ok(true)
+    }
+
+    fn present(&mut self, bus: &mut MemoryBus) -> Result<(), EmulatorError> {
+        let (width, height) = if self.sgb_layout { (BORDER_WIDTH, BORDER_HEIGHT) } else { (SCREEN_WIDTH, SCREEN_HEIGHT) };
+
+        if self.thumbnail.as_ref().is_some_and(|(_, shown)| shown.elapsed() > THUMBNAIL_DURATION) { self.thumbnail = None; }
+        if let Some((thumbnail, _)) = &self.thumbnail {
+            // Shown without the border, which isn't part of the thumbnail
+            self.frame.fill(0x000000);
+            let (x, y) = if self.sgb_layout { (SCREEN_X, SCREEN_Y) } else { (0, 0) };
+            for row in 0 .. SCREEN_HEIGHT {
+                let start = (y + row) * width + x;
+                self.frame[start .. start + SCREEN_WIDTH].copy_from_slice(&thumbnail[row * SCREEN_WIDTH .. (row + 1) * SCREEN_WIDTH]);
+            }
+        } else if !self.sgb_layout {
+            self.frame.copy_from_slice(bus.ppu.framebuffer());
+        } else if let Some(sgb) = &mut bus.sgb {
+            sgb.render(bus.ppu.shades(), &mut self.frame);
+        } else {
+            // SGB hardware without SGB functions in the cartridge, no border to show
+            self.frame.fill(0x000000);
+            for y in 0 .. SCREEN_HEIGHT {
+                let start = (SCREEN_Y + y) * BORDER_WIDTH + SCREEN_X;
+                self.frame[start .. start + SCREEN_WIDTH].copy_from_slice(&bus.ppu.framebuffer()[y * SCREEN_WIDTH .. (y + 1) * SCREEN_WIDTH]);
+            }
+        }
+
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB888, width as u32, height as u32)
+            .map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+        let bytes: Vec<u8> = self.frame.iter().flat_map(|pixel| pixel.to_ne_bytes()).collect();
+        texture.update(None, &bytes, width * 4).map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).map_err(EmulatorError::Video)?;
+        self.canvas.present();
+
+        Ok(())
+    }
+
+    fn present_debug_windows(&mut self, bus: &MemoryBus) -> Result<(), EmulatorError> {
+        for window in &mut self.debug_windows {
+            window.view.render(bus, &mut window.image);
+
+            let texture_creator = window.canvas.texture_creator();
+            let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB888, window.image.width as u32, window.image.height as u32)
+                .map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+            let bytes: Vec<u8> = window.image.pixels.iter().flat_map(|pixel| pixel.to_ne_bytes()).collect();
+            texture.update(None, &bytes, window.image.width * 4).map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+            window.canvas.clear();
+            window.canvas.copy(&texture, None, None).map_err(EmulatorError::Video)?;
+            window.canvas.present();
+        }
+
+        Ok(())
+    }
+
+    // Opens the view in a new window, or closes it when it is already open
+    fn toggle_debug_window(&mut self, view: View) -> Result<(), EmulatorError> {
+        if let Some(index) = self.debug_windows.iter().position(|window| std::mem::discriminant(&window.view) == std::mem::discriminant(&view)) {
+            self.debug_windows.remove(index);
+            return Ok(())
+        }
+
+        let (width, height) = view.size();
+        let window = self.video.window(view.title(), width as u32 * DEBUG_SCALE, height as u32 * DEBUG_SCALE)
+            .resizable()
+            .build()
+            .map_err(|error| EmulatorError::Video(error.to_string()))?;
+        let mut canvas = window.into_canvas().build().map_err(|error| EmulatorError::Video(error.to_string()))?;
+        canvas.set_logical_size(width as u32, height as u32).map_err(|error| EmulatorError::Video(error.to_string()))?;
+
+        self.debug_windows.push(DebugWindow { view, canvas, image: Image::new(width, height) });
+        Ok(())
+    }
+
+    // As a mask in the order of Button::ALL
+    pub fn held_buttons(&self) -> u8 {
+        self.held
+    }
+
+    // Shows the screen saved in the slot for a moment, or says that it is empty
+    fn select_slot(&mut self, slot: u8) {
+        self.slot = slot;
+        self.thumbnail = None;
+        match self.states.info(slot) {
+            Some(Ok(info)) if !info.thumbnail.is_empty() => {
+                println!("Save state slot {}", slot);
+                self.thumbnail = Some((info.thumbnail, std::time::Instant::now()));
+            }
+            Some(Ok(_)) => println!("Save state slot {}", slot),
+            Some(Err(error)) => println!("Save state slot {}: {}", slot, error),
+            None => println!("Save state slot {} (empty)", slot)
+        }
+    }
+
+    fn handle_input(&mut self, bus: &mut MemoryBus) -> Result<bool, EmulatorError> {
+        let main_window = self.canvas.window().id();
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+
+        for event in events {
+            match event {
+                Event::Quit { .. } => return Ok(false),
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } if window_id == main_window => return Ok(false),
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                    self.debug_windows.retain(|window| window.canvas.window().id() != window_id);
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } if debug_view_for(keycode).is_some() => {
+                    if let Some(view) = debug_view_for(keycode) { self.toggle_debug_window(view)?; }
+                }
+                // Keys typed into a debug window don't reach the joypad
+                Event::KeyDown { window_id, keycode: Some(keycode), .. } if window_id != main_window => {
+                    let window = self.debug_windows.iter_mut().find(|window| window.canvas.window().id() == window_id);
+                    if let (Some(window), Some(key)) = (window, view_key_for(keycode)) { window.view.handle_key(bus, key); }
+                }
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(false),
+                Event::KeyDown { keycode: Some(Keycode::F6), repeat: false, .. } => bus.push_event(event::Event::SaveState { slot: self.slot }),
+                Event::KeyDown { keycode: Some(Keycode::F7), repeat: false, .. } => bus.push_event(event::Event::LoadState { slot: self.slot }),
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => self.rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::R), .. } => self.rewinding = false,
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } if slot_for(keycode).is_some() => {
+                    if let Some(slot) = slot_for(keycode) { self.select_slot(slot); }
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    if let Some(button) = button_for(keycode) { self.held |= button.held_bit(); }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(button) = button_for(keycode) { self.held &= !button.held_bit(); }
+                }
+                _ => { }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+fn slot_for(keycode: Keycode) -> Option<u8> {
+    let digits = [
+        Keycode::Num0, Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+        Keycode::Num5, Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9
+    ];
+    digits.iter().position(|&digit| digit == keycode).map(|slot| slot as u8)
+}
+
+fn debug_view_for(keycode: Keycode) -> Option<View> {
+    match keycode {
+        Keycode::F1 => Some(View::Memory { address: 0xC000, bank: None, input: String::new() }),
+        Keycode::F2 => Some(View::Tiles { palette: 0 }),
+        Keycode::F3 => Some(View::TileMaps),
+        Keycode::F4 => Some(View::Oam),
+        Keycode::F5 => Some(View::Palettes),
+        _ => None
+    }
+}
+
+fn view_key_for(keycode: Keycode) -> Option<ViewKey> {
+    let hex_digits = [
+        Keycode::Num0, Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4, Keycode::Num5, Keycode::Num6, Keycode::Num7,
+        Keycode::Num8, Keycode::Num9, Keycode::A, Keycode::B, Keycode::C, Keycode::D, Keycode::E, Keycode::F
+    ];
+    if let Some(digit) = hex_digits.iter().position(|&digit| digit == keycode) {
+        return Some(ViewKey::HexDigit(digit as u8))
+    }
+
+    match keycode {
+        Keycode::Up => Some(ViewKey::Up),
+        Keycode::Down => Some(ViewKey::Down),
+        Keycode::PageUp => Some(ViewKey::PageUp),
+        Keycode::PageDown => Some(ViewKey::PageDown),
+        Keycode::RightBracket => Some(ViewKey::NextBank),
+        Keycode::LeftBracket => Some(ViewKey::PreviousBank),
+        Keycode::Return => Some(ViewKey::Confirm),
+        Keycode::Escape | Keycode::Backspace => Some(ViewKey::Cancel),
+        _ => None
+    }
+}
+
+fn button_for(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::Backspace => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None
+    }
+}
@@ -0,0 +1,380 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::CPU;
+use crate::debugger::Watchpoint;
+use crate::error::EmulatorError;
+
+// GDB's z80 target, which also covers the SM83, has 13 16-bit registers:
+// af, bc, de, hl, sp, pc, ix, iy, af', bc', de', hl', ir. Only the first six exist here
+const REGISTER_COUNT: usize = 13;
+
+// Watchpoint ids of the stub, kept clear of the ones the terminal debugger hands out
+const FIRST_WATCHPOINT_ID: u32 = 0x10000;
+
+// Instructions between checks for an interrupt (Ctrl-C) from GDB while running
+const INTERRUPT_POLL_INTERVAL: u32 = 0x1000;
+
+const SIGTRAP: u8 = 5;
+
+// Largest packet GDB may send or receive, memory reads are capped to fit their hex in a reply
+const PACKET_SIZE: usize = 0x1000;
+
+enum StopReason {
+    Signal,
+    Breakpoint,
+    Watchpoint { address: u16, kind: &'static str }
+}
+
+pub struct GdbStub {
+    stream: Option<TcpStream>,
+    address: String,
+    breakpoints: Vec<u16>,
+    next_watchpoint_id: u32,
+    running: bool,
+    single_step: bool,
+    pending_stop: Option<StopReason>,
+    poll_countdown: u32,
+    no_ack: bool,
+    buffer: Vec<u8>
+}
+
+impl GdbStub {
+    // Waits for GDB to connect, the emulator starts stopped at its first instruction
+    pub fn listen(port: u16) -> Result<GdbStub, EmulatorError> {
+        let address = format!("127.0.0.1:{}", port);
+        let to_error = |error| EmulatorError::Io { path: address.clone(), error };
+
+        let listener = TcpListener::bind(&address).map_err(to_error)?;
+        println!("Waiting for GDB on {}", address);
+        let (stream, _) = listener.accept().map_err(to_error)?;
+        stream.set_nodelay(true).map_err(to_error)?;
+        println!("GDB connected");
+
+        Ok(GdbStub {
+            stream: Some(stream),
+            address,
+            breakpoints: Vec::new(),
+            next_watchpoint_id: FIRST_WATCHPOINT_ID,
+            running: false,
+            single_step: false,
+            pending_stop: None,
+            poll_countdown: INTERRUPT_POLL_INTERVAL,
+            no_ack: false,
+            buffer: Vec::new()
+        })
+    }
+
+    pub fn owns_watchpoint(&self, id: u32) -> bool {
+        id >= FIRST_WATCHPOINT_ID
+    }
+
+    pub fn watchpoint_hit(&mut self, cpu: &CPU, id: u32, address: u16, write: bool) {
+        let kind = match cpu.bus.watchpoints.iter().find(|watchpoint| watchpoint.id == id) {
+            Some(watchpoint) if watchpoint.read && watchpoint.write => "awatch",
+            _ if write => "watch",
+            _ => "rwatch"
+        };
+        self.pending_stop = Some(StopReason::Watchpoint { address, kind });
+    }
+
+    // Called before every instruction, serves GDB while the emulator is stopped.
+    // Returns false once GDB kills the target
+    pub fn update(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        if self.stream.is_none() { return Ok(true) }
+
+        if self.running {
+            // Steps of a halted CPU run no instruction, they neither finish a step nor hit breakpoints
+            let fetching = cpu.fetches_instruction();
+            let mut stop = self.pending_stop.take();
+            if stop.is_none() && self.single_step && fetching {
+                stop = Some(StopReason::Signal);
+            }
+            if stop.is_none() && fetching && self.breakpoints.contains(&cpu.pc) {
+                stop = Some(StopReason::Breakpoint);
+            }
+
+            if stop.is_none() {
+                self.poll_countdown -= 1;
+                if self.poll_countdown == 0 {
+                    self.poll_countdown = INTERRUPT_POLL_INTERVAL;
+                    if self.poll_interrupt()? { stop = Some(StopReason::Signal); }
+                }
+            }
+
+            let Some(stop) = stop else { return Ok(true) };
+            self.running = false;
+            self.single_step = false;
+            self.send_stop(&stop)?;
+        }
+
+        self.serve(cpu)
+    }
+
+    // Handles packets until GDB resumes, detaches or kills the target
+    fn serve(&mut self, cpu: &mut CPU) -> Result<bool, EmulatorError> {
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                // The connection closed, keep running without the stub
+                println!("GDB disconnected");
+                self.detach(cpu);
+                return Ok(true)
+            };
+            let (command, arguments) = packet.split_at(1.min(packet.len()));
+
+            let reply = match command {
+                "?" => format!("S{:02x}", SIGTRAP),
+                "g" => {
+                    let mut registers = [0u16; REGISTER_COUNT];
+                    registers[.. 6].copy_from_slice(&[cpu.registers.get_af(), cpu.registers.get_bc(), cpu.registers.get_de(), cpu.registers.get_hl(), cpu.sp, cpu.pc]);
+                    registers.iter().map(|value| format!("{:02x}{:02x}", value & 0xFF, value >> 8)).collect()
+                }
+                "G" => {
+                    let bytes = decode_hex(arguments);
+                    for (index, value) in bytes.chunks(2).take(6).enumerate() {
+                        if let [low, high] = value { set_register(cpu, index, u16::from_le_bytes([*low, *high])); }
+                    }
+                    "OK".to_string()
+                }
+                "p" => match usize::from_str_radix(arguments, 16) {
+                    Ok(index) if index < REGISTER_COUNT => {
+                        let value = get_register(cpu, index);
+                        format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+                    }
+                    _ => "E01".to_string()
+                },
+                "P" => match arguments.split_once('=') {
+                    Some((index, value)) => {
+                        let bytes = decode_hex(value);
+                        match (usize::from_str_radix(index, 16), bytes.as_slice()) {
+                            (Ok(index), [low, high]) if index < REGISTER_COUNT => {
+                                set_register(cpu, index, u16::from_le_bytes([*low, *high]));
+                                "OK".to_string()
+                            }
+                            _ => "E01".to_string()
+                        }
+                    }
+                    None => "E01".to_string()
+                },
+                "m" => match parse_address_length(arguments) {
+                    Some((address, length)) if length.checked_mul(2).is_some_and(|size| size <= PACKET_SIZE) => (0 .. length)
+                        .map(|offset| format!("{:02x}", cpu.bus.read_byte(address.wrapping_add(offset as u16))))
+                        .collect(),
+                    _ => "E01".to_string()
+                },
+                "M" => match arguments.split_once(':').and_then(|(range, data)| Some((parse_address_length(range)?, data))) {
+                    Some(((address, _), data)) => {
+                        for (offset, byte) in decode_hex(data).into_iter().enumerate() {
+                            cpu.bus.write_byte(address.wrapping_add(offset as u16), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string()
+                },
+                "c" | "s" => {
+                    if let Ok(address) = u16::from_str_radix(arguments, 16) { cpu.pc = address; }
+                    self.running = true;
+                    self.single_step = command == "s";
+                    return Ok(true)
+                }
+                "Z" | "z" => self.update_breakpoint(cpu, command == "Z", arguments),
+                "D" => {
+                    self.send_packet("OK")?;
+                    self.detach(cpu);
+                    return Ok(true)
+                }
+                "k" => return Ok(false),
+                "H" => "OK".to_string(),
+                "T" => "OK".to_string(),
+                "q" | "Q" => self.query(&packet),
+                _ => String::new()
+            };
+
+            self.send_packet(&reply)?;
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        match packet.split(':').next().unwrap_or_default() {
+            "qSupported" => format!("PacketSize={:x};swbreak+;hwbreak+;QStartNoAckMode+", PACKET_SIZE),
+            "QStartNoAckMode" => {
+                // Takes effect after this packet is acknowledged
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new()
+        }
+    }
+
+    fn update_breakpoint(&mut self, cpu: &mut CPU, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let (Some(kind), Some(address)) = (fields.next(), fields.next().and_then(|address| u16::from_str_radix(address, 16).ok())) else {
+            return "E01".to_string()
+        };
+        let length = fields.next().and_then(|length| u16::from_str_radix(length, 16).ok()).unwrap_or(1).max(1);
+
+        match kind {
+            // Software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if insert {
+                    if !self.breakpoints.contains(&address) { self.breakpoints.push(address); }
+                } else {
+                    self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                }
+            }
+            "2" | "3" | "4" => {
+                let (read, write) = match kind {
+                    "2" => (false, true),
+                    "3" => (true, false),
+                    _ => (true, true)
+                };
+                let end = address.saturating_add(length - 1);
+                if insert {
+                    cpu.bus.watchpoints.push(Watchpoint { id: self.next_watchpoint_id, start: address, end, read, write, execute: false });
+                    self.next_watchpoint_id += 1;
+                } else {
+                    cpu.bus.watchpoints.retain(|watchpoint| {
+                        !(watchpoint.id >= FIRST_WATCHPOINT_ID && watchpoint.start == address && watchpoint.end == end && watchpoint.read == read && watchpoint.write == write)
+                    });
+                }
+            }
+            _ => return String::new()
+        }
+
+        "OK".to_string()
+    }
+
+    fn detach(&mut self, cpu: &mut CPU) {
+        cpu.bus.watchpoints.retain(|watchpoint| watchpoint.id < FIRST_WATCHPOINT_ID);
+        self.breakpoints.clear();
+        self.stream = None;
+    }
+
+    fn send_stop(&mut self, stop: &StopReason) -> Result<(), EmulatorError> {
+        let reply = match stop {
+            StopReason::Signal => format!("S{:02x}", SIGTRAP),
+            StopReason::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint { address, kind } => format!("T{:02x}{}:{:04x};", SIGTRAP, kind, address)
+        };
+        self.send_packet(&reply)
+    }
+
+    // Checks for the 0x03 byte GDB sends to interrupt a running target
+    fn poll_interrupt(&mut self) -> Result<bool, EmulatorError> {
+        let Some(stream) = &mut self.stream else { return Ok(false) };
+        let to_error = |error| EmulatorError::Io { path: self.address.clone(), error };
+
+        stream.set_nonblocking(true).map_err(to_error)?;
+        let mut bytes = [0; 64];
+        let read = stream.read(&mut bytes);
+        stream.set_nonblocking(false).map_err(to_error)?;
+
+        match read {
+            Ok(length) => {
+                self.buffer.extend_from_slice(&bytes[.. length]);
+                Ok(self.buffer.contains(&0x03))
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(to_error(error))
+        }
+    }
+
+    // Returns None when the connection is closed
+    fn read_packet(&mut self) -> Result<Option<String>, EmulatorError> {
+        loop {
+            // Acknowledgements and interrupts outside of packets carry no information once stopped
+            while let Some(&byte) = self.buffer.first() {
+                if byte == b'$' { break }
+                self.buffer.remove(0);
+            }
+
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'#') {
+                if self.buffer.len() >= end + 3 {
+                    let data: Vec<u8> = self.buffer[1 .. end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buffer[end + 1 .. end + 3]).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+                    self.buffer.drain(.. end + 3);
+
+                    let valid = checksum == Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+                    if !self.no_ack { self.write_all(if valid { b"+" } else { b"-" })?; }
+                    if !valid { continue }
+
+                    return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()))
+                }
+            }
+
+            let Some(stream) = &mut self.stream else { return Ok(None) };
+            let mut bytes = [0; 1024];
+            let length = stream.read(&mut bytes).map_err(|error| EmulatorError::Io { path: self.address.clone(), error })?;
+            if length == 0 { return Ok(None) }
+            self.buffer.extend_from_slice(&bytes[.. length]);
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), EmulatorError> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.write_all(format!("${}#{:02x}", data, checksum).as_bytes())
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let Some(stream) = &mut self.stream else { return Ok(()) };
+        stream.write_all(bytes).map_err(|error| EmulatorError::Io { path: self.address.clone(), error })
+    }
+}
+
+fn get_register(cpu: &CPU, index: usize) -> u16 {
+    match index {
+        0 => cpu.registers.get_af(),
+        1 => cpu.registers.get_bc(),
+        2 => cpu.registers.get_de(),
+        3 => cpu.registers.get_hl(),
+        4 => cpu.sp,
+        5 => cpu.pc,
+        _ => 0
+    }
+}
+
+// Writes to the registers the SM83 doesn't have are ignored
+fn set_register(cpu: &mut CPU, index: usize, value: u16) {
+    match index {
+        0 => cpu.registers.set_af(value & 0xFFF0),
+        1 => cpu.registers.set_bc(value),
+        2 => cpu.registers.set_de(value),
+        3 => cpu.registers.set_hl(value),
+        4 => cpu.sp = value,
+        5 => cpu.pc = value,
+        _ => { }
+    }
+}
+
+fn parse_address_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn decode_hex(text: &str) -> Vec<u8> {
+    text.as_bytes()
+        .chunks(2)
+        .filter_map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+// Binary data escapes #, $, } and * as } followed by the byte xor 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &byte in data {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(byte);
+        }
+    }
+    bytes
+}
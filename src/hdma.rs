@@ -0,0 +1,156 @@
+use crate::save_state::{StateWriter, StateReader};
+
+// Registers of the CGB VRAM DMA, the bus does the copying since it needs both sides
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16,
+    // Blocks of 16 bytes still to copy, minus one, as read back from HDMA5
+    pub length: u8,
+    pub hblank_active: bool,
+    // An HBlank started since the last block was copied
+    pub hblank_pending: bool
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            hblank_active: false,
+            hblank_pending: false
+        }
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            // Bit 7 is cleared while an HBlank transfer is still running
+            0xFF55 => ((!self.hblank_active as u8) << 7) | self.length,
+            _ => 0xFF
+        }
+    }
+
+    // Returns the number of blocks to copy right away for a general purpose transfer.
+    // In HBlank or with the LCD off an HBlank transfer has its first block copied right away too
+    pub fn write(&mut self, address: usize, byte: u8, in_hblank: bool) -> Option<u8> {
+        match address {
+            0xFF51 => { self.source = (self.source & 0x00FF) | ((byte as u16) << 8); }
+            0xFF52 => { self.source = (self.source & 0xFF00) | (byte & 0xF0) as u16; }
+            0xFF53 => { self.destination = (self.destination & 0x00FF) | (((byte & 0x1F) as u16) << 8); }
+            0xFF54 => { self.destination = (self.destination & 0xFF00) | (byte & 0xF0) as u16; }
+            0xFF55 => {
+                // Clearing bit 7 during an HBlank transfer cancels it instead of starting a new one
+                if self.hblank_active && (byte & 0x80) == 0 {
+                    self.hblank_active = false;
+                    self.hblank_pending = false;
+                    return None
+                }
+
+                self.length = byte & 0x7F;
+                if (byte & 0x80) != 0 {
+                    self.hblank_active = true;
+                    self.hblank_pending = in_hblank;
+                    return None
+                }
+                return Some(self.length + 1)
+            }
+            _ => { }
+        }
+
+        None
+    }
+
+    // Moves on to the next block, returns false once the transfer is done
+    pub fn advance(&mut self) -> bool {
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+
+        if self.length == 0 {
+            self.length = 0x7F;
+            self.hblank_active = false;
+            return false
+        }
+        self.length -= 1;
+
+        true
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.length);
+        state.bool(self.hblank_active);
+        state.bool(self.hblank_pending);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.source = state.u16();
+        self.destination = state.u16();
+        self.length = state.u8();
+        self.hblank_active = state.bool();
+        self.hblank_pending = state.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn general_purpose_transfer_copies_all_blocks() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.write(0xFF55, 0x03, false), Some(4));
+        assert!(!hdma.hblank_active);
+    }
+
+    #[test]
+    fn hblank_transfer_counts_down_remaining_length() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.write(0xFF55, 0x82, false), None);
+        assert_eq!(hdma.read(0xFF55), 0x02);
+
+        assert!(hdma.advance());
+        assert_eq!(hdma.read(0xFF55), 0x01);
+        assert!(hdma.advance());
+        assert!(!hdma.advance());
+        assert_eq!(hdma.read(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn clearing_bit_7_cancels_an_hblank_transfer() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF55, 0x85, false);
+        hdma.advance();
+        hdma.hblank_pending = true;
+
+        assert_eq!(hdma.write(0xFF55, 0x00, false), None);
+        assert!(!hdma.hblank_active);
+        assert!(!hdma.hblank_pending);
+        // The blocks that were left stay readable
+        assert_eq!(hdma.read(0xFF55), 0x84);
+    }
+
+    #[test]
+    fn hblank_transfer_started_in_hblank_copies_right_away() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF55, 0x80, true);
+        assert!(hdma.hblank_pending);
+
+        hdma.write(0xFF55, 0x00, false);
+        hdma.write(0xFF55, 0x80, false);
+        assert!(!hdma.hblank_pending);
+    }
+
+    #[test]
+    fn blocks_advance_source_and_destination() {
+        let mut hdma = Hdma::new();
+        for (address, byte) in [(0xFF51, 0xC1), (0xFF52, 0x2F), (0xFF53, 0xFF), (0xFF54, 0xF5)] {
+            hdma.write(address, byte, false);
+        }
+        assert_eq!((hdma.source, hdma.destination), (0xC120, 0x1FF0));
+
+        hdma.write(0xFF55, 0x81, false);
+        hdma.advance();
+        assert_eq!((hdma.source, hdma.destination), (0xC130, 0x0000));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::save_state::{StateWriter, StateReader};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start
+}
+
+impl Button {
+    // In the order of their bits in a held buttons mask, the directions in the low nibble
+    pub const ALL: [Button; 8] = [
+        Button::Right, Button::Left, Button::Up, Button::Down,
+        Button::A, Button::B, Button::Select, Button::Start
+    ];
+
+    fn is_direction(&self) -> bool {
+        matches!(self, Button::Right | Button::Left | Button::Up | Button::Down)
+    }
+
+    // Bit of the button in a held buttons mask
+    pub fn held_bit(&self) -> u8 {
+        if self.is_direction() { self.mask() } else { self.mask() << 4 }
+    }
+
+    fn mask(&self) -> u8 {
+        match self {
+            Button::Right | Button::A => 0b0001,
+            Button::Left | Button::B => 0b0010,
+            Button::Up | Button::Select => 0b0100,
+            Button::Down | Button::Start => 0b1000
+        }
+    }
+}
+
+pub struct Joypad {
+    select_buttons: bool,
+    select_directions: bool,
+    // Bit set means the button is held down, in the same order as the P1 lines
+    buttons: u8,
+    directions: u8
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select_buttons: false,
+            select_directions: false,
+            buttons: 0,
+            directions: 0
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select_buttons { lines &= !self.buttons; }
+        if self.select_directions { lines &= !self.directions; }
+
+        0xC0 |
+        ((!self.select_buttons as u8) << 5) |
+        ((!self.select_directions as u8) << 4) |
+        lines
+    }
+
+    pub fn write(&mut self, byte: u8) {
+        self.select_buttons = (byte & 0b00100000) == 0;
+        self.select_directions = (byte & 0b00010000) == 0;
+    }
+
+    // Returns true if a selected line went from high to low, which requests a joypad interrupt
+    pub fn press(&mut self, button: Button) -> bool {
+        let lines_before = self.read() & 0x0F;
+
+        if button.is_direction() {
+            self.directions |= button.mask();
+        } else {
+            self.buttons |= button.mask();
+        }
+
+        (lines_before & !(self.read() & 0x0F)) != 0
+    }
+
+    pub fn release(&mut self, button: Button) {
+        if button.is_direction() {
+            self.directions &= !button.mask();
+        } else {
+            self.buttons &= !button.mask();
+        }
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        let lines = if button.is_direction() { self.directions } else { self.buttons };
+        (lines & button.mask()) != 0
+    }
+
+    // As a mask in the order of Button::ALL
+    pub fn held(&self) -> u8 {
+        self.directions | (self.buttons << 4)
+    }
+
+    pub fn is_any_selected_pressed(&self) -> bool {
+        (self.read() & 0x0F) != 0x0F
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.select_buttons);
+        state.bool(self.select_directions);
+        state.u8(self.buttons);
+        state.u8(self.directions);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.select_buttons = state.bool();
+        self.select_directions = state.bool();
+        self.buttons = state.u8();
+        self.directions = state.u8();
+    }
+}
@@ -2,169 +2,383 @@
 #![allow(dead_code)]
 #![allow(unused_assignments)]
 use std::io::Read;
-use std::io::Write;
+use std::rc::Rc;
 
 mod cpu;
 mod ppu;
 mod memory_bus;
 mod interrupt_flag;
 mod timer;
+mod joypad;
+mod event;
+mod error;
+mod model;
+mod config;
+mod boot_rom;
+mod cartridge;
+mod serial;
+mod hdma;
+mod compat_palette;
+mod sgb;
+mod frontend;
+mod debug_views;
+mod debugger;
+mod gdb_stub;
+mod symbols;
+mod tracer;
+mod trace_diff;
+mod profiler;
+mod code_data_log;
+mod save_state;
+mod rewind;
+mod movie;
+mod test_runner;
 
-enum Mode { 
-    Main,
-    Boot, 
-    Debug
-}
+use error::EmulatorError;
+use config::{Config, Mode};
 
 fn main() {
-    let mode = Mode::Main;
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        std::process::exit(error.exit_code())
+    }
+}
 
-    let boot_rom_path = "./roms/dmg_boot.bin";
-    let test_rom_path = "./tests_blargg/instr_timing/instr_timing.gb";
+fn run() -> Result<(), EmulatorError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::from_args(&args)?;
 
-    let boot_rom = read_rom(boot_rom_path);
-    let game_rom = read_rom(test_rom_path);
+    let game_rom = read_rom(&config.rom_path)?;
+    if game_rom.len() < memory_bus::GAME_ROM_MIN_SIZE {
+        return Err(EmulatorError::InvalidGameRomSize { minimum: memory_bus::GAME_ROM_MIN_SIZE, found: game_rom.len() })
+    }
+    let symbols = Rc::new(load_symbols(&config)?);
 
-    println!("ok!");
+    if !matches!(config.mode, Mode::Disasm) { println!("ok!"); }
     
-    match mode {
+    match config.mode {
+        Mode::Disasm => {
+            disassemble_rom(&game_rom, &symbols, config.disasm_bank, config.disasm_from, config.disasm_to);
+            Ok(())
+        }
+
         Mode::Boot =>{
-            let memory_bus = memory_bus::MemoryBus::new(boot_rom, game_rom);
-            let mut _cpu = cpu::CPU::new(memory_bus);
+            let mut machine = Machine::new(&config, game_rom, &symbols)?;
             loop {
-                if _cpu.pc >= 0x100 {
+                if machine.cpu.pc >= 0x100 {
                     println!(""); // 329480 CPU cycles later
                     println!(" S U C C E S S ");
                     println!("Boot successfuly completed! Exiting...");
-                    
-                    std::process::exit(0)
-                }
-
-                let mut cycles = _cpu.step();
-                if cycles != 4 { panic!("Unhandled CPU cycle detected.") }
-
-                while cycles > 0 {
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        //println!("Requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        //println!("Requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
 
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        //println!("Requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        //println!("Requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-
-                    cycles = 0;
+                    return Ok(())
                 }
+
+                if !machine.step(0)? { return Ok(()) }
             }
         }
 
         Mode::Main => {
-            let memory_bus = memory_bus::MemoryBus::new(boot_rom, game_rom);
-            let mut _cpu = cpu::CPU::new(memory_bus);
+            let mut machine = Machine::new(&config, game_rom, &symbols)?;
+            let mut frontend = frontend::Frontend::new(machine.cpu.bus.model.is_sgb(), machine.states.clone())?;
             loop {
-                if _cpu.pc >= 0x100 && _cpu.is_booted == false {
-                    _cpu.is_booted = true;
+                if machine.cpu.pc >= 0x100 && machine.cpu.is_booted == false {
+                    machine.cpu.is_booted = true;
                     println!(""); // 329480 CPU cycles later
                     println!(" S U C C E S S ");
                     println!("Boot successfuly completed! Continuing...");
                     println!("");
                 }
             
-                let mut cycles = _cpu.step();
-                if cycles != 4 { panic!("Unhandled CPU cycle detected.") }
-
-                while cycles > 0 {
-                    if _cpu.pc > 0xC2D2 && _cpu.pc < 0xC2CF { println!("Main:"); }
-
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        println!("Main requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-                    _cpu.bus.ppu.tick();
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        println!("Main requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-                    _cpu.bus.ppu.tick();
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        println!("Main requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-                    _cpu.bus.ppu.tick();
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                        println!("Main requesting timer interrupt at pc 0x{:02X}", _cpu.pc);
-                    }
-                    _cpu.bus.ppu.tick();
-
-                    cycles = 0;
-                }
+                if !frontend.update(&mut machine.cpu.bus)? { return Ok(()) }
+                if !machine.step(frontend.held_buttons())? { return Ok(()) }
             }
         }
 
-        // This mode writes emulator state to a log file after every instruction
-        // Change log path to ensure your old logs don't get overwritten
+        // This mode starts paused in the terminal debugger
         Mode::Debug => {
-            let memory_bus = memory_bus::MemoryBus::new(boot_rom, game_rom);
-            let mut _cpu = cpu::CPU::new(memory_bus);
-            let mut file = std::fs::File::create("./logs/log_itiming.txt").expect("error creating file");
+            let mut machine = Machine::new(&config, game_rom, &symbols)?;
+            machine.debugger.pause();
 
             loop {
-                if _cpu.pc >= 0x100 && _cpu.is_booted == false {
-                    _cpu.is_booted = true;
+                if machine.cpu.pc >= 0x100 && machine.cpu.is_booted == false {
+                    machine.cpu.is_booted = true;
                     println!(""); // 329480 CPU cycles later
                     println!(" S U C C E S S ");
                     println!("Boot successfuly completed! Continuing...");
-                    //writeln!(&mut file, "A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})", _cpu.registers.a, u8::from(_cpu.registers.f), _cpu.registers.b, _cpu.registers.c, _cpu.registers.d, _cpu.registers.e, _cpu.registers.h, _cpu.registers.l, _cpu.sp, _cpu.pc, _cpu.bus.read_byte(_cpu.pc), _cpu.bus.read_byte(_cpu.pc + 1), _cpu.bus.read_byte(_cpu.pc + 2), _cpu.bus.read_byte(_cpu.pc + 3)).expect("error logging to file");
                 }
             
-                let mut cycles = _cpu.step();
-                if cycles != 4 { panic!("Unhandled CPU cycle detected.") }
+                if !machine.step(0)? { return Ok(()) }
+            }
+        }
 
-                while cycles > 0 {
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                    }
+        Mode::Diff => {
+            let mut machine = Machine::new(&config, game_rom, &symbols)?;
+            let reference_path = config.reference_path.as_deref().unwrap_or_default();
+            let mut diff = trace_diff::TraceDiff::new(reference_path, config.trace_format.clone(), symbols.clone())?;
 
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
+            loop {
+                match diff.check(&machine.cpu)? {
+                    trace_diff::DiffResult::Matching => { }
+                    trace_diff::DiffResult::ReferenceEnded => {
+                        println!("All {} lines of {} matched", diff.line_number(), reference_path);
+                        return Ok(())
                     }
+                    trace_diff::DiffResult::Diverged => return Err(EmulatorError::TraceDiverged { line: diff.line_number() })
+                }
 
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                    }
+                if !machine.step(0)? { return Ok(()) }
+            }
+        }
 
-                    if _cpu.bus.timer.tick() {
-                        _cpu.bus.request_timer_interrupt();
-                    }
+        // Runs without a window until the test ROM reports its result, for scripts and CI
+        Mode::Test => {
+            let mut machine = Machine::new(&config, game_rom, &symbols)?;
+            let mut runner = test_runner::TestRunner::new(config.test_detectors.clone(), config.test_timeout);
 
-                    cycles = 0;
+            loop {
+                match runner.check(&mut machine.cpu)? {
+                    None => { }
+                    Some(test_runner::Verdict::Passed(reason)) => {
+                        println!("Passed: {}", reason);
+                        return Ok(())
+                    }
+                    Some(test_runner::Verdict::Failed(reason)) => return Err(EmulatorError::TestFailed(reason))
                 }
 
-                if _cpu.is_booted { writeln!(&mut file, "A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X}). tima: {:08b}. if: {:08b}", _cpu.registers.a, u8::from(_cpu.registers.f), _cpu.registers.b, _cpu.registers.c, _cpu.registers.d, _cpu.registers.e, _cpu.registers.h, _cpu.registers.l, _cpu.sp, _cpu.pc, _cpu.bus.read_byte(_cpu.pc), _cpu.bus.read_byte(_cpu.pc + 1), _cpu.bus.read_byte(_cpu.pc + 2), _cpu.bus.read_byte(_cpu.pc + 3), _cpu.bus.timer.tima, _cpu.bus.interrupt_flag.to_byte()).expect("error logging to file"); }
+                if !machine.step(0)? { return Ok(()) }
+            }
+        }
+    }
+}
+
+// The CPU with everything the modes attach to it, set up the same way for all of them
+struct Machine {
+    cpu: cpu::CPU,
+    debugger: debugger::Debugger,
+    gdb: Option<gdb_stub::GdbStub>,
+    tracer: Option<tracer::Tracer>,
+    profiler: Option<profiler::Profiler>,
+    rewind: Option<rewind::Rewind>,
+    movie: Option<movie::Movie>,
+    states: save_state::SaveStates,
+    break_on_lock: bool,
+    // Serial output is echoed to the terminal except when stdout carries a report or the trace
+    echo_serial: bool,
+    serial_echoed: usize
+}
+
+impl Machine {
+    fn new(config: &Config, game_rom: Vec<u8>, symbols: &Rc<symbols::Symbols>) -> Result<Machine, EmulatorError> {
+        let rom_size = game_rom.len();
+        let states = save_state::SaveStates::new(&config.rom_path);
+        let played_movie = config.play_movie_path.as_deref().map(movie::Movie::load).transpose()?;
+        // Without --model the CGB flag in the cartridge header picks the hardware, or the model a movie was recorded on
+        let model = config.model.or(played_movie.as_ref().and_then(movie::Movie::model))
+            .unwrap_or_else(|| cartridge::Header::parse(&game_rom).preferred_model());
+
+        let boot_rom = if config.skip_boot {
+            None
+        } else if let Some(path) = &config.boot_rom_path {
+            Some(read_rom(path)?)
+        } else {
+            Some(boot_rom::built_in(model))
+        };
+        let palette_choice = match config.palette {
+            Some(palette) => compat_palette::PaletteChoice::Fixed(palette),
+            None if config.boot_rom_path.is_some() && !config.skip_boot => compat_palette::PaletteChoice::BootRom,
+            None => compat_palette::PaletteChoice::Automatic
+        };
+        let mut movie = create_movie(config, played_movie, &game_rom, boot_rom.as_deref(), &states)?;
+
+        let memory_bus = memory_bus::MemoryBus::new(boot_rom, game_rom, model)?;
+        let mut cpu = cpu::CPU::new(memory_bus);
+        cpu.bus.palette_choice = palette_choice;
+        if config.skip_boot { cpu.skip_boot(model); }
+        cpu.bus.stub_ly = config.stub_ly;
+        cpu.bus.code_data_log = create_code_data_log(config, rom_size)?;
+        if let Some(movie) = &mut movie { movie.start(&mut cpu)?; }
+
+        // GDB is left out of the modes that run to a result on their own, rewinding needs the window
+        let gdb = match config.gdb_port {
+            Some(port) if matches!(config.mode, Mode::Boot | Mode::Main | Mode::Debug) => Some(gdb_stub::GdbStub::listen(port)?),
+            _ => None
+        };
+        let rewind = if matches!(config.mode, Mode::Main) { create_rewind(config) } else { None };
+        let echo_serial = matches!(config.mode, Mode::Boot | Mode::Main | Mode::Debug) && config.trace_path.as_deref() != Some("-");
+
+        Ok(Machine {
+            cpu,
+            debugger: debugger::Debugger::new(symbols.clone(), states.clone()),
+            gdb,
+            tracer: create_tracer(config, symbols)?,
+            profiler: create_profiler(config, symbols)?,
+            rewind,
+            movie,
+            states,
+            break_on_lock: config.break_on_lock,
+            echo_serial,
+            serial_echoed: 0
+        })
+    }
+
+    // Runs one step with the debuggers and tools around it, false once one of them quits.
+    // The joypad gets the movie input of the frame or else the buttons held on the keyboard
+    fn step(&mut self, held: u8) -> Result<bool, EmulatorError> {
+        match &mut self.movie {
+            Some(movie) if movie.is_active() => movie.update(&mut self.cpu.bus, held),
+            _ => self.cpu.bus.set_held_buttons(held)
+        }
+
+        if !self.debugger.update(&mut self.cpu)? { return Ok(false) }
+        if let Some(gdb) = &mut self.gdb {
+            if !gdb.update(&mut self.cpu)? { return Ok(false) }
+        }
+        if let Some(tracer) = &mut self.tracer { tracer.trace(&self.cpu)?; }
+        if let Some(profiler) = &mut self.profiler { profiler.record(&self.cpu); }
+        self.cpu.step();
+        self.handle_events();
+        if let Some(rewind) = &mut self.rewind { rewind.record(&self.cpu); }
+        if self.echo_serial { self.echo_serial_output(); }
+
+        Ok(true)
+    }
+
+    // Prints the bytes sent over the serial port since the last call
+    fn echo_serial_output(&mut self) {
+        let output = &self.cpu.bus.serial.output;
+        if output.len() == self.serial_echoed { return }
+
+        let text: String = output[self.serial_echoed ..].iter().map(|&byte| byte as char).collect();
+        print!("{}", text);
+        self.serial_echoed = output.len();
+    }
+
+    fn handle_events(&mut self) {
+        for event in self.cpu.bus.take_events() {
+            match event {
+                event::Event::CpuLocked { opcode, pc } => {
+                    println!();
+                    println!("CPU locked up by illegal opcode 0x{:02X} at 0x{:04X}", opcode, pc);
+
+                    if self.break_on_lock { self.debugger.pause(); }
+                }
+                event::Event::Watchpoint { id, address, value, write } => match &mut self.gdb {
+                    Some(gdb) if gdb.owns_watchpoint(id) => gdb.watchpoint_hit(&self.cpu, id, address, write),
+                    _ => self.debugger.watchpoint_hit(id, address, value, write)
+                },
+                event::Event::StackImbalance { pc, sp, expected, found } => self.debugger.stack_imbalance(&self.cpu, pc, sp, expected, found),
+                event::Event::SaveState { slot } => match self.states.save(&self.cpu, slot) {
+                    Ok(()) => println!("Saved state to slot {}", slot),
+                    Err(error) => println!("{}", error)
+                },
+                event::Event::LoadState { slot } => match self.states.load(&mut self.cpu, slot) {
+                    Ok(()) => {
+                        println!("Loaded state from slot {}", slot);
+                        if let Some(movie) = &mut self.movie { movie.rerecord(); }
+                    }
+                    Err(error) => println!("{}", error)
+                },
+                event::Event::StateLoaded => {
+                    if let Some(movie) = &mut self.movie { movie.rerecord(); }
+                }
+                event::Event::Rewind => {
+                    if let Some(rewind) = &mut self.rewind {
+                        if rewind.step_back(&mut self.cpu) {
+                            if let Some(movie) = &mut self.movie { movie.rerecord(); }
+                        }
+                    }
+                }
             }
         }
     }
 }
 
-fn read_rom(path: &str) -> Vec<u8> {
-    let error_message: String = format!("Could not read rom at {}", path.to_string());
+// Prints one instruction per line, banked addresses are read from the given ROM bank
+fn disassemble_rom(game_rom: &[u8], symbols: &symbols::Symbols, bank: u16, from: u16, to: u16) {
+    let read = |address: u16| {
+        let offset = match address {
+            0x0000 ..= 0x3FFF => address as usize,
+            0x4000 ..= 0x7FFF => bank as usize * 0x4000 + (address as usize - 0x4000),
+            _ => return 0xFF
+        };
+        game_rom.get(offset).copied().unwrap_or(0xFF)
+    };
+
+    let bank_at = |address: u16| if (0x4000 ..= 0x7FFF).contains(&address) { bank } else { 0 };
+    let label = |target: u16| symbols.describe(bank_at(target), target);
+
+    let mut address = from as u32;
+    while address <= to as u32 {
+        let line = cpu::disassembler::disassemble_with_labels(read, address as u16, label);
+        let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let line_bank = bank_at(address as u16);
+        if let Some(name) = symbols.label(line_bank, address as u16) { println!("{}:", name); }
+        println!("{:02X}:{:04X}  {:<9} {:<22} ; {}", line_bank, address, bytes.join(" "), line.text, line.cycles_text());
+        address += line.length() as u32;
+    }
+}
+
+fn create_tracer(config: &Config, symbols: &Rc<symbols::Symbols>) -> Result<Option<tracer::Tracer>, EmulatorError> {
+    match &config.trace_path {
+        Some(path) => Ok(Some(tracer::Tracer::new(path, config.trace_format.clone(), config.trace_start, config.trace_stop, symbols.clone())?)),
+        None => Ok(None)
+    }
+}
+
+fn create_profiler(config: &Config, symbols: &Rc<symbols::Symbols>) -> Result<Option<profiler::Profiler>, EmulatorError> {
+    if config.profile_path.is_none() && config.collapsed_stacks_path.is_none() { return Ok(None) }
+    Ok(Some(profiler::Profiler::new(config.profile_path.as_deref(), config.collapsed_stacks_path.as_deref(), symbols.clone())?))
+}
+
+fn create_code_data_log(config: &Config, rom_size: usize) -> Result<Option<code_data_log::CodeDataLog>, EmulatorError> {
+    match &config.cdl_path {
+        Some(path) => Ok(Some(code_data_log::CodeDataLog::new(path, rom_size)?)),
+        None => Ok(None)
+    }
+}
+
+// The movie to play, or a new one to record from power on or the given save state
+// A played movie is loaded earlier, its model decides the boot ROM it is checked against here
+fn create_movie(config: &Config, played_movie: Option<movie::Movie>, game_rom: &[u8], boot_rom: Option<&[u8]>, states: &save_state::SaveStates) -> Result<Option<movie::Movie>, EmulatorError> {
+    if let Some(movie) = played_movie {
+        movie.check(game_rom, boot_rom)?;
+        return Ok(Some(movie))
+    }
+
+    match &config.record_movie_path {
+        Some(path) => {
+            let start_state = config.movie_state_slot.map(|slot| states.read(slot)).transpose()?;
+            Ok(Some(movie::Movie::record(path, game_rom, boot_rom, start_state)))
+        }
+        None => Ok(None)
+    }
+}
+
+fn create_rewind(config: &Config) -> Option<rewind::Rewind> {
+    if config.rewind_memory == 0 { return None }
+    Some(rewind::Rewind::new(config.rewind_interval, config.rewind_memory * 1024 * 1024))
+}
+
+fn load_symbols(config: &Config) -> Result<symbols::Symbols, EmulatorError> {
+    if let Some(path) = &config.sym_path {
+        return symbols::Symbols::load(path)
+    }
+
+    let path = std::path::Path::new(&config.rom_path).with_extension("sym");
+    if path.exists() {
+        symbols::Symbols::load(&path.to_string_lossy())
+    } else {
+        Ok(symbols::Symbols::new())
+    }
+}
+
+fn read_rom(path: &str) -> Result<Vec<u8>, EmulatorError> {
+    let to_error = |error| EmulatorError::Io { path: path.to_string(), error };
 
-    let mut file = std::fs::File::open(path).expect(&error_message);
+    let mut file = std::fs::File::open(path).map_err(to_error)?;
     let mut rom = Vec::new();
 
-    file.read_to_end(&mut rom).expect(&error_message);
+    file.read_to_end(&mut rom).map_err(to_error)?;
     
-    rom
+    Ok(rom)
 }
\ No newline at end of file
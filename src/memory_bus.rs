@@ -1,11 +1,31 @@
-use crate::ppu::PPU;
+use crate::ppu::{PPU, PPUMode};
 use crate::interrupt_flag::InterruptFlag;
 use crate::timer::Timer;
+use crate::serial::Serial;
+use crate::hdma::Hdma;
+use crate::sgb::Sgb;
+use crate::joypad::{Joypad, Button};
+use crate::event::Event;
+use crate::error::EmulatorError;
+use crate::model::Model;
+use crate::cartridge::Header;
+use crate::compat_palette::{self, PaletteChoice};
+use crate::debugger::Watchpoint;
+use crate::code_data_log::{self, CodeDataLog, Region};
+use crate::save_state::{StateWriter, StateReader};
 
 pub const BOOT_ROM_BEGIN: usize = 0x00;
 pub const BOOT_ROM_END: usize = 0xFF;
 pub const BOOT_ROM_SIZE: usize = BOOT_ROM_END - BOOT_ROM_BEGIN + 1;
 
+// CGB boot ROMs continue after the cartridge header
+pub const CGB_BOOT_ROM_HIGH_BEGIN: usize = 0x200;
+pub const CGB_BOOT_ROM_HIGH_END: usize = 0x8FF;
+pub const CGB_BOOT_ROM_SIZE: usize = CGB_BOOT_ROM_HIGH_END + 1;
+
+// Everything up to the end of the cartridge header has to be present
+pub const GAME_ROM_MIN_SIZE: usize = 0x150;
+
 pub const ROM_BANK_0_BEGIN: usize = 0x0000;
 pub const ROM_BANK_0_END: usize = 0x3FFF;
 pub const ROM_BANK_0_SIZE: usize = ROM_BANK_0_END - ROM_BANK_0_BEGIN + 1;
@@ -26,6 +46,10 @@ pub const WORKING_RAM_BEGIN: usize = 0xC000;
 pub const WORKING_RAM_END: usize = 0xDFFF;
 pub const WORKING_RAM_SIZE: usize = WORKING_RAM_END - WORKING_RAM_BEGIN + 1;
 
+// 0xD000-0xDFFF is switched between banks 1-7 with SVBK on CGB
+pub const WORKING_RAM_BANK_SIZE: usize = 0x1000;
+pub const WORKING_RAM_BANKS: usize = 8;
+
 pub const ECHO_RAM_BEGIN: usize = 0xE000;
 pub const ECHO_RAM_END: usize = 0xFDFF;
 
@@ -46,28 +70,77 @@ pub const ZERO_PAGE_SIZE: usize = ZERO_PAGE_END - ZERO_PAGE_BEGIN + 1;
 
 pub const INTERRUPT_ENABLE_REGISTER: usize = 0xFFFF;
 
+// IO registers as the DMG boot ROM leaves them, per model differences are applied on top
+const POST_BOOT_IO_REGISTERS: [(usize, u8); 30] = [
+    (0xFF01, 0x00), (0xFF02, 0x7E),
+    (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+    (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+    (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+    (0xFF40, 0x91), (0xFF41, 0x85), (0xFF42, 0x00), (0xFF43, 0x00),
+    (0xFF46, 0xFF), (0xFF47, 0xFC), (0xFF4A, 0x00)
+];
+
+// The speed switch takes 2050 M-cycles
+const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+// Color 0 to 3 of the compatibility palettes the CGB boot ROM falls back to, in RGB555
+const GRAYSCALE_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+// The (R) tile the DMG boot ROM draws next to the cartridge logo
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
 pub struct MemoryBus {
     is_boot_rom_mapped: bool,
-    boot_rom: [u8; BOOT_ROM_SIZE],
+    boot_rom: Vec<u8>,
     rom_bank_0: [u8; ROM_BANK_0_SIZE],
     rom_bank_n: [u8; ROM_BANK_N_SIZE],
     external_ram: [u8; EXTERNAL_RAM_SIZE],
-    working_ram: [u8; WORKING_RAM_SIZE],
+    working_ram: [u8; WORKING_RAM_BANK_SIZE * WORKING_RAM_BANKS],
+    working_ram_bank: usize,
     zero_page: [u8; ZERO_PAGE_SIZE],
     io_temp: [u8; IO_REGISTERS_SIZE],
-    oam_temp: [u8; OAM_SIZE],
+    pub header: Header,
+    pub model: Model,
+    // Palette for DMG cartridges on CGB hardware, applied when the boot ROM hands over
+    pub palette_choice: PaletteChoice,
     pub interrupt_flag: InterruptFlag,
     pub interrupt_enable: InterruptFlag,
     pub ppu: PPU,
-    pub timer: Timer
+    pub timer: Timer,
+    pub serial: Serial,
+    pub hdma: Hdma,
+    pub joypad: Joypad,
+    // Only present on SGB hardware with a cartridge that enables SGB functions
+    pub sgb: Option<Sgb>,
+    pub cgb_mode: bool,
+    pub speed_switch_armed: bool,
+    pub double_speed: bool,
+    // The PPU only advances every other CPU cycle in double speed
+    ppu_phase: bool,
+    // CPU cycles since power on, twice as many per frame in double speed
+    pub cycles: u64,
+    // Checked on every data access of the CPU, hits are reported as events
+    pub watchpoints: Vec<Watchpoint>,
+    // LY always reads 0x90, which Gameboy Doctor traces expect
+    pub stub_ly: bool,
+    pub code_data_log: Option<CodeDataLog>,
+    events: Vec<Event>
 }
 
 impl MemoryBus {
-    pub fn new(boot_rom_buffer: Vec<u8>, game_rom: Vec<u8>) -> MemoryBus {
-        let mut boot_rom = [0; BOOT_ROM_SIZE];
-            boot_rom.copy_from_slice(&boot_rom_buffer);
-        
-        if boot_rom.len() != BOOT_ROM_SIZE { panic!("Invalid boot rom, size does not match reality."); }
+    // Without a boot ROM the bus starts unmapped from it, see skip_boot
+    pub fn new(boot_rom_buffer: Option<Vec<u8>>, game_rom: Vec<u8>, model: Model) -> Result<MemoryBus, EmulatorError> {
+        let is_boot_rom_mapped = boot_rom_buffer.is_some();
+        let boot_rom = boot_rom_buffer.unwrap_or(vec![0; BOOT_ROM_SIZE]);
+        if boot_rom.len() != BOOT_ROM_SIZE && boot_rom.len() != CGB_BOOT_ROM_SIZE {
+            return Err(EmulatorError::InvalidBootRomSize { found: boot_rom.len() })
+        }
+        if game_rom.len() < GAME_ROM_MIN_SIZE {
+            return Err(EmulatorError::InvalidGameRomSize { minimum: GAME_ROM_MIN_SIZE, found: game_rom.len() })
+        }
+
         
         let mut rom_bank_0 = [0; ROM_BANK_0_SIZE];
         for i in 0 ..= ROM_BANK_0_SIZE - 1 {
@@ -76,38 +149,235 @@ impl MemoryBus {
         }
         let mut rom_bank_n = [0; ROM_BANK_N_SIZE];
         for i in 0 ..= ROM_BANK_N_SIZE - 1 {
-            if i == game_rom.len() { break }
+            if ROM_BANK_0_SIZE + i >= game_rom.len() { break }
             rom_bank_n[i] = game_rom[ROM_BANK_0_SIZE + i];
         }
 
-        let mut io_temp = [0; IO_REGISTERS_SIZE];
-        io_temp[0xff44 - IO_REGISTERS_BEGIN] = 0x90;
+        let header = Header::parse(&game_rom);
+        let sgb = if model.is_sgb() && header.supports_sgb() { Some(Sgb::new()) } else { None };
 
-        
         let interrupt_flag = InterruptFlag::new();
         let interrupt_enable = InterruptFlag::new();
 
         let timer = Timer::new();
 
-        MemoryBus {
-            is_boot_rom_mapped: true,
+        let mut ppu = PPU::new();
+        // The CGB boot ROM always starts in CGB mode and switches to compatibility through KEY0
+        ppu.cgb_hardware = model.is_cgb();
+        ppu.cgb_mode = model.is_cgb();
+
+        Ok(MemoryBus {
+            is_boot_rom_mapped,
             boot_rom,
             rom_bank_0,
             rom_bank_n,
             external_ram: [0; EXTERNAL_RAM_SIZE],
-            working_ram: [0; WORKING_RAM_SIZE],
+            working_ram: [0; WORKING_RAM_BANK_SIZE * WORKING_RAM_BANKS],
+            working_ram_bank: 1,
             zero_page: [0; ZERO_PAGE_SIZE],
-            io_temp,
-            oam_temp: [0; OAM_SIZE],
+            io_temp: [0; IO_REGISTERS_SIZE],
+            header,
+            model,
+            palette_choice: PaletteChoice::Automatic,
             interrupt_flag,
             interrupt_enable,
-            ppu: PPU::new(),
-            timer
+            ppu,
+            timer,
+            serial: Serial::new(),
+            hdma: Hdma::new(),
+            joypad: Joypad::new(),
+            sgb,
+            cgb_mode: model.is_cgb(),
+            speed_switch_armed: false,
+            double_speed: false,
+            ppu_phase: false,
+            cycles: 0,
+            watchpoints: Vec::new(),
+            stub_ly: false,
+            code_data_log: None,
+            events: Vec::new()
+        })
+    }
+
+    // Sets IO registers, DIV and VRAM to what the boot ROM of the given model leaves behind
+    pub fn skip_boot(&mut self, model: Model) {
+        self.is_boot_rom_mapped = false;
+
+        for (address, value) in POST_BOOT_IO_REGISTERS {
+            self.io_temp[address - IO_REGISTERS_BEGIN] = value;
+            if is_ppu_register(address) { self.ppu.write_register(address, value); }
         }
+        self.ppu.obp0 = 0xFF;
+        self.ppu.obp1 = 0xFF;
+
+        match model {
+            Model::DMG0 => { self.ppu.write_register(0xFF41, 0x81); }
+            Model::SGB | Model::SGB2 => { self.io_temp[0xFF26 - IO_REGISTERS_BEGIN] = 0xF0; }
+            _ if model.is_cgb() => {
+                self.serial.write_sc(0x7F, true);
+                self.io_temp[0xFF46 - IO_REGISTERS_BEGIN] = 0x00;
+            }
+            _ => { }
+        }
+        if model.is_cgb() { self.skip_cgb_boot(); }
+
+        self.joypad.write(if model.is_sgb() || model.is_cgb() { 0xC7 } else { 0xCF });
+        self.timer.set_div(model.post_boot_div());
+        self.timer.tac = 0xF8;
+        self.interrupt_flag.from_byte(0xE1);
+        self.interrupt_enable.from_byte(0x00);
+
+        if !model.is_cgb() { self.load_boot_logo(); }
+    }
+
+    // KEY0 and palettes as the CGB boot ROM leaves them for CGB and DMG cartridges
+    fn skip_cgb_boot(&mut self) {
+        self.set_cgb_mode(self.header.supports_cgb());
+
+        for palette in 0 .. 8 {
+            self.ppu.set_bg_palette(palette, [0x7FFF; 4]);
+        }
+        if !self.cgb_mode {
+            self.ppu.set_bg_palette(0, GRAYSCALE_PALETTE);
+            self.ppu.set_obj_palette(0, GRAYSCALE_PALETTE);
+            self.ppu.set_obj_palette(1, GRAYSCALE_PALETTE);
+            self.ppu.write_register(0xFF6C, 0x01);
+            self.apply_compat_palette();
+        }
+    }
+
+    // Loads the chosen compatibility palette into BG0, OBJ0 and OBJ1
+    fn apply_compat_palette(&mut self) {
+        let palette = match self.palette_choice {
+            PaletteChoice::Automatic => compat_palette::select(&self.joypad, &self.header),
+            PaletteChoice::Fixed(palette) => palette,
+            PaletteChoice::BootRom => return
+        };
+
+        self.ppu.set_bg_palette(0, compat_palette::colors_to_rgb555(palette.bg));
+        self.ppu.set_obj_palette(0, compat_palette::colors_to_rgb555(palette.obj0));
+        self.ppu.set_obj_palette(1, compat_palette::colors_to_rgb555(palette.obj1));
     }
 
+    fn is_cgb_boot_running(&self) -> bool {
+        self.is_boot_rom_mapped && self.model.is_cgb()
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+        self.ppu.cgb_mode = cgb_mode;
+    }
+
+    // Decompresses the header logo into VRAM and lays out its tile map like the DMG boot ROM
+    fn load_boot_logo(&mut self) {
+        let mut vram_address = 0x0010;
+        for logo_address in 0x0104 ..= 0x0133 {
+            let logo_byte = self.rom_bank_0[logo_address];
+
+            for nibble in [logo_byte >> 4, logo_byte & 0x0F] {
+                let mut row = 0;
+                for bit in (0 .. 4).rev() {
+                    let pixel = (nibble >> bit) & 0b1;
+                    row = (row << 2) | (pixel << 1) | pixel;
+                }
+
+                self.ppu.write_vram(vram_address, row);
+                self.ppu.write_vram(vram_address + 2, row);
+                vram_address += 4;
+            }
+        }
+
+        for (i, row) in REGISTERED_TILE.iter().enumerate() {
+            self.ppu.write_vram(0x0190 + i * 2, *row);
+        }
+
+        for i in 0 .. 12 {
+            self.ppu.write_vram(0x1904 + i, (i + 0x01) as u8);
+            self.ppu.write_vram(0x1924 + i, (i + 0x0D) as u8);
+        }
+        self.ppu.write_vram(0x1910, 0x19);
+    }
+
+    // Advances the timer, serial port and PPU by the given number of CPU cycles
     pub fn step(&mut self, cycles: u8) {
-        //
+        for _ in 0 .. cycles {
+            self.cycles += 1;
+
+            if self.timer.tick() {
+                self.request_timer_interrupt();
+            }
+            if self.serial.tick() {
+                self.interrupt_flag.serial = true;
+            }
+            self.tick_ppu();
+        }
+    }
+
+    // In STOP mode only the clock moves, DIV is held and the PPU stays idle
+    pub fn step_stopped(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+    }
+
+    // Called every CPU cycle, the PPU keeps its own rate in double speed
+    fn tick_ppu(&mut self) {
+        self.ppu_phase = !self.ppu_phase;
+        if self.double_speed && !self.ppu_phase { return }
+
+        let (vblank, stat) = self.ppu.tick();
+        if vblank { self.interrupt_flag.vblank = true; }
+        if stat { self.interrupt_flag.stat = true; }
+
+        // SGB VRAM transfers capture the frame after the command
+        if vblank {
+            if let Some(sgb) = &mut self.sgb {
+                if sgb.has_pending_transfer() { sgb.vram_transfer(&self.ppu.screen_tile_data()); }
+            }
+        }
+
+        if self.ppu.hblank_started {
+            self.ppu.hblank_started = false;
+            if self.hdma.hblank_active { self.hdma.hblank_pending = true; }
+        }
+    }
+
+    // Copies the next HBlank DMA block if an HBlank started, called by the CPU between
+    // instructions so a halted CPU holds the transfer back until it wakes up
+    pub fn step_hblank_dma(&mut self) {
+        if !self.hdma.hblank_pending { return }
+        self.hdma.hblank_pending = false;
+
+        self.copy_hdma_block();
+        self.hdma.advance();
+        self.stall_for_hdma_block();
+    }
+
+    fn general_purpose_dma(&mut self, blocks: u8) {
+        for _ in 0 .. blocks {
+            self.copy_hdma_block();
+            self.hdma.advance();
+            self.stall_for_hdma_block();
+        }
+    }
+
+    fn copy_hdma_block(&mut self) {
+        for offset in 0 .. 0x10 {
+            let byte = self.read_byte(self.hdma.source.wrapping_add(offset));
+            self.log_access(self.hdma.source.wrapping_add(offset), code_data_log::DMA_SOURCE);
+            self.ppu.write_vram(self.hdma.destination as usize + offset as usize, byte);
+        }
+    }
+
+    // Every block takes 8 M-cycles, which are twice as many CPU cycles in double speed
+    fn stall_for_hdma_block(&mut self) {
+        self.step(if self.double_speed { 64 } else { 32 });
+    }
+
+    // Called for 16-bit increments and decrements, which put the register on the address bus
+    pub fn trigger_oam_bug(&mut self, address: u16) {
+        let address = address as usize;
+        if self.model.has_oam_bug() && (OAM_BEGIN ..= UNUSED_END).contains(&address) {
+            self.ppu.corrupt_oam();
+        }
     }
 
     pub fn interrupted(&self) -> bool {
@@ -128,18 +398,97 @@ impl MemoryBus {
                     self.rom_bank_0[address]
                 }
             }
+            CGB_BOOT_ROM_HIGH_BEGIN ..= CGB_BOOT_ROM_HIGH_END if self.is_boot_rom_mapped && self.boot_rom.len() == CGB_BOOT_ROM_SIZE => {
+                self.boot_rom[address]
+            }
             ROM_BANK_0_BEGIN ..= ROM_BANK_0_END => self.rom_bank_0[address],
             ROM_BANK_N_BEGIN ..= ROM_BANK_N_END => self.rom_bank_n[address - ROM_BANK_N_BEGIN],
             VRAM_BEGIN ..= VRAM_END => { self.ppu.read_vram(address - VRAM_BEGIN) }
             EXTERNAL_RAM_BEGIN ..= EXTERNAL_RAM_END => self.external_ram[address - EXTERNAL_RAM_BEGIN],
-            WORKING_RAM_BEGIN ..= WORKING_RAM_END => self.working_ram[address - WORKING_RAM_BEGIN],
-            ECHO_RAM_BEGIN ..= ECHO_RAM_END => self.working_ram[address - ECHO_RAM_BEGIN],
-            OAM_BEGIN ..= OAM_END => self.oam_temp[address - OAM_BEGIN],
+            WORKING_RAM_BEGIN ..= WORKING_RAM_END => self.working_ram[self.working_ram_index(address - WORKING_RAM_BEGIN)],
+            ECHO_RAM_BEGIN ..= ECHO_RAM_END => self.working_ram[self.working_ram_index(address - ECHO_RAM_BEGIN)],
+            OAM_BEGIN ..= OAM_END => self.ppu.read_oam(address - OAM_BEGIN),
             IO_REGISTERS_BEGIN ..= IO_REGISTERS_END => self.read_io(address),
             UNUSED_BEGIN ..= UNUSED_END => { 0 }
             ZERO_PAGE_BEGIN ..= ZERO_PAGE_END => self.zero_page[address - ZERO_PAGE_BEGIN],
             INTERRUPT_ENABLE_REGISTER => self.interrupt_enable.to_byte(),
-            _ => panic!("Memory address 0x{:x} invalid or not supported yet.", address)
+            // Every u16 address is covered above, this only keeps the usize match exhaustive
+            _ => 0xFF
+        }
+    }
+
+    // Data reads and writes of the CPU, unlike read_byte and write_byte these trigger watchpoints
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        let byte = self.read_byte(address);
+        self.log_access(address, code_data_log::DATA);
+        self.check_watchpoints(address, byte, false);
+        byte
+    }
+
+    pub fn cpu_write(&mut self, address: u16, byte: u8) {
+        self.check_watchpoints(address, byte, true);
+        self.write_byte(address, byte);
+    }
+
+    fn check_watchpoints(&mut self, address: u16, value: u8, write: bool) {
+        if self.watchpoints.is_empty() { return }
+
+        let hits: Vec<u32> = self.watchpoints.iter()
+            .filter(|watchpoint| watchpoint.matches(address, write))
+            .map(|watchpoint| watchpoint.id)
+            .collect();
+        for id in hits {
+            self.events.push(Event::Watchpoint { id, address, value, write });
+        }
+    }
+
+    // Records how the CPU or a DMA used a byte when a code/data log is kept
+    pub fn log_access(&mut self, address: u16, flags: u8) {
+        if self.code_data_log.is_none() { return }
+
+        let address = address as usize;
+        let (region, offset) = match address {
+            BOOT_ROM_BEGIN ..= BOOT_ROM_END if self.is_boot_rom_mapped => return,
+            CGB_BOOT_ROM_HIGH_BEGIN ..= CGB_BOOT_ROM_HIGH_END if self.is_boot_rom_mapped && self.boot_rom.len() == CGB_BOOT_ROM_SIZE => return,
+            ROM_BANK_0_BEGIN ..= ROM_BANK_0_END => (Region::Rom, address),
+            ROM_BANK_N_BEGIN ..= ROM_BANK_N_END => (Region::Rom, self.bank_at(address as u16) as usize * ROM_BANK_N_SIZE + address - ROM_BANK_N_BEGIN),
+            EXTERNAL_RAM_BEGIN ..= EXTERNAL_RAM_END => (Region::ExternalRam, address - EXTERNAL_RAM_BEGIN),
+            WORKING_RAM_BEGIN ..= WORKING_RAM_END => (Region::WorkingRam, self.working_ram_index(address - WORKING_RAM_BEGIN)),
+            ECHO_RAM_BEGIN ..= ECHO_RAM_END => (Region::WorkingRam, self.working_ram_index(address - ECHO_RAM_BEGIN)),
+            ZERO_PAGE_BEGIN ..= ZERO_PAGE_END => (Region::Hram, address - ZERO_PAGE_BEGIN),
+            _ => return
+        };
+        if let Some(log) = &mut self.code_data_log { log.mark(region, offset, flags); }
+    }
+
+    // Bank mapped at the given address, 0 for regions without banks
+    pub fn bank_at(&self, address: u16) -> u16 {
+        match address as usize {
+            // There is no MBC yet, so the switchable region always holds bank 1
+            ROM_BANK_N_BEGIN ..= ROM_BANK_N_END => 1,
+            VRAM_BEGIN ..= VRAM_END => self.ppu.vram_bank() as u16,
+            0xD000 ..= WORKING_RAM_END => self.working_ram_bank as u16,
+            _ => 0
+        }
+    }
+
+    // Banks that can be mapped at the given address on this hardware
+    pub fn banks_at(&self, address: u16) -> std::ops::RangeInclusive<u16> {
+        match address as usize {
+            ROM_BANK_N_BEGIN ..= ROM_BANK_N_END => 1 ..= 1,
+            VRAM_BEGIN ..= VRAM_END if self.model.is_cgb() => 0 ..= 1,
+            0xD000 ..= WORKING_RAM_END if self.model.is_cgb() => 1 ..= 7,
+            0xD000 ..= WORKING_RAM_END => 1 ..= 1,
+            _ => 0 ..= 0
+        }
+    }
+
+    // Reads from the given bank whatever is mapped right now, for memory viewers
+    pub fn read_banked(&self, bank: u16, address: u16) -> u8 {
+        match address as usize {
+            VRAM_BEGIN ..= VRAM_END => self.ppu.read_vram_bank(bank as usize & 1, address as usize - VRAM_BEGIN),
+            0xD000 ..= WORKING_RAM_END => self.working_ram[(bank as usize & 7).max(1) * WORKING_RAM_BANK_SIZE + address as usize - 0xD000],
+            _ => self.read_byte(address)
         }
     }
 
@@ -160,13 +509,15 @@ impl MemoryBus {
                 self.external_ram[address - EXTERNAL_RAM_BEGIN] = byte;
             }
             WORKING_RAM_BEGIN ..= WORKING_RAM_END => {
-                self.working_ram[address - WORKING_RAM_BEGIN] = byte;
+                let index = self.working_ram_index(address - WORKING_RAM_BEGIN);
+                self.working_ram[index] = byte;
             }
             ECHO_RAM_BEGIN ..= ECHO_RAM_END => {
-                self.working_ram[address - ECHO_RAM_BEGIN] = byte;
+                let index = self.working_ram_index(address - ECHO_RAM_BEGIN);
+                self.working_ram[index] = byte;
             }
             OAM_BEGIN ..= OAM_END => {
-                self.oam_temp[address - OAM_BEGIN] = byte;
+                self.ppu.write_oam(address - OAM_BEGIN, byte);
             }
             IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.write_io(address, byte),
             UNUSED_BEGIN ..= UNUSED_END => { }
@@ -174,23 +525,51 @@ impl MemoryBus {
                 self.zero_page[address - ZERO_PAGE_BEGIN] = byte;
             }
             INTERRUPT_ENABLE_REGISTER => self.interrupt_enable.from_byte(byte),
-            _ => panic!("Memory address 0x{:x} invalid or not supported yet.", address)
+            _ => { }
+        }
+    }
+
+    // Offset into the 8 banks of working RAM for an offset from 0xC000
+    fn working_ram_index(&self, offset: usize) -> usize {
+        if offset < WORKING_RAM_BANK_SIZE {
+            offset
+        } else {
+            self.working_ram_bank * WORKING_RAM_BANK_SIZE + offset - WORKING_RAM_BANK_SIZE
         }
     }
 
     fn read_io(&self, address: usize) -> u8 {
         match address {
-            //0xFF00 => { /* joypad */ 0 }
-            //0xFF01 => { /* Serial Transfer */ 0 }
-            //0xFF02 => { /* Serial Transfer Control */ 0 }
+            0xFF00 => {
+                match &self.sgb {
+                    Some(sgb) => sgb.read_p1(self.joypad.read()),
+                    None => self.joypad.read()
+                }
+            }
+            0xFF01 => self.serial.sb,
+            0xFF02 => self.serial.read_sc(self.cgb_mode),
             0xFF04 => self.timer.read_div(),
             0xFF05 => self.timer.tima,
             0xFF06 => self.timer.tma,
             0xFF07 => self.timer.tac,
             0xFF0F => self.interrupt_flag.to_byte(),
-            //0xFF40 => { /* LCD Control */ 0 }
-            //0xFF42 => { /* Scroll Y Position */ 0 }
-            //0xFF44 => { self.io_temp[address - IO_REGISTERS_BEGIN] }
+            0xFF4F | 0xFF68 ..= 0xFF6B if !self.cgb_mode => 0xFF,
+            0xFF6C if !self.model.is_cgb() => 0xFF,
+            0xFF44 if self.stub_ly => 0x90,
+            _ if is_ppu_register(address) => self.ppu.read_register(address),
+            0xFF4D => {
+                if self.cgb_mode {
+                    0x7E | ((self.double_speed as u8) << 7) | (self.speed_switch_armed as u8)
+                } else {
+                    0xFF
+                }
+            }
+            0xFF51 ..= 0xFF55 => {
+                if self.cgb_mode { self.hdma.read(address) } else { 0xFF }
+            }
+            0xFF70 => {
+                if self.cgb_mode { 0xF8 | self.working_ram_bank as u8 } else { 0xFF }
+            }
             _ => {
                 self.io_temp[address - IO_REGISTERS_BEGIN]
             }
@@ -199,9 +578,12 @@ impl MemoryBus {
 
     fn write_io(&mut self, address: usize, byte: u8) {
         match address {
-            0xFF00 => { /* joypad */ print!("{}", byte as char); }
-            0xFF01 => { /* Serial Transfer */ print!("{}", byte as char); }
-            0xFF02 => { /* Serial Transfer Control */ print!("{}", byte as char); }
+            0xFF00 => {
+                self.joypad.write(byte);
+                if let Some(sgb) = &mut self.sgb { sgb.write_p1(byte); }
+            }
+            0xFF01 => { self.serial.sb = byte; }
+            0xFF02 => self.serial.write_sc(byte, self.cgb_mode),
             0xFF04 => self.timer.write_div(byte),
             0xFF05 => self.timer.write_tima(byte),
             0xFF06 => self.timer.write_tma(byte),
@@ -212,11 +594,36 @@ impl MemoryBus {
             //0xFF24 => { /* Sound  Volume */ }
             //0xFF25 => { /* Sound output terminal selection */ }
             //0xFF26 => { /* Sound on/off */ }
-            //0xFF40 => { /* LCD Control */ }
-            //0xFF42 => { /* Viewport Y Offset */ }
-            //0xFF44 => {  }
-            //0xFF47 => { /* Background Colors Setting */ }
-            0xFF50 => { self.is_boot_rom_mapped = false; }
+            0xFF46 => {
+                self.io_temp[address - IO_REGISTERS_BEGIN] = byte;
+                self.oam_dma(byte);
+            }
+            // KEY0 can only be written by the boot ROM, bit 2 selects DMG compatibility mode
+            0xFF4C => {
+                if self.is_cgb_boot_running() { self.set_cgb_mode((byte & 0b100) == 0); }
+            }
+            // Palette RAM stays writable for the boot ROM to set up compatibility palettes
+            0xFF68 ..= 0xFF6B if !(self.cgb_mode || self.is_cgb_boot_running()) => { }
+            0xFF4F if !self.cgb_mode => { }
+            0xFF6C if !self.is_cgb_boot_running() => { }
+            _ if is_ppu_register(address) => self.ppu.write_register(address, byte),
+            0xFF4D => {
+                if self.cgb_mode { self.speed_switch_armed = (byte & 0b1) != 0; }
+            }
+            0xFF50 => {
+                if self.is_cgb_boot_running() && !self.cgb_mode { self.apply_compat_palette(); }
+                self.is_boot_rom_mapped = false;
+            }
+            0xFF51 ..= 0xFF55 => {
+                if self.cgb_mode {
+                    let in_hblank = !self.ppu.is_lcd_enabled() || self.ppu.mode() == PPUMode::HBlank;
+                    if let Some(blocks) = self.hdma.write(address, byte, in_hblank) { self.general_purpose_dma(blocks); }
+                }
+            }
+            0xFF70 => {
+                // Bank 0 selects bank 1 as well
+                if self.cgb_mode { self.working_ram_bank = ((byte & 0b111) as usize).max(1); }
+            }
             0xFF7F => { /* Nothing */ }
             _ => {
                 self.io_temp[address - IO_REGISTERS_BEGIN] = byte;
@@ -224,7 +631,103 @@ impl MemoryBus {
         }
     } 
 
+    // Copies 160 bytes from source * 0x100 to OAM at once
+    fn oam_dma(&mut self, source: u8) {
+        let source = (source as u16) << 8;
+        for offset in 0 .. OAM_SIZE as u16 {
+            let byte = self.read_byte(source + offset);
+            self.log_access(source + offset, code_data_log::DMA_SOURCE);
+            self.ppu.write_oam(offset as usize, byte);
+        }
+    }
+
     pub fn request_timer_interrupt(&mut self) {
         self.interrupt_flag.timer = true;
     }
-}
\ No newline at end of file
+
+    pub fn push_event(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn press_button(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.interrupt_flag.joypad = true;
+        }
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    // Everything outside of the components, which save their own sections. The boot ROM is
+    // only saved as whether it is mapped and has to be supplied again for a state taken during boot
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.is_boot_rom_mapped);
+        state.bytes(&self.working_ram);
+        state.u8(self.working_ram_bank as u8);
+        state.bytes(&self.zero_page);
+        state.bytes(&self.io_temp);
+        state.u8(self.interrupt_flag.to_byte());
+        state.u8(self.interrupt_enable.to_byte());
+        state.bool(self.cgb_mode);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.double_speed);
+        state.bool(self.ppu_phase);
+        state.u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.is_boot_rom_mapped = state.bool();
+        state.bytes_into(&mut self.working_ram);
+        self.working_ram_bank = (state.u8() as usize % WORKING_RAM_BANKS).max(1);
+        state.bytes_into(&mut self.zero_page);
+        state.bytes_into(&mut self.io_temp);
+        self.interrupt_flag.from_byte(state.u8());
+        self.interrupt_enable.from_byte(state.u8());
+        self.cgb_mode = state.bool();
+        self.speed_switch_armed = state.bool();
+        self.double_speed = state.bool();
+        self.ppu_phase = state.bool();
+        self.cycles = state.u64();
+        self.events.clear();
+    }
+
+    // There is no MBC yet, so the cartridge is just its RAM
+    pub fn save_cartridge_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.external_ram);
+    }
+
+    pub fn load_cartridge_state(&mut self, state: &mut StateReader) {
+        state.bytes_into(&mut self.external_ram);
+    }
+
+    // Presses and releases buttons to match a mask in the order of Button::ALL
+    pub fn set_held_buttons(&mut self, mask: u8) {
+        let changed = mask ^ self.joypad.held();
+        for button in Button::ALL {
+            if (changed & button.held_bit()) == 0 { continue }
+            if (mask & button.held_bit()) != 0 { self.press_button(button); } else { self.release_button(button); }
+        }
+    }
+
+    // Called by STOP when KEY1 has been armed on CGB hardware. The CPU and DIV are paused
+    // for the duration of the switch while the PPU keeps going
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+
+        for _ in 0 .. SPEED_SWITCH_CYCLES {
+            self.cycles += 1;
+            self.tick_ppu();
+        }
+    }
+}
+
+// LCD registers handled by the PPU, DMA at 0xFF46 stays with the bus
+fn is_ppu_register(address: usize) -> bool {
+    matches!(address, 0xFF40 ..= 0xFF45 | 0xFF47 ..= 0xFF4B | 0xFF4F | 0xFF68 ..= 0xFF6C)
+}
@@ -0,0 +1,57 @@
+// The Game Boy revision being emulated
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Model {
+    DMG0,
+    DMG,
+    MGB,
+    SGB,
+    SGB2,
+    CGB0,
+    CGB,
+    AGB
+}
+
+// In the order of their numbers in save states and movies
+pub const ALL: [Model; 8] = [Model::DMG0, Model::DMG, Model::MGB, Model::SGB, Model::SGB2, Model::CGB0, Model::CGB, Model::AGB];
+
+pub const NAMES: &str = "dmg0, dmg, mgb, sgb, sgb2, cgb0, cgb, agb";
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg0" => Some(Model::DMG0),
+            "dmg" => Some(Model::DMG),
+            "mgb" => Some(Model::MGB),
+            "sgb" => Some(Model::SGB),
+            "sgb2" => Some(Model::SGB2),
+            "cgb0" => Some(Model::CGB0),
+            "cgb" => Some(Model::CGB),
+            "agb" => Some(Model::AGB),
+            _ => None
+        }
+    }
+
+    // The GBA runs Game Boy games on its CGB hardware
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB0 | Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+
+    // 16-bit increments and decrements of a register pointing at OAM corrupt it during mode 2
+    pub fn has_oam_bug(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    // Internal DIV counter right after the boot ROM hands over at 0x0100
+    pub fn post_boot_div(&self) -> u16 {
+        match self {
+            Model::DMG0 => 0x182C,
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB | Model::SGB2 => 0xD85C,
+            Model::CGB0 | Model::CGB | Model::AGB => 0x1EA0
+        }
+    }
+}
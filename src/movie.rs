@@ -0,0 +1,302 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::memory_bus::MemoryBus;
+use crate::model::{self, Model};
+use crate::save_state::{self, StateWriter, StateReader};
+
+// Movies are cut into frames of a fixed number of cycles, so the frame is always known from
+// the cycle count, even after loading a state. In double speed a frame is half a video frame
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+// A movie file is MAGIC, the version as u16, the model, flags, the CRC32 of the ROM and of
+// the boot ROM (0 with SKIP_BOOT), the rerecord count and the number of frames, then the save
+// state it starts from when FROM_STATE is set and one byte of held buttons per frame, in the
+// order of Button::ALL
+const MAGIC: &[u8; 8] = b"GBMOVIE\0";
+const VERSION: u16 = 1;
+
+const SKIP_BOOT: u8 = 0x01;
+const FROM_STATE: u8 = 0x02;
+
+#[derive(PartialEq)]
+enum MovieMode {
+    Recording,
+    Playing,
+    // Played to the end, input is back with the keyboard
+    Finished
+}
+
+// Joypad input per frame from power on or a save state, replayed to get the same run again
+pub struct Movie {
+    path: String,
+    mode: MovieMode,
+    model: Model,
+    skip_boot: bool,
+    rom_crc: u32,
+    // None for skipped boots
+    boot_crc: Option<u32>,
+    // Times a state was loaded while recording, each starts a new branch of the movie
+    rerecords: u32,
+    start_state: Option<Vec<u8>>,
+    start_cycles: u64,
+    inputs: Vec<u8>,
+    // A state was loaded while recording, input goes on from its frame
+    branched: bool
+}
+
+impl Movie {
+    // Records from power on, or from a save state to start with. Without a boot ROM the boot is skipped
+    pub fn record(path: &str, rom: &[u8], boot_rom: Option<&[u8]>, start_state: Option<Vec<u8>>) -> Movie {
+        Movie {
+            path: path.to_string(),
+            mode: MovieMode::Recording,
+            // Taken from the machine once it starts
+            model: Model::DMG,
+            skip_boot: boot_rom.is_none(),
+            rom_crc: crc32(rom),
+            boot_crc: boot_rom.map(crc32),
+            rerecords: 0,
+            start_state,
+            start_cycles: 0,
+            inputs: Vec::new(),
+            branched: false
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Movie, EmulatorError> {
+        let data = std::fs::read(path).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?;
+        let invalid = |reason: &str| EmulatorError::InvalidMovie { path: path.to_string(), reason: reason.to_string() };
+        if data.len() < MAGIC.len() || &data[.. MAGIC.len()] != MAGIC { return Err(invalid("not a movie")) }
+
+        let mut movie = StateReader::new(&data[MAGIC.len() ..]);
+        let version = movie.u16();
+        if version > VERSION { return Err(invalid("the movie is from a newer version")) }
+        let model = *model::ALL.get(movie.u8() as usize).ok_or_else(|| invalid("unknown model"))?;
+        let flags = movie.u8();
+        let rom_crc = movie.u32();
+        let boot_crc = movie.u32();
+        let skip_boot = (flags & SKIP_BOOT) != 0;
+        let rerecords = movie.u32();
+        let frames = movie.u32() as usize;
+        let start_state = if (flags & FROM_STATE) != 0 { Some(movie.bytes()) } else { None };
+        let inputs = movie.bytes();
+        if inputs.len() != frames { return Err(invalid("the movie is truncated")) }
+
+        Ok(Movie {
+            path: path.to_string(),
+            mode: MovieMode::Playing,
+            model,
+            skip_boot,
+            rom_crc,
+            boot_crc: Some(boot_crc).filter(|_| !skip_boot),
+            rerecords,
+            start_state,
+            start_cycles: 0,
+            inputs,
+            branched: false
+        })
+    }
+
+    // The model a played movie was recorded on
+    pub fn model(&self) -> Option<Model> {
+        if self.mode == MovieMode::Playing { Some(self.model) } else { None }
+    }
+
+    // Power on movies also need the same boot, a boot ROM taking longer would shift all input
+    pub fn check(&self, rom: &[u8], boot_rom: Option<&[u8]>) -> Result<(), EmulatorError> {
+        let invalid = |reason: String| Err(EmulatorError::InvalidMovie { path: self.path.clone(), reason });
+        if crc32(rom) != self.rom_crc { return invalid(format!("it was recorded with another ROM, CRC32 {:08X}", self.rom_crc)) }
+        if self.start_state.is_some() { return Ok(()) }
+
+        if boot_rom.is_none() != self.skip_boot {
+            return invalid(format!("it was recorded {}", if self.skip_boot { "with --skip-boot" } else { "without --skip-boot" }))
+        }
+        if let (Some(boot_rom), Some(boot_crc)) = (boot_rom, self.boot_crc) {
+            if crc32(boot_rom) != boot_crc { return invalid(format!("it was recorded with another boot ROM, CRC32 {:08X}", boot_crc)) }
+        }
+        Ok(())
+    }
+
+    // Puts the machine in the start state, if there is one, and starts counting frames
+    pub fn start(&mut self, cpu: &mut CPU) -> Result<(), EmulatorError> {
+        if self.mode == MovieMode::Playing && cpu.bus.model != self.model {
+            return Err(EmulatorError::InvalidMovie { path: self.path.clone(), reason: format!("it was recorded on {:?}", self.model) })
+        }
+        if let Some(state) = &self.start_state {
+            save_state::load(cpu, state).map_err(|reason| EmulatorError::InvalidMovie { path: self.path.clone(), reason })?;
+        }
+        self.model = cpu.bus.model;
+        self.start_cycles = cpu.bus.cycles;
+
+        match self.mode {
+            MovieMode::Recording => println!("Recording {}", self.path),
+            _ => println!("Playing {}, {} frames and {} rerecords", self.path, self.inputs.len(), self.rerecords)
+        }
+        Ok(())
+    }
+
+    // Called for every state loaded or rewound to, which starts a new branch of a recording
+    pub fn rerecord(&mut self) {
+        if self.mode != MovieMode::Recording { return }
+        self.rerecords += 1;
+        self.branched = true;
+    }
+
+    // False once playback has finished and the keyboard drives the joypad again
+    pub fn is_active(&self) -> bool {
+        self.mode != MovieMode::Finished
+    }
+
+    // Called after every step with the buttons held on the keyboard, sets the joypad to
+    // the input of the current frame
+    pub fn update(&mut self, bus: &mut MemoryBus, held: u8) {
+        let Some(cycles) = bus.cycles.checked_sub(self.start_cycles) else { return };
+        let frame = (cycles / CYCLES_PER_FRAME) as usize;
+
+        match self.mode {
+            MovieMode::Recording => {
+                // Input recorded after the loaded state belongs to the old branch
+                if self.branched {
+                    self.inputs.truncate(frame + 1);
+                    self.branched = false;
+                }
+                while self.inputs.len() <= frame { self.inputs.push(held); }
+                bus.set_held_buttons(self.inputs[frame]);
+            }
+            MovieMode::Playing => match self.inputs.get(frame) {
+                Some(&input) => bus.set_held_buttons(input),
+                None => {
+                    println!("Movie finished after {} frames", self.inputs.len());
+                    self.mode = MovieMode::Finished;
+                    bus.set_held_buttons(held);
+                }
+            },
+            MovieMode::Finished => { }
+        }
+    }
+
+    fn write(&self) -> Result<(), EmulatorError> {
+        let mut movie = StateWriter::new();
+        movie.u16(VERSION);
+        movie.u8(self.model as u8);
+        let start_flag = if self.start_state.is_some() { FROM_STATE } else { 0 };
+        movie.u8(if self.skip_boot { SKIP_BOOT } else { 0 } | start_flag);
+        movie.u32(self.rom_crc);
+        movie.u32(self.boot_crc.unwrap_or(0));
+        movie.u32(self.rerecords);
+        movie.u32(self.inputs.len() as u32);
+        if let Some(state) = &self.start_state { movie.bytes(state); }
+        movie.bytes(&self.inputs);
+
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&movie.into_bytes());
+        std::fs::write(&self.path, data).map_err(|error| EmulatorError::Io { path: self.path.clone(), error })
+    }
+}
+
+// Written on exit like the profiler, recording ends wherever the emulator stops
+impl Drop for Movie {
+    fn drop(&mut self) {
+        if self.mode != MovieMode::Recording { return }
+        match self.write() {
+            Ok(()) => println!("Recorded {} frames and {} rerecords to {}", self.inputs.len(), self.rerecords, self.path),
+            Err(error) => eprintln!("{}", error)
+        }
+    }
+}
+
+// The CRC32 of zip and PNG, which other tools show for ROMs as well
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0 .. 8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("gb_emulator_{}_{}.gbm", name, std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn crc32_known_vectors() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn recorded_movies_load_back() {
+        let path = temp_path("record");
+        let rom = vec![0; 0x8000];
+        let boot_rom = vec![0x31; 0x100];
+        let mut cpu = CPU::new(MemoryBus::new(None, rom.clone(), Model::DMG).unwrap());
+
+        let mut movie = Movie::record(&path, &rom, Some(&boot_rom), None);
+        movie.start(&mut cpu).unwrap();
+        for frame in 0 .. 3 {
+            cpu.bus.cycles = frame * CYCLES_PER_FRAME;
+            movie.update(&mut cpu.bus, frame as u8);
+        }
+        movie.rerecord();
+        drop(movie);
+
+        let movie = Movie::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(movie.inputs, [0, 1, 2]);
+        assert_eq!(movie.rerecords, 1);
+        assert_eq!(movie.model(), Some(Model::DMG));
+        assert_eq!(movie.boot_crc, Some(crc32(&boot_rom)));
+        assert!(movie.check(&rom, Some(&boot_rom)).is_ok());
+        assert!(movie.check(&rom, Some(&[0; 0x100])).is_err());
+        assert!(movie.check(&rom, None).is_err());
+        assert!(movie.check(&[1; 0x8000], Some(&boot_rom)).is_err());
+    }
+
+    #[test]
+    fn playback_sets_the_input_of_each_frame() {
+        let path = temp_path("play");
+        let rom = vec![0; 0x8000];
+        let mut cpu = CPU::new(MemoryBus::new(None, rom.clone(), Model::DMG).unwrap());
+        let mut movie = Movie::record(&path, &rom, None, None);
+        movie.start(&mut cpu).unwrap();
+        movie.inputs = vec![0x01, 0x80];
+        drop(movie);
+
+        let mut movie = Movie::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        movie.start(&mut cpu).unwrap();
+        cpu.bus.cycles = CYCLES_PER_FRAME;
+        movie.update(&mut cpu.bus, 0);
+        assert_eq!(cpu.bus.joypad.held(), 0x80);
+        assert!(movie.is_active());
+
+        cpu.bus.cycles = 2 * CYCLES_PER_FRAME;
+        movie.update(&mut cpu.bus, 0x04);
+        assert!(!movie.is_active());
+        assert_eq!(cpu.bus.joypad.held(), 0x04);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let path = temp_path("invalid");
+        let mut movie = Movie::record(&path, &[], None, None);
+        movie.inputs = vec![0; 4];
+        drop(movie);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[.. data.len() - 1]).unwrap();
+        let truncated = Movie::load(&path);
+        std::fs::write(&path, b"GBSTATE\0").unwrap();
+        let other = Movie::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(truncated, Err(EmulatorError::InvalidMovie { reason, .. }) if reason == "the movie is truncated"));
+        assert!(matches!(other, Err(EmulatorError::InvalidMovie { reason, .. }) if reason == "not a movie"));
+    }
+}
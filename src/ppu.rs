@@ -1,6 +1,17 @@
-extern crate sdl2;
+use crate::memory_bus::{VRAM_SIZE, OAM_SIZE};
+use crate::save_state::{StateWriter, StateReader};
 
-use crate::memory_bus::VRAM_SIZE;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// Shades of the DMG screen as 0x00RRGGBB
+pub const DMG_COLORS: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
 
 #[derive(Copy,Clone)]
 enum TilePixelValue {
@@ -10,55 +21,351 @@ enum TilePixelValue {
     Three,
 }
 
+impl std::convert::From<TilePixelValue> for u8 {
+    fn from(value: TilePixelValue) -> u8 {
+        match value {
+            TilePixelValue::Zero => 0,
+            TilePixelValue::One => 1,
+            TilePixelValue::Two => 2,
+            TilePixelValue::Three => 3
+        }
+    }
+}
+
 type Tile = [[TilePixelValue; 8]; 8];
 fn empty_tile() -> Tile {
     [[TilePixelValue::Zero; 8]; 8]
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PPUMode {
+    HBlank,
+    VBlank,
+    OAMScan,
+    Drawing
+}
+
+impl PPUMode {
+    fn to_bits(self) -> u8 {
+        match self {
+            PPUMode::HBlank => 0,
+            PPUMode::VBlank => 1,
+            PPUMode::OAMScan => 2,
+            PPUMode::Drawing => 3
+        }
+    }
+}
+
+// Converts a little endian RGB555 color from palette RAM to 0x00RRGGBB
+pub fn rgb555_to_rgb888(color: u16) -> u32 {
+    let expand = |component: u16| {
+        let component = (component & 0x1F) as u32;
+        (component << 3) | (component >> 2)
+    };
+
+    (expand(color) << 16) | (expand(color >> 5) << 8) | expand(color >> 10)
+}
+
 pub struct PPU {
-    vram: [u8; VRAM_SIZE],
-    tile_set: [Tile; 384]
+    // Bank 1 only exists on CGB hardware
+    vram: [[u8; VRAM_SIZE]; 2],
+    tile_set: [[Tile; 384]; 2],
+    oam: [u8; OAM_SIZE],
+    vram_bank: usize,
+
+    pub lcdc: u8,
+    stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+
+    mode: PPUMode,
+    dot: u16,
+    window_line: u8,
+    stat_line: bool,
+
+    // CGB features are in use, set by the bus from KEY0 or the cartridge header
+    pub cgb_mode: bool,
+    // Colors go through palette RAM, also true for DMG games on CGB hardware
+    pub cgb_hardware: bool,
+    bg_palette_ram: [u8; 64],
+    bcps: u8,
+    obj_palette_ram: [u8; 64],
+    ocps: u8,
+    // Object priority by X coordinate like the DMG instead of by OAM position
+    dmg_object_priority: bool,
+
+    framebuffer: Vec<u32>,
+    // DMG shade of every pixel after BGP/OBP, for colorization on top of the DMG picture
+    shades: Vec<u8>,
+    pub frame_ready: bool,
+    pub hblank_started: bool
 }
 
 impl PPU {
     pub fn new() -> PPU {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsys = sdl_context.video().unwrap();
+        PPU {
+            vram: [[0; VRAM_SIZE]; 2],
+            tile_set: [[empty_tile(); 384]; 2],
+            oam: [0; OAM_SIZE],
+            vram_bank: 0,
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: PPUMode::HBlank,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            cgb_mode: false,
+            cgb_hardware: false,
+            bg_palette_ram: [0xFF; 64],
+            bcps: 0,
+            obj_palette_ram: [0xFF; 64],
+            ocps: 0,
+            dmg_object_priority: false,
+            framebuffer: vec![DMG_COLORS[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            hblank_started: false
+        }
+    }
 
-        let window = video_subsys.window("Gameboy DMG-01", 480, 432)
-            .position_centered()
-            .resizable()
-            .build()
-            .unwrap();
+    // Advances one dot, returns whether the VBlank and STAT interrupts should be requested
+    pub fn tick(&mut self) -> (bool, bool) {
+        if !self.is_lcd_enabled() { return (false, false) }
 
-        let canvas = window.into_canvas().build().unwrap();
+        let mut vblank = false;
+        self.dot += 1;
 
-        PPU {
-            vram: [0; VRAM_SIZE],
-            tile_set: [empty_tile(); 384]
+        match self.mode {
+            PPUMode::OAMScan => {
+                if self.dot == OAM_SCAN_DOTS { self.mode = PPUMode::Drawing; }
+            }
+            PPUMode::Drawing => {
+                if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                    self.render_scanline();
+                    self.mode = PPUMode::HBlank;
+                    self.hblank_started = true;
+                }
+            }
+            PPUMode::HBlank | PPUMode::VBlank => {
+                if self.dot == DOTS_PER_LINE {
+                    self.dot = 0;
+                    self.ly += 1;
+
+                    if self.ly == SCREEN_HEIGHT as u8 {
+                        self.mode = PPUMode::VBlank;
+                        self.window_line = 0;
+                        self.frame_ready = true;
+                        vblank = true;
+                    } else if self.ly == LINES_PER_FRAME {
+                        self.ly = 0;
+                        self.mode = PPUMode::OAMScan;
+                    } else if self.ly < SCREEN_HEIGHT as u8 {
+                        self.mode = PPUMode::OAMScan;
+                    }
+                }
+            }
         }
+
+        (vblank, self.update_stat_line())
+    }
+
+    // The STAT interrupt fires on the rising edge of all enabled sources or'ed together
+    fn update_stat_line(&mut self) -> bool {
+        let line = ((self.stat & 0b01000000) != 0 && self.ly == self.lyc) ||
+            ((self.stat & 0b00100000) != 0 && self.mode == PPUMode::OAMScan) ||
+            ((self.stat & 0b00010000) != 0 && self.mode == PPUMode::VBlank) ||
+            ((self.stat & 0b00001000) != 0 && self.mode == PPUMode::HBlank);
+
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+
+        rising_edge
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
     }
 
-    pub fn tick(&self) {
+    pub fn is_lcd_enabled(&self) -> bool {
+        (self.lcdc & 0b10000000) != 0
+    }
+
+    pub fn mode(&self) -> PPUMode {
+        self.mode
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    pub fn read_register(&self, address: usize) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                0x80 | (self.stat & 0b01111000) | (((self.ly == self.lyc) as u8) << 2) | self.mode.to_bits()
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F => 0xFE | self.vram_bank as u8,
+            0xFF68 => 0x40 | self.bcps,
+            0xFF69 => self.bg_palette_ram[(self.bcps & 0x3F) as usize],
+            0xFF6A => 0x40 | self.ocps,
+            0xFF6B => self.obj_palette_ram[(self.ocps & 0x3F) as usize],
+            0xFF6C => 0xFE | self.dmg_object_priority as u8,
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: usize, byte: u8) {
+        match address {
+            0xFF40 => {
+                let was_enabled = self.is_lcd_enabled();
+                self.lcdc = byte;
+
+                if was_enabled && !self.is_lcd_enabled() {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.mode = PPUMode::HBlank;
+                } else if !was_enabled && self.is_lcd_enabled() {
+                    self.mode = PPUMode::OAMScan;
+                }
+            }
+            0xFF41 => { self.stat = byte & 0b01111000; }
+            0xFF42 => { self.scy = byte; }
+            0xFF43 => { self.scx = byte; }
+            0xFF44 => { /* Read only */ }
+            0xFF45 => { self.lyc = byte; }
+            0xFF47 => { self.bgp = byte; }
+            0xFF48 => { self.obp0 = byte; }
+            0xFF49 => { self.obp1 = byte; }
+            0xFF4A => { self.wy = byte; }
+            0xFF4B => { self.wx = byte; }
+            0xFF4F => { self.vram_bank = (byte & 0b1) as usize; }
+            0xFF68 => { self.bcps = byte & 0b10111111; }
+            0xFF69 => {
+                self.bg_palette_ram[(self.bcps & 0x3F) as usize] = byte;
+                self.bcps = increment_palette_index(self.bcps);
+            }
+            0xFF6A => { self.ocps = byte & 0b10111111; }
+            0xFF6B => {
+                self.obj_palette_ram[(self.ocps & 0x3F) as usize] = byte;
+                self.ocps = increment_palette_index(self.ocps);
+            }
+            0xFF6C => { self.dmg_object_priority = (byte & 0b1) != 0; }
+            _ => { }
+        }
+    }
+
+    pub fn set_bg_palette(&mut self, palette: usize, colors: [u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            self.bg_palette_ram[palette * 8 + i * 2] = (color & 0xFF) as u8;
+            self.bg_palette_ram[palette * 8 + i * 2 + 1] = (color >> 8) as u8;
+        }
+    }
+
+    pub fn set_obj_palette(&mut self, palette: usize, colors: [u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            self.obj_palette_ram[palette * 8 + i * 2] = (color & 0xFF) as u8;
+            self.obj_palette_ram[palette * 8 + i * 2 + 1] = (color >> 8) as u8;
+        }
+    }
+
+    // Raw RGB555 value of a color in CGB palette RAM
+    pub fn bg_palette_entry(&self, palette: usize, color: u8) -> u16 {
+        let index = palette * 8 + color as usize * 2;
+        (self.bg_palette_ram[index] as u16) | ((self.bg_palette_ram[index + 1] as u16) << 8)
+    }
+
+    pub fn obj_palette_entry(&self, palette: usize, color: u8) -> u16 {
+        let index = palette * 8 + color as usize * 2;
+        (self.obj_palette_ram[index] as u16) | ((self.obj_palette_ram[index + 1] as u16) << 8)
+    }
+
+    fn bg_color(&self, palette: usize, color: u8) -> u32 {
+        rgb555_to_rgb888(self.bg_palette_entry(palette, color))
+    }
+
+    fn obj_color(&self, palette: usize, color: u8) -> u32 {
+        rgb555_to_rgb888(self.obj_palette_entry(palette, color))
+    }
+
+    // Screen color of a background or window pixel with the given color index and CGB attributes
+    pub fn background_color(&self, color: u8, attributes: u8) -> u32 {
+        let shade = (self.bgp >> (color * 2)) & 0b11;
+        if self.cgb_mode {
+            self.bg_color((attributes & 0b111) as usize, color)
+        } else if self.cgb_hardware {
+            self.bg_color(0, shade)
+        } else {
+            DMG_COLORS[shade as usize]
+        }
+    }
 
+    // Screen color of a sprite pixel with the given color index and OAM attributes
+    pub fn object_color(&self, color: u8, attributes: u8) -> u32 {
+        let obp = if (attributes & 0b00010000) != 0 { self.obp1 } else { self.obp0 };
+        let shade = (obp >> (color * 2)) & 0b11;
+        if self.cgb_mode {
+            self.obj_color((attributes & 0b111) as usize, color)
+        } else if self.cgb_hardware {
+            self.obj_color(((attributes & 0b00010000) >> 4) as usize, shade)
+        } else {
+            DMG_COLORS[shade as usize]
+        }
     }
 
     pub fn read_vram(&self, address: usize) -> u8 {
-        self.vram[address]
+        self.vram[self.vram_bank][address]
     }
 
     pub fn write_vram(&mut self, address: usize, value: u8) {
-        if address > 0x1800 { return }
+        self.write_vram_bank(self.vram_bank, address, value);
+    }
+
+    pub fn read_vram_bank(&self, bank: usize, address: usize) -> u8 {
+        self.vram[bank][address]
+    }
+
+    pub fn write_vram_bank(&mut self, bank: usize, address: usize, value: u8) {
+        self.vram[bank][address] = value;
+        if address >= 0x1800 { return }
 
-        let normalised_address = address & 0xFFEF;
+        let normalised_address = address & 0xFFFE;
 
-        let byte1 = self.vram[normalised_address];
-        let byte2 = self.vram[normalised_address + 1];
+        let byte1 = self.vram[bank][normalised_address];
+        let byte2 = self.vram[bank][normalised_address + 1];
 
         let tile_address = address / 16;
         let row_address = (address % 16) / 2;
 
-        for pixel_address in 0 ..= 8 {
+        for pixel_address in 0 .. 8 {
             let mask = 1 << (7 - pixel_address);
             let least_significant_byte = byte1 & mask;
             let most_significant_byte = byte2 & mask;
@@ -70,7 +377,250 @@ impl PPU {
                 (false, false) => TilePixelValue::Zero
             };
 
-            self.tile_set[tile_address][row_address][pixel_address] = value;
+            self.tile_set[bank][tile_address][row_address][pixel_address] = value;
+        }
+    }
+
+    pub fn read_oam(&self, address: usize) -> u8 {
+        self.oam[address]
+    }
+
+    pub fn write_oam(&mut self, address: usize, value: u8) {
+        self.oam[address] = value;
+    }
+
+    // The tiles shown on the first 13 rows of the background, in screen order. The SGB
+    // receives *_TRN data by capturing the picture, which is this for the usual setup
+    pub fn screen_tile_data(&self) -> Vec<u8> {
+        let bg_map = if (self.lcdc & 0b00001000) != 0 { 0x1C00 } else { 0x1800 };
+        let mut data = Vec::with_capacity(SCREEN_WIDTH / 8 * 13 * 16);
+
+        for row in 0 .. 13 {
+            for column in 0 .. SCREEN_WIDTH / 8 {
+                let tile_number = self.vram[0][bg_map + row * 32 + column] as usize;
+                let address = if (self.lcdc & 0b00010000) != 0 || tile_number >= 128 {
+                    tile_number * 16
+                } else {
+                    0x1000 + tile_number * 16
+                };
+                data.extend_from_slice(&self.vram[0][address .. address + 16]);
+            }
         }
+
+        data
     }
-}
\ No newline at end of file
+
+    // The OAM bug on DMG hardware: during mode 2 the row being scanned is overwritten with
+    // a mix of itself and the row before
+    pub fn corrupt_oam(&mut self) {
+        if !self.is_lcd_enabled() || self.mode != PPUMode::OAMScan { return }
+
+        let row = (self.dot / 4) as usize * 8;
+        if row == 0 || row >= OAM_SIZE { return }
+
+        let word = |oam: &[u8; OAM_SIZE], address: usize| (oam[address] as u16) | ((oam[address + 1] as u16) << 8);
+        let a = word(&self.oam, row);
+        let b = word(&self.oam, row - 8);
+        let c = word(&self.oam, row - 4);
+
+        let corrupted = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[row] = (corrupted & 0xFF) as u8;
+        self.oam[row + 1] = (corrupted >> 8) as u8;
+        for i in 2 .. 8 {
+            self.oam[row + i] = self.oam[row - 8 + i];
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.vram[0]);
+        state.bytes(&self.vram[1]);
+        state.bytes(&self.oam);
+        state.u8(self.vram_bank as u8);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            state.u8(register);
+        }
+        state.u8(self.mode.to_bits());
+        state.u16(self.dot);
+        state.u8(self.window_line);
+        state.bool(self.stat_line);
+        state.bool(self.cgb_mode);
+        state.bool(self.cgb_hardware);
+        state.bytes(&self.bg_palette_ram);
+        state.u8(self.bcps);
+        state.bytes(&self.obj_palette_ram);
+        state.u8(self.ocps);
+        state.bool(self.dmg_object_priority);
+        state.colors(&self.framebuffer);
+        state.bytes(&self.shades);
+        state.bool(self.frame_ready);
+        state.bool(self.hblank_started);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        for bank in 0 .. 2 {
+            let mut vram = [0; VRAM_SIZE];
+            state.bytes_into(&mut vram);
+            // Decodes the tile set again along the way
+            for (address, value) in vram.into_iter().enumerate() {
+                self.write_vram_bank(bank, address, value);
+            }
+        }
+        state.bytes_into(&mut self.oam);
+        self.vram_bank = state.u8() as usize & 1;
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+                         &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            *register = state.u8();
+        }
+        self.mode = match state.u8() & 0b11 {
+            0 => PPUMode::HBlank,
+            1 => PPUMode::VBlank,
+            2 => PPUMode::OAMScan,
+            _ => PPUMode::Drawing
+        };
+        self.dot = state.u16();
+        self.window_line = state.u8();
+        self.stat_line = state.bool();
+        self.cgb_mode = state.bool();
+        self.cgb_hardware = state.bool();
+        state.bytes_into(&mut self.bg_palette_ram);
+        self.bcps = state.u8();
+        state.bytes_into(&mut self.obj_palette_ram);
+        self.ocps = state.u8();
+        self.dmg_object_priority = state.bool();
+        state.colors_into(&mut self.framebuffer);
+        state.bytes_into(&mut self.shades);
+        self.frame_ready = state.bool();
+        self.hblank_started = state.bool();
+    }
+
+    // Color index of a pixel in the decoded tile set, tiles 0-383 from 0x8000 in either VRAM bank
+    pub fn tile_pixel(&self, bank: usize, tile: usize, row: usize, column: usize) -> u8 {
+        self.tile_set[bank][tile][row][column].into()
+    }
+
+    // Returns the color index and CGB attributes of a background or window pixel, map_base is
+    // 0x1800 or 0x1C00 and x and y are in map pixels
+    pub fn map_pixel(&self, map_base: usize, x: usize, y: usize) -> (u8, u8) {
+        let map_address = map_base + (y / 8) * 32 + (x / 8);
+        let tile_number = self.vram[0][map_address] as usize;
+        let attributes = if self.cgb_mode { self.vram[1][map_address] } else { 0 };
+
+        // 0x8800 addressing puts tiles 0-127 after 128-255
+        let tile = if (self.lcdc & 0b00010000) != 0 || tile_number >= 128 { tile_number } else { 256 + tile_number };
+        let bank = ((attributes & 0b00001000) >> 3) as usize;
+        let row = if (attributes & 0b01000000) != 0 { 7 - y % 8 } else { y % 8 };
+        let column = if (attributes & 0b00100000) != 0 { 7 - x % 8 } else { x % 8 };
+
+        (self.tile_pixel(bank, tile, row, column), attributes)
+    }
+
+    fn render_scanline(&mut self) {
+        let ly = self.ly as usize;
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_attributes = [0u8; SCREEN_WIDTH];
+
+        // On CGB, LCDC bit 0 only takes away the background priority
+        let bg_enabled = self.cgb_mode || (self.lcdc & 0b00000001) != 0;
+        let window_visible = bg_enabled && (self.lcdc & 0b00100000) != 0 && self.wy <= self.ly && self.wx <= 166;
+        let bg_map = if (self.lcdc & 0b00001000) != 0 { 0x1C00 } else { 0x1800 };
+        let window_map = if (self.lcdc & 0b01000000) != 0 { 0x1C00 } else { 0x1800 };
+
+        if bg_enabled {
+            for x in 0 .. SCREEN_WIDTH {
+                let (color, attributes) = if window_visible && x + 7 >= self.wx as usize {
+                    self.map_pixel(window_map, x + 7 - self.wx as usize, self.window_line as usize)
+                } else {
+                    self.map_pixel(bg_map, (self.scx as usize + x) & 0xFF, (self.scy as usize + ly) & 0xFF)
+                };
+
+                bg_colors[x] = color;
+                bg_attributes[x] = attributes;
+            }
+        }
+        if window_visible { self.window_line += 1; }
+
+        // Winning sprite pixel per column as (color, attributes)
+        let mut sprite_pixels: [Option<(u8, u8)>; SCREEN_WIDTH] = [None; SCREEN_WIDTH];
+        if (self.lcdc & 0b00000010) != 0 {
+            let sprites = self.sprites_on_line();
+            let height = if (self.lcdc & 0b00000100) != 0 { 16 } else { 8 };
+
+            // Lowest priority first so the highest priority sprite ends up on top
+            for &sprite in sprites.iter().rev() {
+                let y = self.oam[sprite * 4] as isize - 16;
+                let x = self.oam[sprite * 4 + 1] as isize - 8;
+                let mut tile = self.oam[sprite * 4 + 2] as usize;
+                let attributes = self.oam[sprite * 4 + 3];
+                if height == 16 { tile &= 0xFE; }
+
+                let mut row = (ly as isize - y) as usize;
+                if (attributes & 0b01000000) != 0 { row = height - 1 - row; }
+                let bank = if self.cgb_mode { ((attributes & 0b00001000) >> 3) as usize } else { 0 };
+
+                for column in 0 .. 8 {
+                    let screen_x = x + column as isize;
+                    if screen_x < 0 || screen_x >= SCREEN_WIDTH as isize { continue }
+
+                    let tile_column = if (attributes & 0b00100000) != 0 { 7 - column } else { column };
+                    let color = self.tile_pixel(bank, tile + row / 8, row % 8, tile_column);
+                    if color != 0 {
+                        sprite_pixels[screen_x as usize] = Some((color, attributes));
+                    }
+                }
+            }
+        }
+
+        for x in 0 .. SCREEN_WIDTH {
+            let bg_color = bg_colors[x];
+            let mut shade = (self.bgp >> (bg_color * 2)) & 0b11;
+            let mut pixel = self.background_color(bg_color, bg_attributes[x]);
+
+            if let Some((color, attributes)) = sprite_pixels[x] {
+                let bg_on_top = if self.cgb_mode {
+                    (self.lcdc & 0b00000001) != 0 && bg_color != 0 &&
+                    ((bg_attributes[x] & 0b10000000) != 0 || (attributes & 0b10000000) != 0)
+                } else {
+                    bg_color != 0 && (attributes & 0b10000000) != 0
+                };
+
+                if !bg_on_top {
+                    let obp = if (attributes & 0b00010000) != 0 { self.obp1 } else { self.obp0 };
+                    shade = (obp >> (color * 2)) & 0b11;
+                    pixel = self.object_color(color, attributes);
+                }
+            }
+
+            self.framebuffer[ly * SCREEN_WIDTH + x] = pixel;
+            self.shades[ly * SCREEN_WIDTH + x] = shade;
+        }
+    }
+
+    // OAM indexes of the sprites on the current line, highest priority first
+    fn sprites_on_line(&self) -> Vec<usize> {
+        let height = if (self.lcdc & 0b00000100) != 0 { 16 } else { 8 };
+        let ly = self.ly as isize;
+
+        let mut sprites: Vec<usize> = (0 .. 40)
+            .filter(|sprite| {
+                let y = self.oam[sprite * 4] as isize - 16;
+                ly >= y && ly < y + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // The DMG favours the lowest X, the CGB only looks at the OAM position
+        if !self.cgb_mode || self.dmg_object_priority {
+            sprites.sort_by_key(|sprite| self.oam[sprite * 4 + 1]);
+        }
+
+        sprites
+    }
+}
+
+fn increment_palette_index(specification: u8) -> u8 {
+    if (specification & 0b10000000) != 0 {
+        0b10000000 | ((specification + 1) & 0x3F)
+    } else {
+        specification
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+
+use crate::cpu::{self, CPU};
+use crate::cpu::call_stack::FrameKind;
+use crate::error::EmulatorError;
+use crate::symbols::Symbols;
+use crate::tracer;
+
+// Stands in for a function in the stacks of halted time
+const HALT_FRAME: (u16, u16) = (0xFFFF, 0xFFFF);
+const ENTRY_POINT: u16 = 0x0100;
+
+struct AddressStats {
+    cycles: u64,
+    executions: u64,
+    text: String
+}
+
+// What the CPU was doing when a step started, its cycles are added once the next step starts
+struct Sample {
+    bank: u16,
+    pc: u16,
+    halted: bool,
+    // Vector of the innermost interrupt handler
+    interrupt: Option<u16>
+}
+
+// Counts CPU cycles per instruction, per function and per call stack, for finding hot spots.
+// The report and collapsed stacks are written when the profiler is dropped
+pub struct Profiler {
+    report: Option<(String, std::fs::File)>,
+    collapsed: Option<(String, std::fs::File)>,
+    symbols: Rc<Symbols>,
+    addresses: HashMap<(u16, u16), AddressStats>,
+    // Cycles per chain of function entry points, outermost first, as (bank, address)
+    stacks: HashMap<Vec<(u16, u16)>, u64>,
+    interrupts: HashMap<u16, u64>,
+    halted_cycles: u64,
+    total_cycles: u64,
+    last: Option<Sample>,
+    last_cycles: u64,
+    // The stack of the last sample, reused to avoid allocating every step
+    stack: Vec<(u16, u16)>
+}
+
+impl Profiler {
+    pub fn new(report_path: Option<&str>, collapsed_path: Option<&str>, symbols: Rc<Symbols>) -> Result<Profiler, EmulatorError> {
+        let create = |path: Option<&str>| -> Result<Option<(String, std::fs::File)>, EmulatorError> {
+            match path {
+                Some(path) => Ok(Some((path.to_string(), std::fs::File::create(path).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?))),
+                None => Ok(None)
+            }
+        };
+
+        Ok(Profiler {
+            report: create(report_path)?,
+            collapsed: create(collapsed_path)?,
+            symbols,
+            addresses: HashMap::new(),
+            stacks: HashMap::new(),
+            interrupts: HashMap::new(),
+            halted_cycles: 0,
+            total_cycles: 0,
+            last: None,
+            last_cycles: 0,
+            stack: Vec::new()
+        })
+    }
+
+    // Called before every CPU step
+    pub fn record(&mut self, cpu: &CPU) {
+        // Loading a state goes back in time, the step before it counts nothing
+        let elapsed = cpu.bus.cycles.saturating_sub(self.last_cycles);
+        self.last_cycles = cpu.bus.cycles;
+        if let Some(last) = self.last.take() { self.add(last, elapsed); }
+
+        let bank = cpu.bus.bank_at(cpu.pc);
+        let halted = cpu.is_halted || cpu.is_stopped || cpu.is_locked;
+        let frames = cpu.call_stack.frames();
+        let interrupt = frames.iter().rev().find_map(|frame| match frame.kind {
+            FrameKind::Interrupt(vector) => Some(vector),
+            _ => None
+        });
+
+        // Code without a label before it belongs to the last entry point, the cartridge's outside of any call
+        let function = |address: u16, caller: (u16, u16)| {
+            let bank = cpu.bus.bank_at(address);
+            self.symbols.function_start(bank, address).map_or(caller, |start| (bank, start))
+        };
+        let mut stack = std::mem::take(&mut self.stack);
+        stack.clear();
+        let mut current = (0, ENTRY_POINT);
+        if let Some(outermost) = frames.first() {
+            current = function(outermost.caller, current);
+            stack.push(current);
+        }
+        for frame in frames {
+            current = function(frame.target, (cpu.bus.bank_at(frame.target), frame.target));
+            stack.push(current);
+        }
+        current = function(cpu.pc, current);
+        if stack.last() != Some(&current) { stack.push(current); }
+        if halted { stack.push(HALT_FRAME); }
+        self.stack = stack;
+
+        if !halted && !self.addresses.contains_key(&(bank, cpu.pc)) {
+            let text = tracer::disassemble_at(cpu, cpu.pc, &self.symbols).text;
+            self.addresses.insert((bank, cpu.pc), AddressStats { cycles: 0, executions: 0, text });
+        }
+
+        self.last = Some(Sample { bank, pc: cpu.pc, halted, interrupt });
+    }
+
+    fn add(&mut self, sample: Sample, cycles: u64) {
+        self.total_cycles += cycles;
+        if let Some(vector) = sample.interrupt { *self.interrupts.entry(vector).or_insert(0) += cycles; }
+
+        if sample.halted {
+            self.halted_cycles += cycles;
+        } else if let Some(stats) = self.addresses.get_mut(&(sample.bank, sample.pc)) {
+            stats.cycles += cycles;
+            stats.executions += 1;
+        }
+
+        match self.stacks.get_mut(self.stack.as_slice()) {
+            Some(total) => *total += cycles,
+            None => { self.stacks.insert(self.stack.clone(), cycles); }
+        }
+    }
+
+    fn function_name(&self, (bank, address): (u16, u16)) -> String {
+        if (bank, address) == HALT_FRAME { return "[halt]".to_string() }
+        match self.symbols.label(bank, address) {
+            Some(label) => label.to_string(),
+            None => format!("{:02X}:{:04X}", bank, address)
+        }
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total_cycles == 0 { 0.0 } else { cycles as f64 * 100.0 / self.total_cycles as f64 }
+    }
+
+    fn write_report(&self, output: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(output, "Profile of {} CPU cycles", self.total_cycles)?;
+        writeln!(output)?;
+        writeln!(output, "Halted:             {:>12} cycles {:>6.2}%", self.halted_cycles, self.percent(self.halted_cycles))?;
+        let interrupt_cycles: u64 = self.interrupts.values().sum();
+        writeln!(output, "Interrupt handlers: {:>12} cycles {:>6.2}%", interrupt_cycles, self.percent(interrupt_cycles))?;
+        let mut interrupts: Vec<(&u16, &u64)> = self.interrupts.iter().collect();
+        interrupts.sort_by_key(|&(vector, _)| *vector);
+        for (&vector, &cycles) in interrupts {
+            writeln!(output, "  {:<16}  {:>12} cycles {:>6.2}%", cpu::interrupt_name(vector), cycles, self.percent(cycles))?;
+        }
+
+        // Self time counts stacks ending in a function, total time every stack it appears in once
+        let mut functions: HashMap<(u16, u16), (u64, u64)> = HashMap::new();
+        for (stack, &cycles) in &self.stacks {
+            let Some(&innermost) = stack.iter().rev().find(|&&frame| frame != HALT_FRAME) else { continue };
+            if stack.last() != Some(&HALT_FRAME) { functions.entry(innermost).or_insert((0, 0)).0 += cycles; }
+            for (index, frame) in stack.iter().enumerate() {
+                if *frame != HALT_FRAME && !stack[.. index].contains(frame) { functions.entry(*frame).or_insert((0, 0)).1 += cycles; }
+            }
+        }
+        let mut functions: Vec<((u16, u16), (u64, u64))> = functions.into_iter().collect();
+        functions.sort_by_key(|&(location, (self_cycles, _))| (std::cmp::Reverse(self_cycles), location));
+
+        writeln!(output)?;
+        writeln!(output, "Functions by self time:")?;
+        writeln!(output, "  {:>7} {:>12} {:>7} {:>12}  function", "self%", "self", "total%", "total")?;
+        for (location, (self_cycles, total_cycles)) in functions {
+            writeln!(output, "  {:>6.2}% {:>12} {:>6.2}% {:>12}  {}",
+                self.percent(self_cycles), self_cycles, self.percent(total_cycles), total_cycles, self.function_name(location))?;
+        }
+
+        let mut addresses: Vec<(&(u16, u16), &AddressStats)> = self.addresses.iter().filter(|(_, stats)| stats.executions > 0).collect();
+        addresses.sort_by_key(|&(location, stats)| (std::cmp::Reverse(stats.cycles), *location));
+
+        writeln!(output)?;
+        writeln!(output, "Instructions by time:")?;
+        writeln!(output, "  {:>7} {:>12} {:>10}  location", "cycles%", "cycles", "count")?;
+        for (&(bank, address), stats) in addresses {
+            writeln!(output, "  {:>6.2}% {:>12} {:>10}  {:<32} {}",
+                self.percent(stats.cycles), stats.cycles, stats.executions, self.symbols.format_location(bank, address), stats.text)?;
+        }
+
+        Ok(())
+    }
+
+    // One line per stack like "Main;UpdateSprites;CopyOAM 1234", as flamegraph.pl and speedscope read them
+    fn write_collapsed(&self, output: &mut dyn Write) -> std::io::Result<()> {
+        let mut stacks: Vec<(String, u64)> = self.stacks.iter()
+            .map(|(stack, &cycles)| (stack.iter().map(|&frame| self.function_name(frame)).collect::<Vec<String>>().join(";"), cycles))
+            .collect();
+        stacks.sort();
+
+        for (stack, cycles) in stacks {
+            writeln!(output, "{} {}", stack, cycles)?;
+        }
+
+        Ok(())
+    }
+
+    // The step that was about to start when the emulator stopped isn't counted
+    fn write(&self) -> Result<(), EmulatorError> {
+        if let Some((path, file)) = &self.report {
+            let mut output = BufWriter::new(file);
+            self.write_report(&mut output).and_then(|_| output.flush()).map_err(|error| EmulatorError::Io { path: path.clone(), error })?;
+        }
+        if let Some((path, file)) = &self.collapsed {
+            let mut output = BufWriter::new(file);
+            self.write_collapsed(&mut output).and_then(|_| output.flush()).map_err(|error| EmulatorError::Io { path: path.clone(), error })?;
+        }
+
+        Ok(())
+    }
+}
+
+// The emulator stops in many places, from the debugger, the window or the end of the boot ROM
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(error) = self.write() { eprintln!("{}", error); }
+    }
+}
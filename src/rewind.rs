@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::save_state;
+
+// CPU cycles per frame at normal speed
+const CYCLES_PER_FRAME: u64 = 70224;
+
+// Turns the newer of two consecutive snapshots back into the older one
+struct Delta {
+    length: usize,
+    // The two states XORed and run length encoded, mostly runs of zeros
+    data: Vec<u8>
+}
+
+// Snapshots of the last seconds of play for going backwards in time. A full state is kept
+// only for the newest snapshot, every older one is the difference to the one after it
+pub struct Rewind {
+    // Frames between snapshots
+    interval: u32,
+    // Bytes the snapshots may use, the oldest are dropped beyond that
+    budget: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+    used: usize,
+    next_capture: u64
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+            next_capture: 0
+        }
+    }
+
+    fn capture_cycles(&self, cpu: &CPU) -> u64 {
+        let cycles_per_frame = if cpu.bus.double_speed { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME };
+        cycles_per_frame * self.interval as u64
+    }
+
+    // Called after every step, takes a snapshot every interval frames
+    pub fn record(&mut self, cpu: &CPU) {
+        if cpu.bus.cycles < self.next_capture { return }
+        self.next_capture = cpu.bus.cycles + self.capture_cycles(cpu);
+
+        let state = save_state::save(cpu);
+        if let Some(newest) = self.newest.take() {
+            let delta = Delta { length: newest.len(), data: encode(&state, &newest) };
+            self.used += delta.data.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(state);
+
+        let newest_size = self.newest.as_ref().map_or(0, Vec::len);
+        while self.used + newest_size > self.budget {
+            let Some(oldest) = self.deltas.pop_front() else { break };
+            self.used -= oldest.data.len();
+        }
+    }
+
+    // Loads the newest snapshot and makes the one before it the newest, the oldest one stays.
+    // Returns false when there is nothing to go back to
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let Some(newest) = self.newest.take() else { return false };
+        if let Err(reason) = save_state::load(cpu, &newest) {
+            println!("Could not rewind: {}", reason);
+            self.newest = Some(newest);
+            return false
+        }
+        // Shown right away instead of waiting for the next frame
+        cpu.bus.ppu.frame_ready = true;
+        self.next_capture = cpu.bus.cycles + self.capture_cycles(cpu);
+
+        self.newest = Some(match self.deltas.pop_back() {
+            Some(delta) => {
+                self.used -= delta.data.len();
+                decode(&newest, &delta)
+            }
+            None => newest
+        });
+
+        true
+    }
+}
+
+// Runs of zeros in the XOR of both states are stored as their length, everything else
+// as literal bytes: repeated [zero count][literal count][literals], counts in LEB128
+fn encode(state: &[u8], older: &[u8]) -> Vec<u8> {
+    let length = state.len().max(older.len());
+    let byte_at = |index: usize| state.get(index).copied().unwrap_or(0) ^ older.get(index).copied().unwrap_or(0);
+
+    let mut data = Vec::new();
+    let mut index = 0;
+    while index < length {
+        let zeros_start = index;
+        while index < length && byte_at(index) == 0 { index += 1; }
+        let literals_start = index;
+        while index < length && (byte_at(index) != 0 || (index + 1 < length && byte_at(index + 1) != 0)) { index += 1; }
+
+        write_count(&mut data, literals_start - zeros_start);
+        write_count(&mut data, index - literals_start);
+        data.extend((literals_start .. index).map(byte_at));
+    }
+
+    data
+}
+
+fn decode(state: &[u8], delta: &Delta) -> Vec<u8> {
+    let mut older = state.to_vec();
+    older.resize(older.len().max(delta.length), 0);
+
+    let mut position = 0;
+    let mut index = 0;
+    while position < delta.data.len() {
+        index += read_count(&delta.data, &mut position);
+        let literals = read_count(&delta.data, &mut position);
+        for byte in &delta.data[position .. position + literals] {
+            older[index] ^= byte;
+            index += 1;
+        }
+        position += literals;
+    }
+
+    older.truncate(delta.length);
+    older
+}
+
+fn write_count(data: &mut Vec<u8>, mut count: usize) {
+    while count >= 0x80 {
+        data.push((count as u8 & 0x7F) | 0x80);
+        count >>= 7;
+    }
+    data.push(count as u8);
+}
+
+fn read_count(data: &[u8], position: &mut usize) -> usize {
+    let mut count = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        count |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 { return count }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(state: &[u8], older: &[u8]) -> Vec<u8> {
+        let delta = Delta { length: older.len(), data: encode(state, older) };
+        decode(state, &delta)
+    }
+
+    #[test]
+    fn decode_restores_the_older_state() {
+        let older = [1, 2, 3, 4, 0, 0, 7, 8];
+        let state = [1, 2, 9, 4, 0, 5, 7, 0];
+        assert_eq!(round_trip(&state, &older), older);
+        assert_eq!(round_trip(&older, &state), state);
+    }
+
+    #[test]
+    fn states_of_different_lengths() {
+        let short = [1, 2, 3];
+        let long = [1, 2, 3, 4, 5, 0, 0, 6];
+        assert_eq!(round_trip(&long, &short), short);
+        assert_eq!(round_trip(&short, &long), long);
+        assert_eq!(round_trip(&[], &long), long);
+    }
+
+    #[test]
+    fn equal_states_are_one_run_of_zeros() {
+        let state = vec![0x55; 1000];
+        let data = encode(&state, &state);
+        // 1000 zeros in two LEB128 bytes and no literals
+        assert_eq!(data, [0xE8, 0x07, 0x00]);
+        assert_eq!(round_trip(&state, &state), state);
+    }
+
+    #[test]
+    fn long_runs_and_literals() {
+        let older: Vec<u8> = (0 .. 5000).map(|index| (index % 251) as u8).collect();
+        let mut state = older.clone();
+        for index in (0 .. 300).chain(2000 .. 2001).chain(4990 .. 5000) { state[index] ^= 0xA5; }
+        assert_eq!(round_trip(&state, &older), older);
+    }
+
+    #[test]
+    fn counts_round_trip() {
+        for count in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, usize::MAX >> 1] {
+            let mut data = Vec::new();
+            write_count(&mut data, count);
+            let mut position = 0;
+            assert_eq!(read_count(&data, &mut position), count);
+            assert_eq!(position, data.len());
+        }
+    }
+}
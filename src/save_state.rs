@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// A state file is MAGIC, the format version as u16 and then sections of a 4 byte tag,
+// a u32 length and the data, all little endian. Each part of the machine writes its fields
+// into its own section in a fixed order. New fields only ever go at the end of a section,
+// which older files then lack and which read as zero, and sections a version doesn't know
+// are skipped. A newer VERSION marks a change older versions can't read, those are rejected.
+const MAGIC: &[u8; 8] = b"GBSTATE\0";
+pub const VERSION: u16 = 1;
+
+pub const SLOTS: u8 = 10;
+
+// Model and cartridge the state was taken from, it can't be loaded into anything else
+const INFO: [u8; 4] = *b"INFO";
+// The screen at the time of saving, RGB888
+const THUMBNAIL: [u8; 4] = *b"THMB";
+const CPU_SECTION: [u8; 4] = *b"CPU ";
+const BUS: [u8; 4] = *b"BUS ";
+const PPU_SECTION: [u8; 4] = *b"PPU ";
+const TIMER: [u8; 4] = *b"TIMR";
+const SERIAL: [u8; 4] = *b"SERL";
+const HDMA: [u8; 4] = *b"HDMA";
+const JOYPAD: [u8; 4] = *b"JOYP";
+const SGB: [u8; 4] = *b"SGB ";
+// Cartridge RAM, and the mapper registers once there is an MBC
+const CARTRIDGE: [u8; 4] = *b"CART";
+
+pub struct StateWriter {
+    data: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed, so a buffer that grows in a later version still loads
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    // 0x00RRGGBB pixels as three bytes each
+    pub fn colors(&mut self, pixels: &[u32]) {
+        let bytes: Vec<u8> = pixels.iter().flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]).collect();
+        self.bytes(&bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// Reads past the end of a section give zero, for fields added after the state was written
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        if let Some(data) = self.data.get(self.position .. self.position + N) { bytes.copy_from_slice(data); }
+        self.position += N;
+        bytes
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    // Fills as much of the buffer as was saved, the rest reads as zero like missing fields
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) {
+        let length = self.u32() as usize;
+        let start = self.position.min(self.data.len());
+        let available = &self.data[start .. (start + length).min(self.data.len())];
+        let count = available.len().min(buffer.len());
+        buffer[.. count].copy_from_slice(&available[.. count]);
+        buffer[count ..].fill(0);
+        self.position += length;
+    }
+
+    pub fn bytes(&mut self) -> Vec<u8> {
+        let length = self.u32() as usize;
+        let start = self.position.min(self.data.len());
+        let bytes = self.data[start .. (start + length).min(self.data.len())].to_vec();
+        self.position += length;
+        bytes
+    }
+
+    pub fn colors_into(&mut self, pixels: &mut [u32]) {
+        let bytes = self.bytes();
+        for (pixel, rgb) in pixels.iter_mut().zip(bytes.chunks_exact(3)) {
+            *pixel = ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | rgb[2] as u32;
+        }
+    }
+}
+
+// Serialises the whole machine, rewind and movies keep states in memory this way too
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let bus = &cpu.bus;
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut section = |tag: [u8; 4], write: &dyn Fn(&mut StateWriter)| {
+        let mut state = StateWriter::new();
+        write(&mut state);
+        let bytes = state.into_bytes();
+        data.extend_from_slice(&tag);
+        data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bytes);
+    };
+
+    section(INFO, &|state| write_info(cpu, state));
+    section(THUMBNAIL, &|state| {
+        state.u16(SCREEN_WIDTH as u16);
+        state.u16(SCREEN_HEIGHT as u16);
+        state.colors(bus.ppu.framebuffer());
+    });
+    section(CPU_SECTION, &|state| cpu.save_state(state));
+    section(BUS, &|state| bus.save_state(state));
+    section(PPU_SECTION, &|state| bus.ppu.save_state(state));
+    section(TIMER, &|state| bus.timer.save_state(state));
+    section(SERIAL, &|state| bus.serial.save_state(state));
+    section(HDMA, &|state| bus.hdma.save_state(state));
+    section(JOYPAD, &|state| bus.joypad.save_state(state));
+    if let Some(sgb) = &bus.sgb { section(SGB, &|state| sgb.save_state(state)); }
+    section(CARTRIDGE, &|state| bus.save_cartridge_state(state));
+
+    data
+}
+
+// Restores a state from save. The header and INFO are checked before anything is restored
+// and only they reject a state, the sections after them read what is missing as zero
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let sections = parse(data)?;
+    let version = version(data);
+    if version > VERSION { return Err(format!("the state is from a newer version, format {} instead of {}", version, VERSION)) }
+    let empty: &[u8] = &[];
+    let section = |tag: [u8; 4]| StateReader::new(sections.get(&tag).copied().unwrap_or(empty));
+
+    let info = sections.get(&INFO).ok_or("the state has no INFO section")?;
+    check_info(cpu, &mut StateReader::new(info))?;
+
+    cpu.load_state(&mut section(CPU_SECTION));
+    let bus = &mut cpu.bus;
+    bus.load_state(&mut section(BUS));
+    bus.ppu.load_state(&mut section(PPU_SECTION));
+    bus.timer.load_state(&mut section(TIMER));
+    bus.serial.load_state(&mut section(SERIAL));
+    bus.hdma.load_state(&mut section(HDMA));
+    bus.joypad.load_state(&mut section(JOYPAD));
+    if let Some(sgb) = &mut bus.sgb { sgb.load_state(&mut section(SGB)); }
+    bus.load_cartridge_state(&mut section(CARTRIDGE));
+
+    Ok(())
+}
+
+fn parse(data: &[u8]) -> Result<HashMap<[u8; 4], &[u8]>, String> {
+    if data.len() < MAGIC.len() + 2 || &data[.. MAGIC.len()] != MAGIC { return Err("not a save state".to_string()) }
+
+    let mut sections = HashMap::new();
+    let mut position = MAGIC.len() + 2;
+    while position < data.len() {
+        let header = data.get(position .. position + 8).ok_or("the state is truncated")?;
+        let tag = [header[0], header[1], header[2], header[3]];
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let body = data.get(position + 8 .. position + 8 + length).ok_or("the state is truncated")?;
+        sections.insert(tag, body);
+        position += 8 + length;
+    }
+
+    Ok(sections)
+}
+
+// Only valid once parse has checked the header
+fn version(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]])
+}
+
+fn write_info(cpu: &CPU, state: &mut StateWriter) {
+    let header = &cpu.bus.header;
+    state.u8(cpu.bus.model as u8);
+    state.bytes(&header.title);
+    state.u8(header.cartridge_type);
+    state.u8(header.header_checksum);
+    state.u64(cpu.bus.cycles);
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    state.u64(time);
+}
+
+fn check_info(cpu: &CPU, info: &mut StateReader) -> Result<(), String> {
+    let header = &cpu.bus.header;
+    if info.u8() != cpu.bus.model as u8 { return Err(format!("the state is from another model than {:?}", cpu.bus.model)) }
+    let title = info.bytes();
+    if title != header.title || info.u8() != header.cartridge_type || info.u8() != header.header_checksum {
+        // Only the printable part, CGB cartridges keep their manufacturer code and flags in the title
+        let title: String = title.iter().take_while(|&&byte| byte.is_ascii_graphic() || byte == b' ').map(|&byte| byte as char).collect();
+        return Err(format!("the state is from another cartridge, {}", title.trim_end()))
+    }
+    Ok(())
+}
+
+// What a slot holds without loading it
+pub struct StateInfo {
+    pub version: u16,
+    pub cycles: u64,
+    // Seconds since the Unix epoch
+    pub saved_at: u64,
+    pub thumbnail: Vec<u32>
+}
+
+pub fn read_info(data: &[u8]) -> Result<StateInfo, String> {
+    let sections = parse(data)?;
+    let version = version(data);
+
+    let mut info = StateReader::new(sections.get(&INFO).copied().unwrap_or_default());
+    info.u8();
+    info.bytes();
+    info.u8();
+    info.u8();
+    let cycles = info.u64();
+    let saved_at = info.u64();
+
+    let mut thumbnail_section = StateReader::new(sections.get(&THUMBNAIL).copied().unwrap_or_default());
+    let width = thumbnail_section.u16() as usize;
+    let height = thumbnail_section.u16() as usize;
+    // Only shown in place of the screen, so any other size is dropped before allocating for it
+    let mut thumbnail = Vec::new();
+    if width == SCREEN_WIDTH && height == SCREEN_HEIGHT {
+        thumbnail.resize(width * height, 0);
+        thumbnail_section.colors_into(&mut thumbnail);
+    }
+
+    Ok(StateInfo { version, cycles, saved_at, thumbnail })
+}
+
+// Numbered save state files next to the ROM, game.gb keeps slot 3 in game.ss3
+#[derive(Clone)]
+pub struct SaveStates {
+    rom_path: PathBuf
+}
+
+impl SaveStates {
+    pub fn new(rom_path: &str) -> SaveStates {
+        SaveStates { rom_path: PathBuf::from(rom_path) }
+    }
+
+    pub fn path(&self, slot: u8) -> String {
+        self.rom_path.with_extension(format!("ss{}", slot)).to_string_lossy().into_owned()
+    }
+
+    pub fn save(&self, cpu: &CPU, slot: u8) -> Result<(), EmulatorError> {
+        let path = self.path(slot);
+        std::fs::write(&path, save(cpu)).map_err(|error| EmulatorError::Io { path, error })
+    }
+
+    pub fn load(&self, cpu: &mut CPU, slot: u8) -> Result<(), EmulatorError> {
+        let data = self.read(slot)?;
+        load(cpu, &data).map_err(|reason| EmulatorError::InvalidSaveState { path: self.path(slot), reason })
+    }
+
+    pub fn read(&self, slot: u8) -> Result<Vec<u8>, EmulatorError> {
+        let path = self.path(slot);
+        std::fs::read(&path).map_err(|error| EmulatorError::Io { path, error })
+    }
+
+    // None for empty slots
+    pub fn info(&self, slot: u8) -> Option<Result<StateInfo, EmulatorError>> {
+        let path = self.path(slot);
+        let data = std::fs::read(&path).ok()?;
+        Some(read_info(&data).map_err(|reason| EmulatorError::InvalidSaveState { path, reason }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_bus::MemoryBus;
+    use crate::model::Model;
+
+    fn machine(title: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134 .. 0x0134 + title.len()].copy_from_slice(title);
+        let mut cpu = CPU::new(MemoryBus::new(None, rom, Model::DMG).unwrap());
+        cpu.skip_boot(Model::DMG);
+        cpu
+    }
+
+    #[test]
+    fn load_restores_a_saved_state() {
+        let mut cpu = machine(b"TEST");
+        cpu.registers.a = 0x12;
+        cpu.pc = 0x1234;
+        cpu.sp = 0xCFF0;
+        cpu.bus.write_byte(0xC123, 0x45);
+        cpu.bus.write_byte(0xFF80, 0x67);
+        let data = save(&cpu);
+
+        cpu.registers.a = 0;
+        cpu.pc = 0x0100;
+        cpu.sp = 0xFFFE;
+        cpu.bus.write_byte(0xC123, 0);
+        cpu.bus.write_byte(0xFF80, 0);
+        load(&mut cpu, &data).unwrap();
+
+        assert_eq!(cpu.registers.a, 0x12);
+        assert_eq!((cpu.pc, cpu.sp), (0x1234, 0xCFF0));
+        assert_eq!(cpu.bus.read_byte(0xC123), 0x45);
+        assert_eq!(cpu.bus.read_byte(0xFF80), 0x67);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected_untouched() {
+        let mut cpu = machine(b"TEST");
+        let mut data = save(&cpu);
+        data[MAGIC.len() .. MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+
+        cpu.pc = 0x4321;
+        assert!(load(&mut cpu, &data).unwrap_err().contains("newer version"));
+        assert_eq!(cpu.pc, 0x4321);
+        assert_eq!(read_info(&data).unwrap().version, VERSION + 1);
+    }
+
+    #[test]
+    fn other_cartridges_are_rejected() {
+        let data = save(&machine(b"FIRST"));
+        let mut cpu = machine(b"SECOND");
+        assert_eq!(load(&mut cpu, &data).unwrap_err(), "the state is from another cartridge, FIRST");
+    }
+
+    #[test]
+    fn truncated_states_are_rejected() {
+        let mut cpu = machine(b"TEST");
+        let data = save(&cpu);
+        assert_eq!(load(&mut cpu, &data[.. data.len() - 1]).unwrap_err(), "the state is truncated");
+        assert_eq!(load(&mut cpu, b"GBSTATE").unwrap_err(), "not a save state");
+    }
+
+    #[test]
+    fn missing_data_reads_as_zero() {
+        let mut state = StateWriter::new();
+        state.u8(0x12);
+        state.bytes(&[1, 2]);
+        let data = state.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8(), 0x12);
+        let mut buffer = [0xFF; 4];
+        reader.bytes_into(&mut buffer);
+        assert_eq!(buffer, [1, 2, 0, 0]);
+        assert_eq!(reader.u32(), 0);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::save_state::{StateWriter, StateReader};
+
+// CPU cycles per bit with the internal clock, 8192 Hz or 262144 Hz with the CGB fast clock.
+// Both are derived from the CPU clock, so they double along with it in CGB double speed
+const CYCLES_PER_BIT: u16 = 512;
+const FAST_CYCLES_PER_BIT: u16 = 16;
+
+pub struct Serial {
+    pub sb: u8,
+    transfer_enable: bool,
+    fast_clock: bool,
+    internal_clock: bool,
+    bits_left: u8,
+    cycles: u16,
+    // Every byte sent so far, test ROMs report their results here
+    pub output: Vec<u8>
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            transfer_enable: false,
+            fast_clock: false,
+            internal_clock: false,
+            bits_left: 0,
+            cycles: 0,
+            output: Vec::new()
+        }
+    }
+
+    pub fn read_sc(&self, cgb_mode: bool) -> u8 {
+        let fast_clock = if cgb_mode { (self.fast_clock as u8) << 1 } else { 0b10 };
+        0b01111100 | ((self.transfer_enable as u8) << 7) | fast_clock | (self.internal_clock as u8)
+    }
+
+    pub fn write_sc(&mut self, byte: u8, cgb_mode: bool) {
+        self.transfer_enable = (byte & 0b10000000) != 0;
+        self.fast_clock = cgb_mode && (byte & 0b10) != 0;
+        self.internal_clock = (byte & 0b1) != 0;
+
+        if self.transfer_enable {
+            self.bits_left = 8;
+            self.cycles = 0;
+        }
+    }
+
+    // Advances one CPU cycle, returns true when a transfer completes and requests the serial interrupt
+    pub fn tick(&mut self) -> bool {
+        // With the external clock nothing is connected to drive the transfer
+        if !self.transfer_enable || !self.internal_clock { return false }
+
+        self.cycles += 1;
+        let cycles_per_bit = if self.fast_clock { FAST_CYCLES_PER_BIT } else { CYCLES_PER_BIT };
+        if self.cycles < cycles_per_bit { return false }
+        self.cycles = 0;
+
+        if self.bits_left == 8 { self.output.push(self.sb); }
+
+        // No link partner, so only ones are shifted in
+        self.sb = (self.sb << 1) | 0b1;
+        self.bits_left -= 1;
+
+        if self.bits_left == 0 {
+            self.transfer_enable = false;
+            return true
+        }
+
+        false
+    }
+
+    // The output so far belongs to this run, not to the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.sb);
+        state.bool(self.transfer_enable);
+        state.bool(self.fast_clock);
+        state.bool(self.internal_clock);
+        state.u8(self.bits_left);
+        state.u16(self.cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.sb = state.u8();
+        self.transfer_enable = state.bool();
+        self.fast_clock = state.bool();
+        self.internal_clock = state.bool();
+        // A running transfer always has a bit left to shift, and the count can't pass a bit
+        self.bits_left = state.u8().clamp(1, 8);
+        self.cycles = state.u16().min(CYCLES_PER_BIT);
+    }
+}
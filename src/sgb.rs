@@ -0,0 +1,587 @@
+use crate::ppu::{self, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::save_state::{StateWriter, StateReader};
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+// Top left corner of the game screen inside the border
+pub const SCREEN_X: usize = 48;
+pub const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const ATTRIBUTE_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_FILE_SIZE: usize = ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT / 4;
+const ATTRIBUTE_FILES: usize = 45;
+const SYSTEM_PALETTES: usize = 512;
+
+// Shades of the DMG screen in RGB555 until the game sends its own palettes
+const GRAYSCALE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transfer {
+    Palettes,
+    // Border tiles 0-127 or 128-255
+    Tiles(bool),
+    Border,
+    AttributeFiles
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0
+}
+
+pub struct Sgb {
+    // Packet bits are sent by pulsing P14 (0) or P15 (1) low, then both high
+    receiving: bool,
+    pending_bit: Option<bool>,
+    bits: usize,
+    packets: Vec<u8>,
+    previous_p1: u8,
+
+    players: u8,
+    player: u8,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    attribute_files: Vec<u8>,
+    pending_transfer: Option<Transfer>,
+    mask: Mask,
+    frozen: Vec<u32>,
+
+    border_tiles: Vec<u8>,
+    border_map: [u16; 32 * 32],
+    // Border palettes 4-7 with 16 colors each, color 0 is transparent
+    border_palettes: [[u16; 16]; 4]
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            pending_bit: None,
+            bits: 0,
+            packets: Vec::new(),
+            previous_p1: 0x30,
+            players: 1,
+            player: 0,
+            palettes: [GRAYSCALE; 4],
+            system_palettes: vec![GRAYSCALE; SYSTEM_PALETTES],
+            attributes: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            attribute_files: vec![0; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILES],
+            pending_transfer: None,
+            mask: Mask::None,
+            frozen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            border_tiles: vec![0; 256 * 32],
+            border_map: [0; 32 * 32],
+            border_palettes: [[0; 16]; 4]
+        }
+    }
+
+    pub fn write_p1(&mut self, byte: u8) {
+        let lines = byte & 0x30;
+
+        match lines {
+            // Both lines low resets the transfer, the next bits start a new packet
+            0x00 => {
+                self.receiving = true;
+                self.pending_bit = None;
+                self.bits = 0;
+                if !self.packets.len().is_multiple_of(PACKET_SIZE) || self.is_command_complete() { self.packets.clear(); }
+            }
+            0x10 => { self.pending_bit = Some(true); }
+            0x20 => { self.pending_bit = Some(false); }
+            _ => {
+                if let Some(bit) = self.pending_bit.take() {
+                    if self.receiving { self.receive_bit(bit); }
+                }
+
+                // MLT_REQ moves on to the next controller when P15 goes high
+                if self.players > 1 && (self.previous_p1 & 0x20) == 0 {
+                    self.player = (self.player + 1) % self.players;
+                }
+            }
+        }
+
+        self.previous_p1 = lines;
+    }
+
+    // Replaces the lower nibble with the controller ID when both lines are deselected
+    pub fn read_p1(&self, joypad: u8) -> u8 {
+        if self.players == 1 { return joypad }
+
+        if (joypad & 0x30) == 0x30 {
+            (joypad & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            // Only the first controller is connected
+            joypad | 0x0F
+        } else {
+            joypad
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        // The 129th bit is the stop bit
+        if self.bits == PACKET_SIZE * 8 {
+            self.receiving = false;
+            if self.is_command_complete() {
+                let packets = std::mem::take(&mut self.packets);
+                self.execute(&packets);
+            }
+            return
+        }
+
+        if self.bits.is_multiple_of(8) { self.packets.push(0); }
+        let last = self.packets.len() - 1;
+        self.packets[last] |= (bit as u8) << (self.bits % 8);
+        self.bits += 1;
+    }
+
+    fn is_command_complete(&self) -> bool {
+        match self.packets.first() {
+            Some(header) => self.packets.len() >= ((header & 0b111).max(1) as usize) * PACKET_SIZE,
+            None => false
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(data, 0, 1),
+            0x01 => self.set_palette_pair(data, 2, 3),
+            0x02 => self.set_palette_pair(data, 0, 3),
+            0x03 => self.set_palette_pair(data, 1, 2),
+            0x04 => self.attribute_block(data),
+            0x05 => self.attribute_line(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            0x0A => self.set_system_palettes(data),
+            0x0B => { self.pending_transfer = Some(Transfer::Palettes); }
+            0x11 => {
+                self.players = match data[1] & 0b11 { 1 => 2, 3 => 4, _ => 1 };
+                self.player = 0;
+            }
+            0x13 => { self.pending_transfer = Some(Transfer::Tiles((data[1] & 0b1) != 0)); }
+            0x14 => { self.pending_transfer = Some(Transfer::Border); }
+            0x15 => { self.pending_transfer = Some(Transfer::AttributeFiles); }
+            0x16 => {
+                self.load_attribute_file((data[1] & 0x3F) as usize);
+                if (data[1] & 0x40) != 0 { self.mask = Mask::None; }
+            }
+            0x17 => {
+                self.mask = match data[1] & 0b11 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None
+                };
+            }
+            // Sound, SNES program transfers and the rest have no effect on the picture
+            _ => { }
+        }
+    }
+
+    // PAL01 and friends, color 0 is shared by all four palettes
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |index: usize| (data[1 + index * 2] as u16) | ((data[2 + index * 2] as u16) << 8);
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1 .. 4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    // PAL_SET, copies four of the palettes sent with PAL_TRN
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for palette in 0 .. 4 {
+            let index = ((data[1 + palette * 2] as usize) | ((data[2 + palette * 2] as usize) << 8)) % SYSTEM_PALETTES;
+            self.palettes[palette] = self.system_palettes[index];
+        }
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+
+        if (data[9] & 0x80) != 0 { self.load_attribute_file((data[9] & 0x3F) as usize); }
+        if (data[9] & 0x40) != 0 { self.mask = Mask::None; }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTRIBUTE_WIDTH && y < ATTRIBUTE_HEIGHT {
+            self.attributes[y * ATTRIBUTE_WIDTH + x] = palette & 0b11;
+        }
+    }
+
+    // ATTR_BLK, colors the inside, border and outside of rectangles
+    fn attribute_block(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(18);
+
+        for set in data[2 ..].chunks_exact(6).take(sets) {
+            let (control, palettes) = (set[0], set[1]);
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            let mut change_border = (control & 0b010) != 0;
+            let change_inside = (control & 0b001) != 0;
+            let change_outside = (control & 0b100) != 0;
+            let inside = palettes & 0b11;
+            let mut border = (palettes >> 2) & 0b11;
+            let outside = (palettes >> 4) & 0b11;
+
+            // Changing only the inside or outside changes the border along with it
+            if change_inside && !change_border && !change_outside {
+                change_border = true;
+                border = inside;
+            } else if change_outside && !change_border && !change_inside {
+                change_border = true;
+                border = outside;
+            }
+
+            for y in 0 .. ATTRIBUTE_HEIGHT {
+                for x in 0 .. ATTRIBUTE_WIDTH {
+                    if x > x1 && x < x2 && y > y1 && y < y2 {
+                        if change_inside { self.set_attribute(x, y, inside); }
+                    } else if x >= x1 && x <= x2 && y >= y1 && y <= y2 {
+                        if change_border { self.set_attribute(x, y, border); }
+                    } else if change_outside {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN, colors whole rows or columns
+    fn attribute_line(&mut self, data: &[u8]) {
+        let lines = data[1] as usize;
+
+        for &line in data[2 ..].iter().take(lines) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+
+            if (line & 0x80) != 0 {
+                for y in 0 .. ATTRIBUTE_HEIGHT { self.set_attribute(number, y, palette); }
+            } else {
+                for x in 0 .. ATTRIBUTE_WIDTH { self.set_attribute(x, number, palette); }
+            }
+        }
+    }
+
+    // ATTR_DIV, splits the screen in two at a row or column
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = (data[1] & 0x40) != 0;
+        let divider = data[2] as usize;
+
+        for y in 0 .. ATTRIBUTE_HEIGHT {
+            for x in 0 .. ATTRIBUTE_WIDTH {
+                let position = if horizontal { y } else { x };
+                let palette = match position.cmp(&divider) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    // ATTR_CHR, one palette per cell starting at a position
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = ((data[3] as usize) | ((data[4] as usize) << 8)).min(ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT);
+        let vertical = data[5] == 1;
+
+        for i in 0 .. count {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            let palette = (byte >> (6 - (i % 4) * 2)) & 0b11;
+            self.set_attribute(x, y, palette);
+
+            if vertical {
+                y += 1;
+                if y == ATTRIBUTE_HEIGHT { y = 0; x += 1; }
+            } else {
+                x += 1;
+                if x == ATTRIBUTE_WIDTH { x = 0; y += 1; }
+            }
+        }
+    }
+
+    fn load_attribute_file(&mut self, file: usize) {
+        if file >= ATTRIBUTE_FILES { return }
+
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        for cell in 0 .. ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT {
+            let byte = self.attribute_files[start + cell / 4];
+            self.attributes[cell] = (byte >> (6 - (cell % 4) * 2)) & 0b11;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.receiving);
+        state.u8(match self.pending_bit { None => 0, Some(false) => 1, Some(true) => 2 });
+        state.u16(self.bits as u16);
+        state.bytes(&self.packets);
+        state.u8(self.previous_p1);
+        state.u8(self.players);
+        state.u8(self.player);
+        for color in self.palettes.iter().chain(self.system_palettes.iter()).flatten() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.bytes(&self.attribute_files);
+        state.u8(match self.pending_transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::Tiles(false)) => 2,
+            Some(Transfer::Tiles(true)) => 3,
+            Some(Transfer::Border) => 4,
+            Some(Transfer::AttributeFiles) => 5
+        });
+        state.u8(match self.mask { Mask::None => 0, Mask::Freeze => 1, Mask::Black => 2, Mask::Color0 => 3 });
+        state.colors(&self.frozen);
+        state.bytes(&self.border_tiles);
+        for entry in self.border_map.iter().chain(self.border_palettes.iter().flatten()) {
+            state.u16(*entry);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.receiving = state.bool();
+        self.pending_bit = match state.u8() { 0 => None, 1 => Some(false), _ => Some(true) };
+        self.bits = state.u16() as usize;
+        self.packets = state.bytes();
+        // The bits of the current packet have to match the bytes received, otherwise the
+        // transfer is dropped and starts over with the next reset pulse
+        let packet_bytes = self.bits.div_ceil(8);
+        if self.bits > PACKET_SIZE * 8 || self.packets.len() < packet_bytes || !(self.packets.len() - packet_bytes).is_multiple_of(PACKET_SIZE) {
+            self.receiving = false;
+            self.bits = 0;
+            self.packets.clear();
+        }
+        self.previous_p1 = state.u8();
+        self.players = state.u8().max(1);
+        self.player = state.u8() % self.players;
+        for color in self.palettes.iter_mut().chain(self.system_palettes.iter_mut()).flatten() {
+            *color = state.u16();
+        }
+        state.bytes_into(&mut self.attributes);
+        state.bytes_into(&mut self.attribute_files);
+        self.pending_transfer = match state.u8() {
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(false)),
+            3 => Some(Transfer::Tiles(true)),
+            4 => Some(Transfer::Border),
+            5 => Some(Transfer::AttributeFiles),
+            _ => None
+        };
+        self.mask = match state.u8() { 1 => Mask::Freeze, 2 => Mask::Black, 3 => Mask::Color0, _ => Mask::None };
+        state.colors_into(&mut self.frozen);
+        state.bytes_into(&mut self.border_tiles);
+        for entry in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()) {
+            *entry = state.u16();
+        }
+    }
+
+    pub fn has_pending_transfer(&self) -> bool {
+        self.pending_transfer.is_some()
+    }
+
+    // Finishes a *_TRN command with the 4KB the game put on screen
+    pub fn vram_transfer(&mut self, data: &[u8]) {
+        let Some(transfer) = self.pending_transfer.take() else { return };
+        let word = |index: usize| (data[index * 2] as u16) | ((data[index * 2 + 1] as u16) << 8);
+
+        match transfer {
+            Transfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (color, value) in palette.iter_mut().enumerate() {
+                        *value = word(i * 4 + color);
+                    }
+                }
+            }
+            Transfer::Tiles(upper) => {
+                let start = if upper { 128 * 32 } else { 0 };
+                self.border_tiles[start .. start + 128 * 32].copy_from_slice(&data[.. 128 * 32]);
+            }
+            Transfer::Border => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = word(i);
+                }
+                for (palette, colors) in self.border_palettes.iter_mut().enumerate() {
+                    for (color, value) in colors.iter_mut().enumerate() {
+                        *value = word(0x400 + palette * 16 + color);
+                    }
+                }
+            }
+            Transfer::AttributeFiles => {
+                self.attribute_files.copy_from_slice(&data[.. ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILES]);
+            }
+        }
+    }
+
+    // Draws the border with the colorized game screen inside, as 0xRRGGBB
+    pub fn render(&mut self, shades: &[u8], frame: &mut [u32]) {
+        let backdrop = ppu::rgb555_to_rgb888(self.palettes[0][0]);
+
+        match self.mask {
+            Mask::None => {
+                for y in 0 .. SCREEN_HEIGHT {
+                    for x in 0 .. SCREEN_WIDTH {
+                        let palette = self.attributes[(y / 8) * ATTRIBUTE_WIDTH + x / 8] as usize;
+                        let shade = shades[y * SCREEN_WIDTH + x] as usize;
+                        self.frozen[y * SCREEN_WIDTH + x] = ppu::rgb555_to_rgb888(self.palettes[palette][shade]);
+                    }
+                }
+            }
+            Mask::Freeze => { }
+            Mask::Black => { self.frozen.fill(0x000000); }
+            Mask::Color0 => { self.frozen.fill(backdrop); }
+        }
+
+        frame.fill(backdrop);
+        for y in 0 .. SCREEN_HEIGHT {
+            let start = (SCREEN_Y + y) * BORDER_WIDTH + SCREEN_X;
+            frame[start .. start + SCREEN_WIDTH].copy_from_slice(&self.frozen[y * SCREEN_WIDTH .. (y + 1) * SCREEN_WIDTH]);
+        }
+
+        for y in 0 .. BORDER_HEIGHT {
+            for x in 0 .. BORDER_WIDTH {
+                if let Some(color) = self.border_pixel(x, y) {
+                    frame[y * BORDER_WIDTH + x] = color;
+                }
+            }
+        }
+    }
+
+    // SNES 4bpp tiles, bitplanes 0 and 1 interleaved in the first 16 bytes, 2 and 3 in the rest
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = (((entry >> 10) & 0b111) as usize).saturating_sub(4).min(3);
+        let column = if (entry & 0x4000) != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if (entry & 0x8000) != 0 { 7 - y % 8 } else { y % 8 };
+
+        let tile_data = &self.border_tiles[tile * 32 .. tile * 32 + 32];
+        let bit = 7 - column;
+        let color = ((tile_data[row * 2] >> bit) & 1) |
+            (((tile_data[row * 2 + 1] >> bit) & 1) << 1) |
+            (((tile_data[16 + row * 2] >> bit) & 1) << 2) |
+            (((tile_data[16 + row * 2 + 1] >> bit) & 1) << 3);
+
+        if color == 0 { return None }
+        Some(ppu::rgb555_to_rgb888(self.border_palettes[palette][color as usize]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A reset pulse and the bits LSB first, each bit a pulse of P14 or P15
+    fn send_bits(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for i in 0 .. PACKET_SIZE * 8 {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            sgb.write_p1(if bit == 1 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+    }
+
+    fn send_stop_bit(sgb: &mut Sgb) {
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        send_bits(sgb, packet);
+        send_stop_bit(sgb);
+    }
+
+    fn attribute(sgb: &Sgb, x: usize, y: usize) -> u8 {
+        sgb.attributes[y * ATTRIBUTE_WIDTH + x]
+    }
+
+    #[test]
+    fn receives_packet_bits() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 0x03;
+        packet[15] = 0x80;
+        send_bits(&mut sgb, &packet);
+
+        assert_eq!(sgb.packets, packet);
+        assert_eq!(sgb.bits, PACKET_SIZE * 8);
+        assert_eq!(sgb.players, 1);
+    }
+
+    #[test]
+    fn executes_after_the_stop_bit() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 0x03;
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(sgb.players, 4);
+        assert!(sgb.packets.is_empty());
+        assert!(!sgb.receiving);
+    }
+
+    #[test]
+    fn attribute_block_colors_inside_border_and_outside() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (0x04 << 3) | 1;
+        packet[1] = 1;
+        packet[2 .. 8].copy_from_slice(&[0b111, 0b11_10_01, 2, 2, 5, 5]);
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(attribute(&sgb, 3, 3), 1);
+        assert_eq!(attribute(&sgb, 2, 4), 2);
+        assert_eq!(attribute(&sgb, 5, 5), 2);
+        assert_eq!(attribute(&sgb, 0, 0), 3);
+        assert_eq!(attribute(&sgb, 6, 3), 3);
+    }
+
+    #[test]
+    fn attribute_block_inside_only_changes_the_border() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = (0x04 << 3) | 1;
+        packet[1] = 1;
+        packet[2 .. 8].copy_from_slice(&[0b001, 0b11_10_01, 2, 2, 5, 5]);
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(attribute(&sgb, 3, 3), 1);
+        assert_eq!(attribute(&sgb, 2, 2), 1);
+        assert_eq!(attribute(&sgb, 0, 0), 0);
+    }
+
+    #[test]
+    fn loading_bits_without_packets_drops_the_transfer() {
+        let mut state = StateWriter::new();
+        state.bool(true);
+        state.u8(0);
+        state.u16(3);
+        state.bytes(&[]);
+        let data = state.into_bytes();
+
+        let mut sgb = Sgb::new();
+        sgb.load_state(&mut StateReader::new(&data));
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+
+        assert_eq!(sgb.bits, 0);
+        assert!(sgb.packets.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::EmulatorError;
+
+// Labels from an RGBDS .sym file, lines like "01:4000 Label" with ; comments
+pub struct Symbols {
+    by_location: BTreeMap<(u16, u16), String>,
+    by_name: HashMap<String, (u16, u16)>
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            by_location: BTreeMap::new(),
+            by_name: HashMap::new()
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, EmulatorError> {
+        let text = std::fs::read_to_string(path).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?;
+        Symbols::parse(&text).map_err(|line| EmulatorError::InvalidSymbolFile { path: path.to_string(), line })
+    }
+
+    // Returns the number of the first malformed line on error
+    pub fn parse(text: &str) -> Result<Symbols, usize> {
+        let mut symbols = Symbols::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() { continue }
+
+            let (location, name) = line.split_once(char::is_whitespace).ok_or(index + 1)?;
+            let (bank, address) = location.split_once(':').ok_or(index + 1)?;
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| index + 1)?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| index + 1)?;
+            let name = name.trim().to_string();
+
+            // The first label at a location names it, later ones are still found by name
+            symbols.by_location.entry((bank, address)).or_insert_with(|| name.clone());
+            symbols.by_name.insert(name, (bank, address));
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    // The label exactly at an address
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        self.by_location.get(&(bank, address)).map(|name| name.as_str())
+    }
+
+    // The closest label at or before an address in the same bank and memory region, as label+$offset
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        let start = region_start(address);
+        let ((_, label_address), name) = self.by_location.range((bank, start) ..= (bank, address)).next_back()?;
+
+        match address - label_address {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+${:X}", name, offset))
+        }
+    }
+
+    // The closest non-local label at or before an address, taken as the start of the function it is in
+    pub fn function_start(&self, bank: u16, address: u16) -> Option<u16> {
+        let start = region_start(address);
+        self.by_location.range((bank, start) ..= (bank, address)).rev()
+            .find(|(_, name)| !name.contains('.'))
+            .map(|(&(_, label_address), _)| label_address)
+    }
+
+    // bank:label+$offset where a label is known, bank:address otherwise
+    pub fn format_location(&self, bank: u16, address: u16) -> String {
+        match self.describe(bank, address) {
+            Some(label) => format!("{:02X}:{}", bank, label),
+            None => format!("{:02X}:{:04X}", bank, address)
+        }
+    }
+
+    // Labels containing the given text, sorted by location
+    pub fn search(&self, text: &str) -> Vec<(u16, u16, &str)> {
+        let text = text.to_ascii_lowercase();
+        self.by_location.iter()
+            .filter(|(_, name)| name.to_ascii_lowercase().contains(&text))
+            .map(|(&(bank, address), name)| (bank, address, name.as_str()))
+            .collect()
+    }
+}
+
+// Labels never describe addresses past the end of their own region
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000 ..= 0x3FFF => 0x0000,
+        0x4000 ..= 0x7FFF => 0x4000,
+        0x8000 ..= 0x9FFF => 0x8000,
+        0xA000 ..= 0xBFFF => 0xA000,
+        0xC000 ..= 0xCFFF => 0xC000,
+        0xD000 ..= 0xDFFF => 0xD000,
+        0xE000 ..= 0xFDFF => 0xE000,
+        0xFE00 ..= 0xFE9F => 0xFE00,
+        0xFEA0 ..= 0xFF7F => 0xFEA0,
+        _ => 0xFF80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Start
+00:0150 EntryPoint
+00:0160 Start.loop
+01:4000 Init
+01:4010 Init.clear
+00:C000 wCounter
+";
+
+    #[test]
+    fn parse_reads_labels_and_skips_comments() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.lookup("Init"), Some((1, 0x4000)));
+        assert_eq!(symbols.lookup("EntryPoint"), Some((0, 0x0150)));
+        // The first label at a location names it
+        assert_eq!(symbols.label(0, 0x0150), Some("Start"));
+        assert_eq!(symbols.lookup("Missing"), None);
+    }
+
+    #[test]
+    fn parse_reports_the_malformed_line() {
+        assert_eq!(Symbols::parse("00:0150 Start\n\nStart 0150\n").err(), Some(3));
+        assert_eq!(Symbols::parse("XX:0150 Start\n").err(), Some(1));
+        assert_eq!(Symbols::parse("00:0150\n").err(), Some(1));
+    }
+
+    #[test]
+    fn describe_uses_the_closest_label_in_the_region() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Start"));
+        assert_eq!(symbols.describe(0, 0x0165).as_deref(), Some("Start.loop+$5"));
+        assert_eq!(symbols.describe(1, 0x4002).as_deref(), Some("Init+$2"));
+        // Other banks and regions don't share labels
+        assert_eq!(symbols.describe(2, 0x4002), None);
+        assert_eq!(symbols.describe(0, 0x4002), None);
+        assert_eq!(symbols.describe(0, 0xD000), None);
+        assert_eq!(symbols.describe(0, 0x0100), None);
+    }
+
+    #[test]
+    fn function_start_skips_local_labels() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.function_start(0, 0x0165), Some(0x0150));
+        assert_eq!(symbols.function_start(1, 0x4012), Some(0x4000));
+        assert_eq!(symbols.function_start(0, 0x0100), None);
+        assert_eq!(symbols.function_start(1, 0x3FFF), None);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::movie;
+
+// CPU cycles per second at normal speed
+const CYCLES_PER_SECOND: u64 = 4194304;
+// Serial output and memory are looked at once per frame
+const CHECK_CYCLES: u64 = 70224;
+
+// Blargg's tests write 0x80 to 0xA000 while running, then the result code, with this
+// signature after it and the text output from 0xA004
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+// Mooneye's tests execute LD B,B when done, with these in B, C, D, E, H and L when passed
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: u8 = 0x42;
+
+pub const DETECTOR_NAMES: &str = "serial, memory, mooneye, hash:<crc32>";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detector {
+    // "Passed" or "Failed" sent over the serial port, as blargg's tests print
+    Serial,
+    // The result blargg's tests leave in cartridge RAM
+    Memory,
+    // The Fibonacci numbers in the registers at LD B,B
+    Mooneye,
+    // CRC32 of a finished frame as RGB888, the test passes when a frame matches
+    FramebufferHash(u32)
+}
+
+impl Detector {
+    pub fn from_name(name: &str) -> Option<Detector> {
+        match name {
+            "serial" => Some(Detector::Serial),
+            "memory" => Some(Detector::Memory),
+            "mooneye" => Some(Detector::Mooneye),
+            _ => {
+                let hash = name.strip_prefix("hash:")?;
+                u32::from_str_radix(hash.trim_start_matches("0x"), 16).ok().map(Detector::FramebufferHash)
+            }
+        }
+    }
+}
+
+pub enum Verdict {
+    Passed(String),
+    Failed(String)
+}
+
+// Watches a test ROM for its result, called before every step in the test mode
+pub struct TestRunner {
+    detectors: Vec<Detector>,
+    timeout_seconds: u64,
+    next_check: u64,
+    last_frame_hash: Option<u32>
+}
+
+impl TestRunner {
+    // Without detectors every kind of test ROM result is looked for, but no framebuffer
+    pub fn new(detectors: Vec<Detector>, timeout_seconds: u64) -> TestRunner {
+        let detectors = if detectors.is_empty() { vec![Detector::Serial, Detector::Memory, Detector::Mooneye] } else { detectors };
+        TestRunner { detectors, timeout_seconds, next_check: 0, last_frame_hash: None }
+    }
+
+    pub fn check(&mut self, cpu: &mut CPU) -> Result<Option<Verdict>, EmulatorError> {
+        if cpu.is_locked { return Ok(Some(Verdict::Failed(format!("the CPU locked up at {:04X}", cpu.pc)))) }
+
+        if self.detectors.contains(&Detector::Mooneye) && !cpu.is_halted && cpu.bus.read_byte(cpu.pc) == LD_B_B {
+            if let Some(verdict) = mooneye_result(cpu) { return Ok(Some(verdict)) }
+        }
+
+        // There is no frontend to take the frame, the flag is cleared here instead
+        if cpu.bus.ppu.frame_ready {
+            cpu.bus.ppu.frame_ready = false;
+            let frame: Vec<u8> = cpu.bus.ppu.framebuffer().iter().flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]).collect();
+            let hash = movie::crc32(&frame);
+            self.last_frame_hash = Some(hash);
+            if self.detectors.contains(&Detector::FramebufferHash(hash)) {
+                return Ok(Some(Verdict::Passed(format!("a frame matched CRC32 {:08X}", hash))))
+            }
+        }
+
+        if cpu.bus.cycles < self.next_check { return Ok(None) }
+        self.next_check = cpu.bus.cycles + CHECK_CYCLES;
+
+        for detector in &self.detectors {
+            let verdict = match detector {
+                Detector::Serial => serial_result(cpu),
+                Detector::Memory => memory_result(cpu),
+                _ => None
+            };
+            if verdict.is_some() { return Ok(verdict) }
+        }
+
+        // Double speed counts twice the cycles in the same time
+        let seconds = cpu.bus.cycles / if cpu.bus.double_speed { CYCLES_PER_SECOND * 2 } else { CYCLES_PER_SECOND };
+        if seconds >= self.timeout_seconds {
+            if let Some(hash) = self.last_frame_hash { println!("The last frame has CRC32 {:08X}", hash); }
+            return Err(EmulatorError::TestTimedOut { seconds: self.timeout_seconds })
+        }
+
+        Ok(None)
+    }
+}
+
+fn serial_result(cpu: &CPU) -> Option<Verdict> {
+    let output = String::from_utf8_lossy(&cpu.bus.serial.output);
+    let text = output.trim().to_string();
+    if output.contains("Passed") {
+        Some(Verdict::Passed(text))
+    } else if output.contains("Failed") {
+        Some(Verdict::Failed(text))
+    } else {
+        None
+    }
+}
+
+fn memory_result(cpu: &CPU) -> Option<Verdict> {
+    let signature = [cpu.bus.read_byte(0xA001), cpu.bus.read_byte(0xA002), cpu.bus.read_byte(0xA003)];
+    let status = cpu.bus.read_byte(0xA000);
+    if signature != BLARGG_SIGNATURE || status == BLARGG_RUNNING { return None }
+
+    let text: String = (0xA004 ..= 0xBFFF_u16)
+        .map(|address| cpu.bus.read_byte(address))
+        .take_while(|&byte| byte != 0)
+        .map(|byte| byte as char)
+        .collect();
+    let text = text.trim().to_string();
+    if status == 0 { Some(Verdict::Passed(text)) } else { Some(Verdict::Failed(format!("result code {:02X}: {}", status, text))) }
+}
+
+fn mooneye_result(cpu: &CPU) -> Option<Verdict> {
+    let registers = &cpu.registers;
+    let values = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+    if values == MOONEYE_PASSED {
+        Some(Verdict::Passed("Fibonacci numbers in the registers at LD B,B".to_string()))
+    } else if values.iter().all(|&value| value == MOONEYE_FAILED) {
+        Some(Verdict::Failed("42 in all registers at LD B,B".to_string()))
+    } else {
+        None
+    }
+}
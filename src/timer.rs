@@ -1,3 +1,5 @@
+use crate::save_state::{StateWriter, StateReader};
+
 pub struct Timer {
     div: u16,
     pub tima: u8,
@@ -76,8 +78,7 @@ impl Timer {
             0b00 => 9,
             0b01 => 3,
             0b10 => 5,
-            0b11 => 7,
-            _ => panic!("Invalid timer frequency!")
+            _ => 7
         };
         let div_bit: u8 = ((self.div & (0b0000000000000001 << bit_pos)) >> bit_pos) as u8;
         let timer_enable: u8 = (self.tac & 0b00000100) >> 2;
@@ -106,6 +107,11 @@ impl Timer {
     pub fn write_div(&mut self, byte: u8) {
         self.div = 0;
     }
+
+    // Sets the whole internal counter, used to start from a known post-boot state
+    pub fn set_div(&mut self, value: u16) {
+        self.div = value;
+    }
     
     pub fn write_tima(&mut self, new_value: u8) {
         self.new_tima = new_value; 
@@ -118,4 +124,28 @@ impl Timer {
     pub fn write_tma(&mut self, new_value: u8) {
         self.new_tma = new_value;
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.div);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.u8(self.edge);
+        state.bool(self.tima_overflow);
+        state.u8(self.overflow_cycle);
+        state.u8(self.new_tima);
+        state.u8(self.new_tma);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) {
+        self.div = state.u16();
+        self.tima = state.u8();
+        self.tma = state.u8();
+        self.tac = state.u8();
+        self.edge = state.u8();
+        self.tima_overflow = state.bool();
+        self.overflow_cycle = state.u8();
+        self.new_tima = state.u8();
+        self.new_tma = state.u8();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::rc::Rc;
+
+use crate::cpu::CPU;
+use crate::error::EmulatorError;
+use crate::symbols::Symbols;
+use crate::tracer::{self, TraceFormat};
+
+// Matching lines shown before a divergence
+const CONTEXT_LINES: usize = 8;
+
+pub enum DiffResult {
+    Matching,
+    // Every line of the reference matched
+    ReferenceEnded,
+    Diverged
+}
+
+// Compares the trace of the running emulator with a reference log one line at a time
+pub struct TraceDiff {
+    reader: BufReader<std::fs::File>,
+    path: String,
+    format: TraceFormat,
+    symbols: Rc<Symbols>,
+    context: VecDeque<String>,
+    line_number: usize,
+    // The instruction executed since the last matching line is usually the culprit
+    last_pc: Option<u16>
+}
+
+impl TraceDiff {
+    pub fn new(path: &str, format: TraceFormat, symbols: Rc<Symbols>) -> Result<TraceDiff, EmulatorError> {
+        let file = std::fs::File::open(path).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?;
+
+        Ok(TraceDiff {
+            reader: BufReader::new(file),
+            path: path.to_string(),
+            format,
+            symbols,
+            context: VecDeque::with_capacity(CONTEXT_LINES),
+            line_number: 0,
+            last_pc: None
+        })
+    }
+
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    // Called before every CPU step, prints a report when the trace diverges
+    pub fn check(&mut self, cpu: &CPU) -> Result<DiffResult, EmulatorError> {
+        if !cpu.fetches_instruction() { return Ok(DiffResult::Matching) }
+
+        let mut reference = String::new();
+        let read = self.reader.read_line(&mut reference).map_err(|error| EmulatorError::Io { path: self.path.clone(), error })?;
+        if read == 0 { return Ok(DiffResult::ReferenceEnded) }
+        self.line_number += 1;
+
+        let reference = reference.trim_end();
+        let line = tracer::format_line(&self.format, cpu, &self.symbols);
+        if line == reference {
+            if self.context.len() == CONTEXT_LINES { self.context.pop_front(); }
+            self.context.push_back(line);
+            self.last_pc = Some(cpu.pc);
+            return Ok(DiffResult::Matching)
+        }
+
+        self.report(cpu, reference, &line);
+        Ok(DiffResult::Diverged)
+    }
+
+    fn report(&self, cpu: &CPU, reference: &str, line: &str) {
+        println!("Trace diverged from {} at line {}", self.path, self.line_number);
+        println!();
+        let first_context_line = self.line_number - self.context.len();
+        for (index, context) in self.context.iter().enumerate() {
+            println!("  {:>10}  {}", first_context_line + index, context);
+        }
+        println!("- {:>10}  {}", self.line_number, reference);
+        println!("+ {:>10}  {}", self.line_number, line);
+        println!();
+
+        if let Some(last_pc) = self.last_pc {
+            let instruction = tracer::disassemble_at(cpu, last_pc, &self.symbols);
+            println!("Last executed: {}  {}", self.symbols.format_location(cpu.bus.bank_at(last_pc), last_pc), instruction.text);
+        }
+        let instruction = tracer::disassemble_at(cpu, cpu.pc, &self.symbols);
+        println!("Next:          {}  {}", self.symbols.format_location(cpu.bus.bank_at(cpu.pc), cpu.pc), instruction.text);
+
+        let differences = differing_fields(reference, line);
+        if !differences.is_empty() {
+            println!("Differences (reference -> emulator):");
+            for (name, expected, found) in differences {
+                println!("  {:<6} {} -> {}", name, expected, found);
+            }
+        }
+    }
+}
+
+// Compares NAME:VALUE fields, which every built-in format is made of
+fn differing_fields(reference: &str, line: &str) -> Vec<(String, String, String)> {
+    let fields = |text: &str| -> Vec<(String, String)> {
+        text.split_whitespace()
+            .filter_map(|field| field.split_once(':'))
+            .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    };
+    let found = fields(line);
+
+    fields(reference).into_iter()
+        .filter_map(|(name, expected)| {
+            let value = found.iter().find(|(found_name, _)| *found_name == name).map(|(_, value)| value.clone()).unwrap_or_else(|| "(missing)".to_string());
+            if value != expected { Some((name, expected, value)) } else { None }
+        })
+        .collect()
+}
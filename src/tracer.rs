@@ -0,0 +1,181 @@
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+
+use crate::cpu::CPU;
+use crate::cpu::disassembler;
+use crate::error::EmulatorError;
+use crate::symbols::Symbols;
+
+pub const FORMAT_NAMES: &str = "doctor, bgb";
+
+#[derive(Clone)]
+pub enum TraceFormat {
+    // Gameboy Doctor: A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:00,00,00,00
+    Doctor,
+    // Like BGB's debugger trace: registers, cycles and the disassembled instruction
+    Bgb,
+    Template(String)
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "doctor" => Some(TraceFormat::Doctor),
+            "bgb" => Some(TraceFormat::Bgb),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum TraceTrigger {
+    Pc(u16),
+    // CPU cycles since power on
+    Cycle(u64)
+}
+
+impl TraceTrigger {
+    // pc:<hex> or cycle:<decimal>
+    pub fn parse(text: &str) -> Option<TraceTrigger> {
+        match text.split_once(':')? {
+            ("pc", address) => u16::from_str_radix(address.trim_start_matches('$').trim_start_matches("0x"), 16).ok().map(TraceTrigger::Pc),
+            ("cycle", cycles) => cycles.parse().ok().map(TraceTrigger::Cycle),
+            _ => None
+        }
+    }
+
+    fn reached(&self, cpu: &CPU) -> bool {
+        match self {
+            TraceTrigger::Pc(address) => cpu.pc == *address,
+            TraceTrigger::Cycle(cycles) => cpu.bus.cycles >= *cycles
+        }
+    }
+}
+
+// Writes one line of CPU state before every instruction that gets executed
+pub struct Tracer {
+    format: TraceFormat,
+    output: BufWriter<Box<dyn Write>>,
+    path: String,
+    start: Option<TraceTrigger>,
+    stop: Option<TraceTrigger>,
+    active: bool,
+    stopped: bool,
+    symbols: Rc<Symbols>
+}
+
+impl Tracer {
+    // A path of - writes to stdout
+    pub fn new(path: &str, format: TraceFormat, start: Option<TraceTrigger>, stop: Option<TraceTrigger>, symbols: Rc<Symbols>) -> Result<Tracer, EmulatorError> {
+        let output: Box<dyn Write> = if path == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(std::fs::File::create(path).map_err(|error| EmulatorError::Io { path: path.to_string(), error })?)
+        };
+
+        Ok(Tracer {
+            format,
+            output: BufWriter::new(output),
+            path: path.to_string(),
+            start,
+            stop,
+            active: start.is_none(),
+            stopped: false,
+            symbols
+        })
+    }
+
+    // Called before every CPU step
+    pub fn trace(&mut self, cpu: &CPU) -> Result<(), EmulatorError> {
+        if self.stopped { return Ok(()) }
+
+        if !self.active && self.start.is_some_and(|start| start.reached(cpu)) {
+            self.active = true;
+        }
+        if self.active && self.stop.is_some_and(|stop| stop.reached(cpu)) {
+            self.active = false;
+            self.stopped = true;
+            return self.output.flush().map_err(|error| EmulatorError::Io { path: self.path.clone(), error })
+        }
+
+        if !self.active || !cpu.fetches_instruction() { return Ok(()) }
+
+        let line = format_line(&self.format, cpu, &self.symbols);
+        writeln!(self.output, "{}", line).map_err(|error| EmulatorError::Io { path: self.path.clone(), error })
+    }
+}
+
+pub fn format_line(format: &TraceFormat, cpu: &CPU, symbols: &Symbols) -> String {
+    let registers = &cpu.registers;
+    let pcmem: Vec<u8> = (0 .. 4).map(|offset| cpu.bus.read_byte(cpu.pc.wrapping_add(offset))).collect();
+
+    match format {
+        TraceFormat::Doctor => format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a, u8::from(registers.f), registers.b, registers.c, registers.d, registers.e, registers.h, registers.l,
+            cpu.sp, cpu.pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3]
+        ),
+        TraceFormat::Bgb => {
+            let instruction = disassemble(cpu, symbols);
+            let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!(
+                "A:{:02X} F:{} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X} (cy: {}) ppu:+{} |[{:02X}]0x{:04X}: {:<9} {}",
+                registers.a, flags(cpu), registers.get_bc(), registers.get_de(), registers.get_hl(), cpu.sp, cpu.pc,
+                cpu.bus.cycles, cpu.bus.ppu.mode() as u8, cpu.bus.bank_at(cpu.pc), cpu.pc, bytes.join(" "), instruction.text
+            )
+        }
+        TraceFormat::Template(template) => {
+            let mut line = template.clone();
+            let hex8 = |value: u8| format!("{:02X}", value);
+            let hex16 = |value: u16| format!("{:04X}", value);
+            let bank = cpu.bus.bank_at(cpu.pc);
+
+            let fields: [(&str, &dyn Fn() -> String); 22] = [
+                ("{a}", &|| hex8(registers.a)),
+                ("{f}", &|| hex8(u8::from(registers.f))),
+                ("{b}", &|| hex8(registers.b)),
+                ("{c}", &|| hex8(registers.c)),
+                ("{d}", &|| hex8(registers.d)),
+                ("{e}", &|| hex8(registers.e)),
+                ("{h}", &|| hex8(registers.h)),
+                ("{l}", &|| hex8(registers.l)),
+                ("{af}", &|| hex16(registers.get_af())),
+                ("{bc}", &|| hex16(registers.get_bc())),
+                ("{de}", &|| hex16(registers.get_de())),
+                ("{hl}", &|| hex16(registers.get_hl())),
+                ("{sp}", &|| hex16(cpu.sp)),
+                ("{pc}", &|| hex16(cpu.pc)),
+                ("{bank}", &|| hex8(bank as u8)),
+                ("{label}", &|| symbols.format_location(bank, cpu.pc)),
+                ("{flags}", &|| flags(cpu)),
+                ("{ime}", &|| (cpu.ime as u8).to_string()),
+                ("{ly}", &|| hex8(cpu.bus.read_byte(0xFF44))),
+                ("{cycles}", &|| cpu.bus.cycles.to_string()),
+                ("{pcmem}", &|| pcmem.iter().map(|&byte| hex8(byte)).collect::<Vec<String>>().join(",")),
+                ("{op}", &|| disassemble(cpu, symbols).text)
+            ];
+            for (name, value) in fields {
+                if line.contains(name) { line = line.replace(name, &value()); }
+            }
+
+            line
+        }
+    }
+}
+
+fn disassemble(cpu: &CPU, symbols: &Symbols) -> disassembler::Disassembly {
+    disassemble_at(cpu, cpu.pc, symbols)
+}
+
+// The instruction at an address with labels for its operands
+pub fn disassemble_at(cpu: &CPU, address: u16, symbols: &Symbols) -> disassembler::Disassembly {
+    disassembler::disassemble_with_labels(|address| cpu.bus.read_byte(address), address, |target| symbols.describe(cpu.bus.bank_at(target), target))
+}
+
+fn flags(cpu: &CPU) -> String {
+    let flags = cpu.registers.f;
+    [(flags.zero, 'Z'), (flags.subtract, 'N'), (flags.half_carry, 'H'), (flags.carry, 'C')]
+        .iter()
+        .map(|&(set, name)| if set { name } else { '-' })
+        .collect()
+}